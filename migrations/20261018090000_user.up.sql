@@ -0,0 +1,2 @@
+ALTER TABLE user
+ADD COLUMN totp_secret VARCHAR(255) DEFAULT NULL AFTER avatar;
@@ -0,0 +1,9 @@
+CREATE TABLE recovery_code
+(
+    id         INT AUTO_INCREMENT NOT NULL PRIMARY KEY,
+    username   VARCHAR(20)  NOT NULL REFERENCES user (username),
+    code       VARCHAR(255) NOT NULL,
+    used_at    DATETIME DEFAULT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    INDEX (username)
+);
@@ -0,0 +1,6 @@
+ALTER TABLE user
+ADD COLUMN role ENUM ('admin', 'editor', 'viewer') NOT NULL DEFAULT 'viewer' AFTER avatar,
+ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT FALSE AFTER role;
+
+-- Accounts created before roles existed had full access
+UPDATE user SET role = 'admin';
@@ -0,0 +1,13 @@
+CREATE TABLE access_token
+(
+    id           INT AUTO_INCREMENT NOT NULL PRIMARY KEY,
+    username     VARCHAR(20)  NOT NULL REFERENCES user (username),
+    name         VARCHAR(255) NOT NULL,
+    prefix       VARCHAR(16)  NOT NULL,
+    token        CHAR(64)     NOT NULL UNIQUE,
+    scopes       VARCHAR(255) NOT NULL,
+    expires_at   DATETIME DEFAULT NULL,
+    last_used_at DATETIME DEFAULT NULL,
+    created_at   DATETIME DEFAULT CURRENT_TIMESTAMP,
+    INDEX (username)
+);
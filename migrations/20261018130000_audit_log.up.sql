@@ -0,0 +1,16 @@
+CREATE TABLE audit_log
+(
+    id           BIGINT AUTO_INCREMENT NOT NULL PRIMARY KEY,
+    username     VARCHAR(20)  DEFAULT NULL,
+    session      VARCHAR(64)  DEFAULT NULL,
+    action       ENUM ('login', 'login_failed', 'logout', 'create', 'update', 'delete', 'sort') NOT NULL,
+    entity       ENUM ('user', 'session', 'access_token', 'category', 'site', 'upload') NOT NULL,
+    entity_id    VARCHAR(255) DEFAULT NULL,
+    remote_ip    VARCHAR(45)  DEFAULT NULL,
+    before_value JSON         DEFAULT NULL,
+    after_value  JSON         DEFAULT NULL,
+    created_at   DATETIME     NOT NULL,
+    INDEX (username),
+    INDEX (action),
+    INDEX (created_at)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE category
+DROP COLUMN is_public;
@@ -0,0 +1,2 @@
+ALTER TABLE category
+ADD COLUMN is_public BOOLEAN NOT NULL DEFAULT FALSE AFTER parent_id;
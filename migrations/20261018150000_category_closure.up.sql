@@ -0,0 +1,18 @@
+CREATE TABLE category_closure
+(
+    ancestor   INT NOT NULL,
+    descendant INT NOT NULL,
+    depth      INT NOT NULL,
+    PRIMARY KEY (ancestor, descendant),
+    INDEX (descendant, depth)
+);
+
+INSERT INTO category_closure (ancestor, descendant, depth)
+WITH RECURSIVE closure AS (
+    SELECT id AS ancestor, id AS descendant, 0 AS depth FROM category
+    UNION ALL
+    SELECT closure.ancestor, category.id, closure.depth + 1
+    FROM closure
+    INNER JOIN category ON category.parent_id = closure.descendant
+)
+SELECT ancestor, descendant, depth FROM closure;
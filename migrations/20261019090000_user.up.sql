@@ -0,0 +1,2 @@
+ALTER TABLE user
+ADD COLUMN source ENUM ('local', 'ldap') NOT NULL DEFAULT 'local' AFTER disabled;
@@ -0,0 +1,6 @@
+DROP TABLE user_identity;
+
+UPDATE user SET source = 'local' WHERE source = 'oidc';
+
+ALTER TABLE user
+MODIFY COLUMN source ENUM ('local', 'ldap') NOT NULL DEFAULT 'local';
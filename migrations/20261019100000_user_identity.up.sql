@@ -0,0 +1,13 @@
+ALTER TABLE user
+MODIFY COLUMN source ENUM ('local', 'ldap', 'oidc') NOT NULL DEFAULT 'local';
+
+CREATE TABLE user_identity
+(
+    issuer     VARCHAR(255) NOT NULL,
+    subject    VARCHAR(255) NOT NULL,
+    username   VARCHAR(20)  NOT NULL,
+    created_at DATETIME     NOT NULL,
+    PRIMARY KEY (issuer, subject),
+    INDEX (username),
+    FOREIGN KEY (username) REFERENCES user (username) ON UPDATE CASCADE ON DELETE CASCADE
+);
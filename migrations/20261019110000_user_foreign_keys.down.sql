@@ -0,0 +1,5 @@
+ALTER TABLE recovery_code
+DROP FOREIGN KEY recovery_code_username;
+
+ALTER TABLE access_token
+DROP FOREIGN KEY access_token_username;
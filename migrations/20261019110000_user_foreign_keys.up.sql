@@ -0,0 +1,11 @@
+-- The column-level REFERENCES clauses were ignored by MySQL, so rows of
+-- deleted or renamed users may be left behind.
+DELETE FROM access_token WHERE username NOT IN (SELECT username FROM user);
+
+DELETE FROM recovery_code WHERE username NOT IN (SELECT username FROM user);
+
+ALTER TABLE access_token
+ADD CONSTRAINT access_token_username FOREIGN KEY (username) REFERENCES user (username) ON UPDATE CASCADE ON DELETE CASCADE;
+
+ALTER TABLE recovery_code
+ADD CONSTRAINT recovery_code_username FOREIGN KEY (username) REFERENCES user (username) ON UPDATE CASCADE ON DELETE CASCADE;
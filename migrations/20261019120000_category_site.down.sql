@@ -0,0 +1,3 @@
+ALTER TABLE category_site
+DROP PRIMARY KEY,
+DROP INDEX site_id;
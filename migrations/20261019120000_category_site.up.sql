@@ -0,0 +1,16 @@
+-- Nothing kept a site from being linked to the same category twice, which
+-- reassigning the sites of a deleted category did.
+CREATE TABLE category_site_unique
+(
+    category_id INT NOT NULL,
+    site_id     INT NOT NULL,
+    PRIMARY KEY (category_id, site_id),
+    INDEX (site_id)
+);
+
+INSERT IGNORE INTO category_site_unique (category_id, site_id)
+SELECT category_id, site_id FROM category_site;
+
+DROP TABLE category_site;
+
+RENAME TABLE category_site_unique TO category_site;
@@ -0,0 +1,3 @@
+ALTER TABLE category_closure
+DROP FOREIGN KEY category_closure_ancestor,
+DROP FOREIGN KEY category_closure_descendant;
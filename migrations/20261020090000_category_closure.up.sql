@@ -0,0 +1,47 @@
+-- Before parents were checked, a category could be made a child of itself or
+-- of one of its own descendants. The members of such cycles are moved to the
+-- top level and the closure is rebuilt from the repaired tree. The walk up is
+-- bounded by the number of categories, which no path without a cycle exceeds.
+SET @categories = (SELECT COUNT(id) FROM category);
+
+SET SESSION cte_max_recursion_depth = GREATEST(@@cte_max_recursion_depth, @categories + 1);
+
+UPDATE category
+SET parent_id = NULL
+WHERE id IN (
+    SELECT id FROM (
+        WITH RECURSIVE ancestry (id, ancestor, steps) AS (
+            SELECT id, parent_id, 1 FROM category WHERE parent_id IS NOT NULL
+            UNION ALL
+            SELECT ancestry.id, category.parent_id, ancestry.steps + 1
+            FROM ancestry
+            INNER JOIN category ON category.id = ancestry.ancestor
+            WHERE category.parent_id IS NOT NULL
+              AND ancestry.ancestor <> ancestry.id
+              AND ancestry.steps < @categories
+        )
+        SELECT DISTINCT id FROM ancestry WHERE ancestor = id
+    ) AS cycle_member
+);
+
+UPDATE category
+SET parent_id = NULL
+WHERE parent_id IS NOT NULL
+  AND parent_id NOT IN (SELECT id FROM (SELECT id FROM category) AS existing);
+
+DELETE FROM category_closure;
+
+INSERT INTO category_closure (ancestor, descendant, depth)
+WITH RECURSIVE closure AS (
+    SELECT id AS ancestor, id AS descendant, 0 AS depth FROM category
+    UNION ALL
+    SELECT closure.ancestor, category.id, closure.depth + 1
+    FROM closure
+    INNER JOIN category ON category.parent_id = closure.descendant
+    WHERE closure.depth < @categories
+)
+SELECT ancestor, descendant, depth FROM closure;
+
+ALTER TABLE category_closure
+ADD CONSTRAINT category_closure_ancestor FOREIGN KEY (ancestor) REFERENCES category (id) ON DELETE CASCADE,
+ADD CONSTRAINT category_closure_descendant FOREIGN KEY (descendant) REFERENCES category (id) ON DELETE CASCADE;
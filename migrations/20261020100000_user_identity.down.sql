@@ -0,0 +1,2 @@
+-- The trailing slashes that were removed are not known any more.
+DO 0;
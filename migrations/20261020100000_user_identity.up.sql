@@ -0,0 +1,3 @@
+-- Issuers are stored without a trailing slash, however the provider or the
+-- configuration spells them.
+UPDATE IGNORE user_identity SET issuer = TRIM(TRAILING '/' FROM issuer);
@@ -2,12 +2,16 @@ use rocket::fairing::AdHoc;
 use rocket::figment::providers::{Format, Serialized, Toml};
 use rocket::figment::{Figment, Profile};
 use rocket::fs::FileServer;
-use rocket::{self, routes};
 use rocket_db_pools::Database;
 
+use startpage::captcha;
 use startpage::config::Config;
-use startpage::routes::upload::upload;
-use startpage::routes::{auth, category, site, user};
+use startpage::guards::remote_ip::TrustedProxies;
+use startpage::handlers::session::SessionPolicy;
+use startpage::keys::JwtKeys;
+use startpage::mailer::Mailer;
+use startpage::password::PasswordPolicy;
+use startpage::routes;
 use startpage::state::AppState;
 use startpage::utils::parse_duration;
 use startpage::{MySQLDb, RedisDb};
@@ -38,6 +42,7 @@ pub fn setup_logger() -> Result<(), fern::InitError> {
     Ok(())
 }
 
+#[allow(clippy::result_large_err)]
 #[rocket::main]
 async fn main() -> Result<(), rocket::Error> {
     if !cfg!(debug_assertions) {
@@ -56,6 +61,29 @@ async fn main() -> Result<(), rocket::Error> {
     let jwt_expiration =
         parse_duration(&config.jwt.expires_in).expect("Failed to parse jwt expiration");
 
+    let refresh_expiration = parse_duration(&config.jwt.refresh_expires_in)
+        .expect("Failed to parse refresh token expiration");
+
+    let jwt_keys = JwtKeys::load(&config.jwt).expect("Failed to load jwt keys");
+
+    let captcha = captcha::from_config(&config.captcha).expect("Failed to set up captcha");
+
+    let trusted_proxies =
+        TrustedProxies::from_config(&config.proxy).expect("Failed to parse trusted proxies");
+
+    let password_policy =
+        PasswordPolicy::from_config(&config.password).expect("Failed to load password policy");
+
+    let session_policy =
+        SessionPolicy::from_config(&config.session).expect("Failed to load session policy");
+
+    let mailer = config
+        .smtp
+        .as_ref()
+        .map(Mailer::from_config)
+        .transpose()
+        .expect("Failed to set up mailer");
+
     let upload_url = figment
         .extract::<Config>()
         .expect("Failed to extract app config")
@@ -66,45 +94,29 @@ async fn main() -> Result<(), rocket::Error> {
         .expect("Failed to extract app config")
         .upload_dir;
 
-    let state = AppState { jwt_expiration };
+    let state = AppState {
+        jwt_expiration,
+        refresh_expiration,
+        jwt_keys,
+        captcha,
+        trusted_proxies,
+        password_policy,
+        session_policy,
+        mailer,
+    };
 
-    let _rok = rocket::custom(figment)
+    let mut rocket = rocket::custom(figment)
         .manage(state)
         .attach(MySQLDb::init())
         .attach(RedisDb::init())
-        .mount(
-            "/api/user",
-            routes![user::me, user::update, user::update_password],
-        )
-        .mount("/api/auth", routes![auth::login, auth::logout])
-        .mount("/api/categories", routes![category::all])
-        .mount(
-            "/api/category",
-            routes![
-                category::update,
-                category::add,
-                category::delete,
-                category::get_sites,
-                category::sort,
-                category::sort_sites,
-            ],
-        )
-        .mount("/api/sites", routes![site::all])
-        .mount(
-            "/api/site",
-            routes![
-                site::get,
-                site::add,
-                site::update,
-                site::delete,
-                site::analytics
-            ],
-        )
-        .mount("/api/upload", routes![upload])
         .mount(upload_url, FileServer::from(upload_dir))
-        .attach(AdHoc::config::<Config>())
-        .launch()
-        .await?;
+        .attach(AdHoc::config::<Config>());
+
+    for (base, routes) in routes::mounts() {
+        rocket = rocket.mount(base, routes);
+    }
+
+    let _rok = rocket.launch().await?;
 
     Ok(())
 }
@@ -0,0 +1,326 @@
+use chrono::Duration;
+use log::{error, warn};
+use rocket::futures::TryFutureExt;
+use rocket_db_pools::deadpool_redis::redis::AsyncCommands;
+use rocket_db_pools::Connection;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::config::{Captcha, CaptchaProvider};
+use crate::errors::ServiceError;
+use crate::response::captcha::{CaptchaChallenge, SiteVerifyResponse};
+use crate::utils::parse_duration;
+use crate::RedisDb;
+
+#[rocket::async_trait]
+pub trait CaptchaVerifier: Send + Sync {
+    /*
+     * Hands out a challenge for providers that issue their own, `None` for
+     * the ones whose widget talks to the provider directly.
+     */
+    async fn challenge(
+        &self,
+        _cache: &mut Connection<RedisDb>,
+    ) -> Result<Option<CaptchaChallenge>, ServiceError> {
+        Ok(None)
+    }
+
+    async fn verify(
+        &self,
+        token: &str,
+        remote_ip: Option<&str>,
+        cache: &mut Connection<RedisDb>,
+    ) -> Result<bool, ServiceError>;
+}
+
+/*
+ * Turnstile, hCaptcha and reCAPTCHA all verify tokens by posting them to a
+ * siteverify endpoint along with the secret.
+ */
+pub struct SiteVerify {
+    name: &'static str,
+    url: String,
+    secret: String,
+    min_score: f64,
+    client: reqwest::Client,
+}
+
+impl SiteVerify {
+    pub fn new(name: &'static str, url: String, secret: String, min_score: f64) -> Self {
+        Self {
+            name,
+            url,
+            secret,
+            min_score,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    async fn siteverify(&self, token: &str, remote_ip: Option<&str>) -> Result<bool, ServiceError> {
+        let params: [(&str, Option<&str>); 3] = [
+            ("secret", Some(&self.secret)),
+            ("response", Some(token)),
+            ("remoteip", remote_ip),
+        ];
+
+        let response = self
+            .client
+            .post(&self.url)
+            .form(&params)
+            .send()
+            .map_err(|e| {
+                error!("Failed to send request to {}: {}", self.name, e);
+
+                ServiceError::InternalServerError
+            })
+            .await?
+            .json::<SiteVerifyResponse>()
+            .map_err(|e| {
+                error!("Failed to parse {} response: {}", self.name, e);
+
+                ServiceError::InternalServerError
+            })
+            .await?;
+
+        if !response.success {
+            warn!("{} rejected token: {:?}", self.name, response.error_codes);
+
+            return Ok(false);
+        }
+
+        Ok(response.score.is_none_or(|score| score >= self.min_score))
+    }
+}
+
+#[rocket::async_trait]
+impl CaptchaVerifier for SiteVerify {
+    async fn verify(
+        &self,
+        token: &str,
+        remote_ip: Option<&str>,
+        _cache: &mut Connection<RedisDb>,
+    ) -> Result<bool, ServiceError> {
+        self.siteverify(token, remote_ip).await
+    }
+}
+
+/*
+ * A self-hosted captcha that makes clients burn CPU time instead of solving
+ * puzzles. Challenges are single-use and expire.
+ */
+pub struct ProofOfWork {
+    difficulty: u32,
+    expiration: Duration,
+}
+
+impl ProofOfWork {
+    pub fn new(difficulty: u32, expiration: Duration) -> Self {
+        Self {
+            difficulty,
+            expiration,
+        }
+    }
+}
+
+fn challenge_key(challenge: &str) -> String {
+    format!("captcha_challenge:{}", challenge)
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+
+    for byte in hash {
+        bits += byte.leading_zeros();
+
+        if *byte != 0 {
+            break;
+        }
+    }
+
+    bits
+}
+
+fn solves(challenge: &str, nonce: &str, difficulty: u32) -> bool {
+    let hash = Sha256::digest(format!("{}:{}", challenge, nonce).as_bytes());
+
+    leading_zero_bits(&hash) >= difficulty
+}
+
+#[rocket::async_trait]
+impl CaptchaVerifier for ProofOfWork {
+    async fn challenge(
+        &self,
+        cache: &mut Connection<RedisDb>,
+    ) -> Result<Option<CaptchaChallenge>, ServiceError> {
+        let challenge = Uuid::new_v4().simple().to_string();
+
+        cache
+            .pset_ex::<_, _, ()>(
+                challenge_key(&challenge),
+                self.difficulty,
+                self.expiration.num_milliseconds() as usize,
+            )
+            .map_err(|e| {
+                error!("Failed to set captcha challenge: {}", e);
+
+                ServiceError::InternalServerError
+            })
+            .await?;
+
+        Ok(Some(CaptchaChallenge {
+            challenge,
+            difficulty: self.difficulty,
+        }))
+    }
+
+    async fn verify(
+        &self,
+        token: &str,
+        _remote_ip: Option<&str>,
+        cache: &mut Connection<RedisDb>,
+    ) -> Result<bool, ServiceError> {
+        let (challenge, nonce) = match token.split_once(':') {
+            Some(parts) => parts,
+            None => return Ok(false),
+        };
+
+        // Deleting claims the challenge, so every solution is accepted once.
+        let difficulty = cache
+            .get_del::<_, Option<u32>>(challenge_key(challenge))
+            .map_err(|e| {
+                error!("Failed to get captcha challenge: {}", e);
+
+                ServiceError::InternalServerError
+            })
+            .await?;
+
+        Ok(match difficulty {
+            Some(difficulty) => solves(challenge, nonce, difficulty),
+            None => false,
+        })
+    }
+}
+
+pub fn from_config(config: &Captcha) -> Result<Option<Box<dyn CaptchaVerifier>>, ServiceError> {
+    let (name, default_url) = match config.provider {
+        CaptchaProvider::None => return Ok(None),
+        CaptchaProvider::Pow => {
+            return Ok(Some(Box::new(ProofOfWork::new(
+                config.difficulty,
+                parse_duration(&config.expires_in)?,
+            ))))
+        }
+        CaptchaProvider::Turnstile => (
+            "Turnstile",
+            "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+        ),
+        CaptchaProvider::HCaptcha => ("hCaptcha", "https://api.hcaptcha.com/siteverify"),
+        CaptchaProvider::ReCaptcha => (
+            "reCAPTCHA",
+            "https://www.google.com/recaptcha/api/siteverify",
+        ),
+    };
+
+    if config.secret.is_empty() {
+        return Err(ServiceError::FormatError(format!(
+            "Missing {} secret",
+            name
+        )));
+    }
+
+    let url = config
+        .verify_url
+        .clone()
+        .unwrap_or_else(|| String::from(default_url));
+
+    Ok(Some(Box::new(SiteVerify::new(
+        name,
+        url,
+        config.secret.clone(),
+        config.min_score,
+    ))))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    fn mock_siteverify(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let url = format!("http://{}/siteverify", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut request = [0u8; 4096];
+
+            let _ = stream.read(&mut request).unwrap();
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+
+        url
+    }
+
+    #[rocket::async_test]
+    async fn test_siteverify() {
+        let accepted = SiteVerify::new(
+            "mock",
+            mock_siteverify(r#"{"success": true, "score": 0.9}"#),
+            String::from("secret"),
+            0.5,
+        );
+
+        let low_score = SiteVerify::new(
+            "mock",
+            mock_siteverify(r#"{"success": true, "score": 0.1}"#),
+            String::from("secret"),
+            0.5,
+        );
+
+        let rejected = SiteVerify::new(
+            "mock",
+            mock_siteverify(r#"{"success": false, "error-codes": ["invalid-input-response"]}"#),
+            String::from("secret"),
+            0.5,
+        );
+
+        assert!(accepted.siteverify("token", None).await.unwrap());
+        assert!(!low_score.siteverify("token", None).await.unwrap());
+        assert!(!rejected
+            .siteverify("token", Some("127.0.0.1"))
+            .await
+            .unwrap());
+    }
+
+    #[test]
+    fn test_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff, 0x00]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10, 0x00]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn test_solves() {
+        let challenge = "challenge";
+
+        let nonce = (0..)
+            .map(|nonce: u64| nonce.to_string())
+            .find(|nonce| solves(challenge, nonce, 8))
+            .unwrap();
+
+        assert!(solves(challenge, &nonce, 8));
+        assert!(!solves("other", &nonce, 64));
+    }
+}
@@ -1,11 +1,32 @@
 use std::path::PathBuf;
 
+use jsonwebtoken::Algorithm;
 use serde::{Deserialize, Serialize};
 
+use crate::models::user::Role;
+
+/*
+ * A PEM key session tokens are signed or verified with, announced by `kid`.
+ * The first key with a `private_key` signs new tokens; keys with only a
+ * `public_key` are kept around to verify tokens issued before a rotation.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JwtKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub private_key: Option<PathBuf>,
+    pub public_key: Option<PathBuf>,
+}
+
+/*
+ * Without `keys`, tokens are signed with HS256 using `secret`.
+ */
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Jwt {
     pub secret: String,
     pub expires_in: String,
+    pub refresh_expires_in: String,
+    pub keys: Vec<JwtKey>,
 }
 
 impl Default for Jwt {
@@ -13,15 +34,334 @@ impl Default for Jwt {
         Self {
             secret: String::from("StartPage"),
             expires_in: String::from("1h"),
+            refresh_expires_in: String::from("30d"),
+            keys: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Totp {
+    pub issuer: String,
+    pub recovery_codes: usize,
+}
+
+impl Default for Totp {
+    fn default() -> Self {
+        Self {
+            issuer: String::from("StartPage"),
+            recovery_codes: 10,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LdapGroup {
+    pub dn: String,
+    pub role: Role,
+}
+
+/*
+ * A directory to authenticate against before falling back to local
+ * passwords. Users are looked up with `user_filter`, in which `{username}` is
+ * replaced, using the `bind_dn` service account, and then bound as themselves.
+ * Their role is the highest one of the `groups` listed in `group_attribute`,
+ * or `default_role`. The `user` row is created (with `auto_provision`) or
+ * synced from the directory on every login; local accounts of the same name
+ * are left alone. A directory that does not answer within `timeout` is
+ * treated as unreachable.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Ldap {
+    pub url: String,
+    pub starttls: bool,
+    pub bind_dn: String,
+    pub bind_password: String,
+    pub base_dn: String,
+    pub user_filter: String,
+    pub nickname_attribute: String,
+    pub email_attribute: String,
+    pub avatar_attribute: Option<String>,
+    pub group_attribute: String,
+    pub groups: Vec<LdapGroup>,
+    pub default_role: Role,
+    pub auto_provision: bool,
+    pub timeout: String,
+}
+
+impl Default for Ldap {
+    fn default() -> Self {
+        Self {
+            url: String::from("ldap://localhost:389"),
+            starttls: false,
+            bind_dn: String::new(),
+            bind_password: String::new(),
+            base_dn: String::new(),
+            user_filter: String::from("(uid={username})"),
+            nickname_attribute: String::from("cn"),
+            email_attribute: String::from("mail"),
+            avatar_attribute: None,
+            group_attribute: String::from("memberOf"),
+            groups: Vec::new(),
+            default_role: Role::Viewer,
+            auto_provision: false,
+            timeout: String::from("10s"),
+        }
+    }
+}
+
+/*
+ * Rules new passwords have to follow. `breached_list` is a file of known
+ * leaked passwords, one per line, that are refused regardless of length.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Password {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub breached_list: Option<PathBuf>,
+}
+
+impl Default for Password {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 128,
+            breached_list: None,
+        }
+    }
+}
+
+/*
+ * Failed logins are counted per client IP and per username within `window`.
+ * Once a counter reaches its threshold the key is locked out for `lockout`,
+ * doubling with every further failure up to `max_lockout`.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub ip_threshold: i64,
+    pub username_threshold: i64,
+    pub window: String,
+    pub lockout: String,
+    pub max_lockout: String,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            ip_threshold: 20,
+            username_threshold: 5,
+            window: String::from("15m"),
+            lockout: String::from("30s"),
+            max_lockout: String::from("1h"),
+        }
+    }
+}
+
+/*
+ * An OpenID Connect provider to log in with instead of a local password.
+ * `redirect_url` is the frontend page that hands `code` and `state` on to
+ * `/api/auth/oidc/callback`. The `username_claim` (`preferred_username`,
+ * `email` or `sub`) is matched against `user.username`; unknown users are
+ * created with `default_role` when `auto_provision` is set.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Oidc {
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    pub username_claim: String,
+    pub auto_provision: bool,
+    pub default_role: Role,
+}
+
+impl Default for Oidc {
+    fn default() -> Self {
+        Self {
+            issuer_url: String::new(),
+            client_id: String::new(),
+            client_secret: None,
+            redirect_url: String::new(),
+            scopes: vec![String::from("profile"), String::from("email")],
+            username_claim: String::from("preferred_username"),
+            auto_provision: false,
+            default_role: Role::Viewer,
+        }
+    }
+}
+
+/*
+ * Proxies in `trusted` (CIDRs) may tell the client IP in one of `headers`,
+ * tried in order. Requests from anywhere else are attributed to the socket
+ * address, whatever headers they carry.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Proxy {
+    pub trusted: Vec<String>,
+    pub headers: Vec<String>,
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Self {
+            trusted: Vec::new(),
+            headers: vec![
+                String::from("X-Forwarded-For"),
+                String::from("X-Real-IP"),
+                String::from("Forwarded"),
+                String::from("CF-Connecting-IP"),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptchaProvider {
+    None,
+    Turnstile,
+    HCaptcha,
+    ReCaptcha,
+    Pow,
+}
+
+/*
+ * The captcha a login has to solve. `verify_url` overrides the provider's
+ * siteverify endpoint, e.g. to point it at a mock; `min_score` only applies
+ * to providers that score their answers. The self-hosted `pow` provider hands
+ * out challenges that take about 2^`difficulty` hashes to solve.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Captcha {
+    pub provider: CaptchaProvider,
+    pub secret: String,
+    pub verify_url: Option<String>,
+    pub min_score: f64,
+    pub difficulty: u32,
+    pub expires_in: String,
+}
+
+impl Default for Captcha {
+    fn default() -> Self {
+        Self {
+            provider: CaptchaProvider::None,
+            secret: String::new(),
+            verify_url: None,
+            min_score: 0.5,
+            difficulty: 18,
+            expires_in: String::from("5m"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    None,
+    StartTls,
+    Tls,
+}
+
+/*
+ * The relay password reset and email verification links are sent through.
+ * The links point at the frontend pages `reset_url` and `verify_url` with the
+ * token appended as `?token=`; the frontend posts it back to the API.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Smtp {
+    pub host: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub reset_url: String,
+    pub verify_url: String,
+    pub reset_expires_in: String,
+    pub verify_expires_in: String,
+}
+
+impl Default for Smtp {
+    fn default() -> Self {
+        Self {
+            host: String::from("localhost"),
+            port: 587,
+            tls: SmtpTls::StartTls,
+            username: None,
+            password: None,
+            from: String::from("StartPage <noreply@localhost>"),
+            reset_url: String::from("http://localhost:3000/reset-password"),
+            verify_url: String::from("http://localhost:3000/verify-email"),
+            reset_expires_in: String::from("30m"),
+            verify_expires_in: String::from("24h"),
+        }
+    }
+}
+
+/*
+ * Ties sessions to the device that signed in. With `bind_fingerprint` a token
+ * is only accepted from the user agent it was issued to and from the same
+ * network, the client IP cut down to `ipv4_prefix` or `ipv6_prefix` bits. A
+ * session that is not used for `idle_timeout` is revoked, however long its
+ * tokens would still be valid.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Session {
+    pub bind_fingerprint: bool,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    pub idle_timeout: Option<String>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            bind_fingerprint: false,
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+            idle_timeout: None,
         }
     }
 }
 
+/*
+ * Who may read the start page: anyone, only signed-in users, or anyone for
+ * the categories marked public and signed-in users for the rest.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Private,
+    Mixed,
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Config {
     pub jwt: Jwt,
+    pub totp: Totp,
+    pub password: Password,
+    pub rate_limit: RateLimit,
+    pub captcha: Captcha,
+    pub proxy: Proxy,
+    pub oidc: Option<Oidc>,
+    pub ldap: Option<Ldap>,
+    pub smtp: Option<Smtp>,
+    pub session: Session,
+    pub visibility: Visibility,
+    /*
+     * How many levels categories may be nested, unlimited if unset.
+     */
+    pub max_category_depth: Option<usize>,
     pub upload_dir: PathBuf,
     pub upload_url: String,
-    pub turnstile_secret: Option<String>,
-    pub turnstile_url: Option<String>,
 }
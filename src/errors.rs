@@ -24,6 +24,12 @@ pub enum ServiceError {
 
     #[display(fmt = "{}", _0)]
     AlreadyExists(String),
+
+    #[display(fmt = "Conflict: {}", _0)]
+    Conflict(String),
+
+    #[display(fmt = "Too many requests, retry after {} seconds", _0)]
+    TooManyRequests(i64),
 }
 
 impl From<sqlx::Error> for ServiceError {
@@ -54,6 +60,15 @@ impl ServiceError {
             ServiceError::InternalServerError => Status::InternalServerError,
             ServiceError::BadRequest(_) => Status::BadRequest,
             ServiceError::AlreadyExists(_) => Status::Conflict,
+            ServiceError::Conflict(_) => Status::Conflict,
+            ServiceError::TooManyRequests(_) => Status::TooManyRequests,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<i64> {
+        match self {
+            ServiceError::TooManyRequests(seconds) => Some(*seconds),
+            _ => None,
         }
     }
 }
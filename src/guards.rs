@@ -1,2 +1,8 @@
+pub mod csrf;
+pub mod device;
 pub mod jwt;
 pub mod remote_ip;
+pub mod role;
+pub mod scope;
+pub mod user_agent;
+pub mod visibility;
@@ -0,0 +1,46 @@
+use rocket::http::Method;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+pub const CSRF_COOKIE: &str = "csrf_token";
+
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/*
+ * Double-submit check for requests authenticated by cookie: a page on another
+ * site can make the browser send the cookies, but cannot read the CSRF cookie
+ * to echo it back in the header.
+ */
+pub(crate) fn verify_csrf(request: &Request<'_>) -> bool {
+    if matches!(
+        request.method(),
+        Method::Get | Method::Head | Method::Options
+    ) {
+        return true;
+    }
+
+    let cookie = match request.cookies().get(CSRF_COOKIE) {
+        Some(cookie) => cookie.value(),
+        None => return false,
+    };
+
+    match request.headers().get_one(CSRF_HEADER) {
+        Some(header) => !cookie.is_empty() && header == cookie,
+        None => false,
+    }
+}
+
+pub struct Csrf {
+    pub verified: bool,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Csrf {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Csrf {
+            verified: verify_csrf(request),
+        })
+    }
+}
@@ -0,0 +1,29 @@
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+use crate::guards::{remote_ip::Ip, user_agent::UserAgent};
+
+pub struct Device {
+    pub(crate) remote_ip: Option<String>,
+    pub(crate) user_agent: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Device {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let remote_ip = request.guard::<Ip>().await.succeeded().and_then(|ip| ip.0);
+
+        let user_agent = request
+            .guard::<UserAgent>()
+            .await
+            .succeeded()
+            .and_then(|user_agent| user_agent.0);
+
+        Outcome::Success(Device {
+            remote_ip,
+            user_agent,
+        })
+    }
+}
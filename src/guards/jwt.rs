@@ -1,23 +1,47 @@
-use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
 use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome};
 use rocket::Request;
 use rocket_db_pools::deadpool_redis::redis::AsyncCommands;
 
-use crate::config::Config;
+use crate::guards::csrf::verify_csrf;
+use crate::guards::remote_ip::Ip;
+use crate::guards::user_agent::UserAgent;
+use crate::handlers::session::{touch_session, SessionUse};
+use crate::state::AppState;
 use crate::{Claims, RedisDb};
 
+pub const TOKEN_COOKIE: &str = "token";
+
 pub struct Middleware {
     pub session: String,
 }
 
-#[derive(Debug)]
+impl Middleware {
+    pub fn username(&self) -> &str {
+        match self.session.split_once(':') {
+            Some((username, _)) => username,
+            None => &self.session,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum JwtError {
     ConfigError,
     CacheError,
     MissingToken,
     InvalidToken,
+    InvalidCsrfToken,
     ExpiredToken,
+    IdleSession,
+    FingerprintMismatch,
+}
+
+pub(crate) fn bearer<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    request
+        .headers()
+        .get_one("Authorization")?
+        .strip_prefix("Bearer ")
 }
 
 #[rocket::async_trait]
@@ -25,49 +49,76 @@ impl<'r> FromRequest<'r> for Middleware {
     type Error = JwtError;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let config = match request.rocket().figment().extract::<Config>() {
-            Ok(config) => config,
-            Err(_) => return Outcome::Error((Status::InternalServerError, JwtError::ConfigError)),
-        };
-
-        let token = match request.headers().get_one("Authorization") {
-            Some(token) => token,
-            None => return Outcome::Error((Status::Unauthorized, JwtError::MissingToken)),
+        let state = match request.rocket().state::<AppState>() {
+            Some(state) => state,
+            None => return Outcome::Error((Status::InternalServerError, JwtError::ConfigError)),
         };
 
-        let token = match token.strip_prefix("Bearer ") {
+        // Browsers send the token as an HttpOnly cookie instead of the header,
+        // which a forged cross-site request would carry as well.
+        let token = match bearer(request) {
             Some(token) => token,
-            None => return Outcome::Error((Status::Unauthorized, JwtError::MissingToken)),
+            None => match request.cookies().get(TOKEN_COOKIE) {
+                Some(cookie) if verify_csrf(request) => cookie.value(),
+                Some(_) => return Outcome::Error((Status::Forbidden, JwtError::InvalidCsrfToken)),
+                None => return Outcome::Error((Status::Unauthorized, JwtError::MissingToken)),
+            },
         };
 
-        let token_data = match decode::<Claims>(
-            token,
-            &DecodingKey::from_secret(config.jwt.secret.as_bytes()),
-            &Validation::new(Algorithm::HS256),
-        ) {
-            Ok(token) => token,
-            Err(_) => return Outcome::Error((Status::Unauthorized, JwtError::InvalidToken)),
+        let claims = match state.jwt_keys.decode::<Claims>(token) {
+            Some(claims) => claims,
+            None => return Outcome::Error((Status::Unauthorized, JwtError::InvalidToken)),
         };
 
-        let session = token_data.claims.sub.clone();
+        let session = claims.sub;
 
-        let is_in_white_list: &Option<bool> = request
+        // Cached, so a request checked by several guards is only touched once.
+        let checked: &Result<(), JwtError> = request
             .local_cache_async(async {
-                let redis = request.guard::<&RedisDb>().await.succeeded()?;
-                let mut connection = redis.get().await.ok()?;
+                let redis = request
+                    .guard::<&RedisDb>()
+                    .await
+                    .succeeded()
+                    .ok_or(JwtError::CacheError)?;
+
+                let mut connection = redis.get().await.map_err(|_| JwtError::CacheError)?;
+
+                let result = connection
+                    .get::<_, String>(&session)
+                    .await
+                    .map_err(|_| JwtError::CacheError)?;
 
-                let result = connection.get::<_, String>(&session).await.ok()?;
+                if result != token {
+                    return Err(JwtError::InvalidToken);
+                }
 
-                Some(result == token)
+                let remote_ip = request.guard::<Ip>().await.succeeded().and_then(|ip| ip.0);
+
+                let user_agent = request
+                    .guard::<UserAgent>()
+                    .await
+                    .succeeded()
+                    .and_then(|user_agent| user_agent.0);
+
+                match touch_session(
+                    &session,
+                    remote_ip.as_deref(),
+                    user_agent.as_deref(),
+                    &state.session_policy,
+                    &mut *connection,
+                )
+                .await
+                {
+                    Ok(SessionUse::Active) => Ok(()),
+                    Ok(SessionUse::Idle) => Err(JwtError::IdleSession),
+                    Ok(SessionUse::Elsewhere) => Err(JwtError::FingerprintMismatch),
+                    Err(_) => Err(JwtError::CacheError),
+                }
             })
             .await;
 
-        if is_in_white_list.is_none() {
-            return Outcome::Error((Status::Unauthorized, JwtError::CacheError));
-        }
-
-        if *is_in_white_list == Some(false) {
-            return Outcome::Error((Status::Unauthorized, JwtError::InvalidToken));
+        if let Err(e) = checked {
+            return Outcome::Error((Status::Unauthorized, *e));
         }
 
         Outcome::Success(Middleware { session })
@@ -1,18 +1,250 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use rocket::http::HeaderMap;
 use rocket::request::{FromRequest, Outcome};
 use rocket::Request;
 
+use crate::config::Proxy;
+use crate::errors::ServiceError;
+use crate::state::AppState;
+
 pub struct Ip(pub(crate) Option<String>);
 
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+    headers: Vec<String>,
+}
+
+impl TrustedProxies {
+    pub fn from_config(config: &Proxy) -> Result<Self, ServiceError> {
+        let networks = config
+            .trusted
+            .iter()
+            .map(|network| {
+                network
+                    .parse::<IpNet>()
+                    .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|e| {
+                        ServiceError::FormatError(format!("Invalid proxy {}: {}", network, e))
+                    })
+            })
+            .collect::<Result<Vec<IpNet>, ServiceError>>()?;
+
+        Ok(Self {
+            networks,
+            headers: config.headers.clone(),
+        })
+    }
+
+    fn trusts(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /*
+     * Walks the hops a header lists from the nearest one back and returns the
+     * first that is not a trusted proxy, as everything before it could have
+     * been made up by the client. A hop that cannot be read ends the walk at
+     * the last address known, so garbage cannot skip to a made-up one.
+     */
+    pub fn resolve(&self, peer: Option<IpAddr>, headers: &HeaderMap<'_>) -> Option<IpAddr> {
+        let peer = peer?;
+
+        if !self.trusts(&peer) {
+            return Some(peer);
+        }
+
+        for name in &self.headers {
+            let hops = headers
+                .get(name)
+                .flat_map(|value| parse_hops(name, value))
+                .collect::<Vec<Option<IpAddr>>>();
+
+            if hops.is_empty() {
+                continue;
+            }
+
+            let mut client = peer;
+
+            for hop in hops.iter().rev() {
+                match hop {
+                    Some(hop) => {
+                        client = *hop;
+
+                        if !self.trusts(hop) {
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            }
+
+            return Some(client);
+        }
+
+        Some(peer)
+    }
+}
+
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(ip);
+    }
+
+    // `[2001:db8::1]:4711` or `192.0.2.60:8080`
+    let host = match value.strip_prefix('[') {
+        Some(rest) => rest.split(']').next()?,
+        None => value.rsplit_once(':')?.0,
+    };
+
+    host.parse::<IpAddr>().ok()
+}
+
+fn parse_hops(name: &str, value: &str) -> Vec<Option<IpAddr>> {
+    if value.trim().is_empty() {
+        return Vec::new();
+    }
+
+    if name.eq_ignore_ascii_case("Forwarded") {
+        return value
+            .split(',')
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+
+                    match key.trim().eq_ignore_ascii_case("for") {
+                        true => parse_ip(value),
+                        false => None,
+                    }
+                })
+            })
+            .collect();
+    }
+
+    value.split(',').map(parse_ip).collect()
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Ip {
     type Error = ();
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let ip = request.headers().get_one("CF-Connecting-IP");
+        let peer = request.remote().map(|remote| remote.ip());
 
-        match ip {
-            Some(ip) => Outcome::Success(Ip(Some(ip.to_string()))),
-            None => Outcome::Success(Ip(None)),
+        let ip = match request.rocket().state::<AppState>() {
+            Some(state) => state.trusted_proxies.resolve(peer, request.headers()),
+            None => peer,
+        };
+
+        Outcome::Success(Ip(ip.map(|ip| ip.to_string())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocket::http::Header;
+
+    use super::*;
+
+    fn proxies(trusted: &[&str]) -> TrustedProxies {
+        TrustedProxies::from_config(&Proxy {
+            trusted: trusted
+                .iter()
+                .map(|network| String::from(*network))
+                .collect(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap<'static> {
+        let mut map = HeaderMap::new();
+
+        for (name, value) in headers {
+            map.add(Header::new(*name, *value));
         }
+
+        map
+    }
+
+    #[test]
+    fn test_untrusted_peer() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+
+        let ip = proxies.resolve(
+            "203.0.113.7".parse().ok(),
+            &headers(&[("X-Forwarded-For", "198.51.100.1")]),
+        );
+
+        assert_eq!(ip, "203.0.113.7".parse().ok());
+    }
+
+    #[test]
+    fn test_forwarded_for() {
+        let proxies = proxies(&["10.0.0.0/8", "127.0.0.1"]);
+
+        let ip = proxies.resolve(
+            "127.0.0.1".parse().ok(),
+            &headers(&[("X-Forwarded-For", "198.51.100.1, 203.0.113.9, 10.1.2.3")]),
+        );
+
+        assert_eq!(ip, "203.0.113.9".parse().ok());
+
+        let ip = proxies.resolve(
+            "127.0.0.1".parse().ok(),
+            &headers(&[("X-Forwarded-For", "10.2.0.1, 10.1.2.3")]),
+        );
+
+        assert_eq!(ip, "10.2.0.1".parse().ok());
+    }
+
+    #[test]
+    fn test_unreadable_hop() {
+        let proxies = proxies(&["10.0.0.0/8", "127.0.0.1"]);
+
+        let ip = proxies.resolve(
+            "127.0.0.1".parse().ok(),
+            &headers(&[("X-Forwarded-For", "1.2.3.4, garbage")]),
+        );
+
+        assert_eq!(ip, "127.0.0.1".parse().ok());
+
+        let ip = proxies.resolve(
+            "127.0.0.1".parse().ok(),
+            &headers(&[("X-Forwarded-For", "1.2.3.4, garbage, 10.1.2.3")]),
+        );
+
+        assert_eq!(ip, "10.1.2.3".parse().ok());
+
+        let ip = proxies.resolve(
+            "127.0.0.1".parse().ok(),
+            &headers(&[("Forwarded", "for=192.0.2.43, for=unknown")]),
+        );
+
+        assert_eq!(ip, "127.0.0.1".parse().ok());
+    }
+
+    #[test]
+    fn test_header_order() {
+        let proxies = proxies(&["127.0.0.1"]);
+
+        let ip = proxies.resolve(
+            "127.0.0.1".parse().ok(),
+            &headers(&[
+                ("CF-Connecting-IP", "198.51.100.1"),
+                (
+                    "Forwarded",
+                    "for=192.0.2.43, for=\"[2001:db8:cafe::17]:4711\";proto=https",
+                ),
+            ]),
+        );
+
+        assert_eq!(ip, "2001:db8:cafe::17".parse().ok());
+
+        let ip = proxies.resolve("127.0.0.1".parse().ok(), &headers(&[]));
+
+        assert_eq!(ip, "127.0.0.1".parse().ok());
     }
 }
@@ -0,0 +1,90 @@
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use sqlx::{query, Row};
+
+use crate::guards::jwt::{JwtError, Middleware};
+use crate::models::user::Role;
+use crate::MySQLDb;
+
+/*
+ * Sessions only. Content routes are guarded by `scope::Authorized` instead,
+ * which also lets access tokens in.
+ */
+pub struct Admin(pub Middleware);
+
+/*
+ * A session of the user the route is about, named by the first segment after
+ * the mount point, or of an admin acting on their behalf.
+ */
+pub struct SelfOrAdmin(pub Middleware);
+
+#[derive(Debug)]
+pub enum RoleError {
+    Jwt(JwtError),
+    InvalidToken,
+    DatabaseError,
+    Forbidden,
+}
+
+pub(crate) async fn authorize(
+    request: &Request<'_>,
+    required: Role,
+) -> Outcome<Middleware, RoleError> {
+    let jwt = match request.guard::<Middleware>().await {
+        Outcome::Success(jwt) => jwt,
+        Outcome::Error((status, e)) => return Outcome::Error((status, RoleError::Jwt(e))),
+        Outcome::Forward(status) => return Outcome::Forward(status),
+    };
+
+    let role: &Option<(Role, bool)> = request
+        .local_cache_async(async {
+            let db = request.guard::<&MySQLDb>().await.succeeded()?;
+
+            let row = query(r#"SELECT role, disabled FROM user WHERE username = ?"#)
+                .bind(jwt.username())
+                .fetch_one(&**db)
+                .await
+                .ok()?;
+
+            Some((row.try_get("role").ok()?, row.try_get("disabled").ok()?))
+        })
+        .await;
+
+    match role {
+        None => Outcome::Error((Status::InternalServerError, RoleError::DatabaseError)),
+        Some((_, true)) => Outcome::Error((Status::Forbidden, RoleError::Forbidden)),
+        Some((role, false)) if *role >= required => Outcome::Success(jwt),
+        Some(_) => Outcome::Error((Status::Forbidden, RoleError::Forbidden)),
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = RoleError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authorize(request, Role::Admin).await.map(Admin)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SelfOrAdmin {
+    type Error = RoleError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let jwt = match request.guard::<Middleware>().await {
+            Outcome::Success(jwt) => jwt,
+            Outcome::Error((status, e)) => return Outcome::Error((status, RoleError::Jwt(e))),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+
+        let target = request.param::<&str>(0).and_then(Result::ok);
+
+        if target == Some(jwt.username()) {
+            return Outcome::Success(SelfOrAdmin(jwt));
+        }
+
+        authorize(request, Role::Admin).await.map(SelfOrAdmin)
+    }
+}
@@ -0,0 +1,129 @@
+use std::marker::PhantomData;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use sqlx::{query, Row};
+
+use crate::guards::jwt::bearer;
+use crate::guards::role::{authorize, RoleError};
+use crate::handlers::access_token::ACCESS_TOKEN_PREFIX;
+use crate::models::access_token::{parse_scopes, Scope};
+use crate::models::user::Role;
+use crate::utils::hash_token;
+use crate::MySQLDb;
+
+/*
+ * A permission a route requires, named after the access token scope that
+ * grants it. Sessions are granted it by the role the scope asks for.
+ */
+pub trait Permission: Send + Sync + 'static {
+    const SCOPE: Scope;
+}
+
+pub struct CategoriesRead;
+
+pub struct CategoriesWrite;
+
+pub struct SitesRead;
+
+pub struct SitesWrite;
+
+pub struct Upload;
+
+impl Permission for CategoriesRead {
+    const SCOPE: Scope = Scope::CategoriesRead;
+}
+
+impl Permission for CategoriesWrite {
+    const SCOPE: Scope = Scope::CategoriesWrite;
+}
+
+impl Permission for SitesRead {
+    const SCOPE: Scope = Scope::SitesRead;
+}
+
+impl Permission for SitesWrite {
+    const SCOPE: Scope = Scope::SitesWrite;
+}
+
+impl Permission for Upload {
+    const SCOPE: Scope = Scope::Upload;
+}
+
+pub struct Authorized<P: Permission> {
+    pub username: String,
+    pub session: Option<String>,
+    permission: PhantomData<P>,
+}
+
+impl<P: Permission> Authorized<P> {
+    pub(crate) fn new(username: String, session: Option<String>) -> Self {
+        Self {
+            username,
+            session,
+            permission: PhantomData,
+        }
+    }
+}
+
+async fn authenticate_token(
+    request: &Request<'_>,
+    token: &str,
+) -> Option<(String, Vec<Scope>, Role)> {
+    let db = request.guard::<&MySQLDb>().await.succeeded()?;
+
+    let row = query(
+        r#"
+            SELECT t.id AS id, t.username AS username, t.scopes AS scopes, u.role AS role
+            FROM access_token AS t
+            INNER JOIN user AS u ON t.username = u.username
+            WHERE t.token = ? AND u.disabled = FALSE AND (t.expires_at IS NULL OR t.expires_at > UTC_TIMESTAMP())
+        "#,
+    )
+    .bind(hash_token(token))
+    .fetch_one(&**db)
+    .await
+    .ok()?;
+
+    let id = row.try_get::<i64, &str>("id").ok()?;
+
+    query(r#"UPDATE access_token SET last_used_at = UTC_TIMESTAMP() WHERE id = ?"#)
+        .bind(id)
+        .execute(&**db)
+        .await
+        .ok()?;
+
+    Some((
+        row.try_get("username").ok()?,
+        parse_scopes(row.try_get("scopes").ok()?),
+        row.try_get("role").ok()?,
+    ))
+}
+
+#[rocket::async_trait]
+impl<'r, P: Permission> FromRequest<'r> for Authorized<P> {
+    type Error = RoleError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = match bearer(request) {
+            Some(token) if token.starts_with(ACCESS_TOKEN_PREFIX) => token,
+            _ => {
+                return authorize(request, P::SCOPE.role())
+                    .await
+                    .map(|jwt| Authorized::new(String::from(jwt.username()), Some(jwt.session)))
+            }
+        };
+
+        let (username, scopes, role) = match authenticate_token(request, token).await {
+            Some(result) => result,
+            None => return Outcome::Error((Status::Unauthorized, RoleError::InvalidToken)),
+        };
+
+        if !scopes.contains(&P::SCOPE) || role < P::SCOPE.role() {
+            return Outcome::Error((Status::Forbidden, RoleError::Forbidden));
+        }
+
+        Outcome::Success(Authorized::new(username, None))
+    }
+}
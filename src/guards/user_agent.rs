@@ -0,0 +1,18 @@
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+pub struct UserAgent(pub(crate) Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserAgent {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user_agent = request.headers().get_one("User-Agent");
+
+        match user_agent {
+            Some(user_agent) => Outcome::Success(UserAgent(Some(user_agent.to_string()))),
+            None => Outcome::Success(UserAgent(None)),
+        }
+    }
+}
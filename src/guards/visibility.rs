@@ -0,0 +1,52 @@
+use std::marker::PhantomData;
+
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+use crate::config::{Config, Visibility};
+use crate::guards::role::RoleError;
+use crate::guards::scope::{Authorized, Permission};
+
+/*
+ * Sessions and access tokens granted `P` see everything; in `mixed` mode
+ * anyone else only sees the public categories and their sites.
+ */
+pub struct Reader<P: Permission> {
+    pub public_only: bool,
+    permission: PhantomData<P>,
+}
+
+impl<P: Permission> Reader<P> {
+    fn new(public_only: bool) -> Self {
+        Self {
+            public_only,
+            permission: PhantomData,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, P: Permission> FromRequest<'r> for Reader<P> {
+    type Error = RoleError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let visibility = request
+            .rocket()
+            .state::<Config>()
+            .map(|config| config.visibility)
+            .unwrap_or_default();
+
+        if visibility == Visibility::Public {
+            return Outcome::Success(Reader::new(false));
+        }
+
+        match request.guard::<Authorized<P>>().await {
+            Outcome::Success(_) => Outcome::Success(Reader::new(false)),
+            Outcome::Error(e) if visibility == Visibility::Private => Outcome::Error(e),
+            Outcome::Forward(status) if visibility == Visibility::Private => {
+                Outcome::Forward(status)
+            }
+            _ => Outcome::Success(Reader::new(true)),
+        }
+    }
+}
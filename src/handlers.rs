@@ -1,5 +1,15 @@
+pub mod access_token;
+pub mod audit;
 pub mod auth;
 pub mod category;
+pub mod email;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+#[cfg(feature = "oidc")]
+pub mod oidc;
+pub mod rate_limit;
+pub mod session;
 pub mod site;
+pub mod totp;
 pub mod upload;
 pub mod user;
@@ -0,0 +1,145 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use rocket_db_pools::Connection;
+use sqlx::{query, query_as};
+use uuid::Uuid;
+
+use crate::errors::ServiceError;
+use crate::handlers::user::get_role;
+use crate::models::access_token::{join_scopes, AccessToken};
+use crate::request::access_token::CreateAccessToken;
+use crate::response;
+use crate::utils::{hash_token, parse_duration};
+use crate::MySQLDb;
+
+/*
+ * Access tokens carry a recognizable prefix, so the guards can tell them
+ * apart from session JWTs without trying to decode them.
+ */
+pub const ACCESS_TOKEN_PREFIX: &str = "sp_";
+
+fn expires_at(expires_in: &str) -> Result<NaiveDateTime, ServiceError> {
+    let lifetime = parse_duration(expires_in)?;
+
+    if lifetime > Duration::days(365) {
+        return Err(ServiceError::BadRequest(String::from(
+            "Tokens expire within a year at most",
+        )));
+    }
+
+    Ok((Utc::now() + lifetime).naive_utc())
+}
+
+pub async fn get_tokens(
+    username: &str,
+    db: &mut Connection<MySQLDb>,
+) -> Result<Vec<response::access_token::AccessToken>, ServiceError> {
+    let tokens = query_as::<_, AccessToken>(
+        r#"SELECT id, username, name, prefix, scopes, expires_at, last_used_at, created_at FROM access_token WHERE username = ? ORDER BY created_at DESC"#,
+    )
+    .bind(username)
+    .fetch_all(&mut ***db)
+    .await?;
+
+    Ok(tokens.into_iter().map(|token| token.into()).collect())
+}
+
+pub async fn create_token(
+    username: &str,
+    data: &CreateAccessToken<'_>,
+    db: &mut Connection<MySQLDb>,
+) -> Result<response::access_token::CreatedAccessToken, ServiceError> {
+    if data.name.is_empty() {
+        return Err(ServiceError::BadRequest(String::from("Name is required")));
+    }
+
+    if data.scopes.is_empty() {
+        return Err(ServiceError::BadRequest(String::from(
+            "At least one scope is required",
+        )));
+    }
+
+    let role = get_role(username, db).await?;
+
+    if let Some(scope) = data.scopes.iter().find(|scope| scope.role() > role) {
+        return Err(ServiceError::BadRequest(format!(
+            "Scope {} is not allowed for this user",
+            scope
+        )));
+    }
+
+    let expires_at = match data.expires_in {
+        Some(expires_in) => Some(expires_at(expires_in)?),
+        None => None,
+    };
+
+    let token = format!(
+        "{}{}{}",
+        ACCESS_TOKEN_PREFIX,
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    );
+
+    let prefix = String::from(&token[..ACCESS_TOKEN_PREFIX.len() + 8]);
+
+    let id = query(
+        r#"INSERT INTO access_token (username, name, prefix, token, scopes, expires_at) VALUES (?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(username)
+    .bind(data.name)
+    .bind(&prefix)
+    .bind(hash_token(&token))
+    .bind(join_scopes(&data.scopes))
+    .bind(expires_at)
+    .execute(&mut ***db)
+    .await?
+    .last_insert_id();
+
+    let record = query_as::<_, AccessToken>(
+        r#"SELECT id, username, name, prefix, scopes, expires_at, last_used_at, created_at FROM access_token WHERE id = ?"#,
+    )
+    .bind(id)
+    .fetch_one(&mut ***db)
+    .await?;
+
+    Ok(response::access_token::CreatedAccessToken {
+        access_token: record.into(),
+        token,
+    })
+}
+
+pub async fn delete_token(
+    username: &str,
+    id: i64,
+    db: &mut Connection<MySQLDb>,
+) -> Result<(), ServiceError> {
+    let result = query(r#"DELETE FROM access_token WHERE id = ? AND username = ?"#)
+        .bind(id)
+        .bind(username)
+        .execute(&mut ***db)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ServiceError::NotFound);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expires_at() {
+        assert!(expires_at("30d").unwrap() > Utc::now().naive_utc());
+        assert!(expires_at("1y").is_ok());
+        assert!(matches!(
+            expires_at("13M"),
+            Err(ServiceError::BadRequest(_))
+        ));
+        assert!(matches!(
+            expires_at("999999999y"),
+            Err(ServiceError::FormatError(_))
+        ));
+    }
+}
@@ -0,0 +1,298 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use log::error;
+use rocket_db_pools::Connection;
+use serde_json::{json, Value};
+use sqlx::{query, query_as, Row};
+
+use crate::errors::ServiceError;
+use crate::guards::jwt::Middleware;
+use crate::guards::remote_ip::Ip;
+use crate::guards::scope::{Authorized, Permission};
+use crate::models::audit_log::{Action, AuditLog, Entity};
+use crate::request::audit::AuditFilter;
+use crate::response::WithTotal;
+use crate::utils::paginate;
+use crate::{models, response, MySQLDb};
+
+/*
+ * Who performed an audited action. Failed logins have no user yet, and
+ * requests made with an access token no session.
+ */
+pub struct Actor<'a> {
+    pub username: Option<&'a str>,
+    pub session: Option<&'a str>,
+    pub remote_ip: Option<&'a str>,
+}
+
+impl<'a, P: Permission> From<(&'a Authorized<P>, &'a Ip)> for Actor<'a> {
+    fn from((auth, remote_ip): (&'a Authorized<P>, &'a Ip)) -> Self {
+        Self {
+            username: Some(&auth.username),
+            session: auth.session.as_deref(),
+            remote_ip: remote_ip.0.as_deref(),
+        }
+    }
+}
+
+impl<'a> From<(&'a Middleware, &'a Ip)> for Actor<'a> {
+    fn from((jwt, remote_ip): (&'a Middleware, &'a Ip)) -> Self {
+        Self {
+            username: Some(jwt.username()),
+            session: Some(&jwt.session),
+            remote_ip: remote_ip.0.as_deref(),
+        }
+    }
+}
+
+/*
+ * The audit log is written after the change it records has gone through, so
+ * a failure here is logged rather than failing a request that already took
+ * effect.
+ */
+pub async fn record(
+    actor: &Actor<'_>,
+    action: Action,
+    entity: Entity,
+    entity_id: Option<&str>,
+    before: Option<Value>,
+    after: Option<Value>,
+    db: &mut Connection<MySQLDb>,
+) {
+    if let Err(e) = query(
+        r#"INSERT INTO audit_log (username, session, action, entity, entity_id, remote_ip, before_value, after_value, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, UTC_TIMESTAMP())"#,
+    )
+    .bind(actor.username)
+    .bind(actor.session)
+    .bind(action)
+    .bind(entity)
+    .bind(entity_id)
+    .bind(actor.remote_ip)
+    .bind(before)
+    .bind(after)
+    .execute(&mut ***db)
+    .await
+    {
+        error!("Failed to write audit log: {}", e);
+    }
+}
+
+/*
+ * The current state of an entity, as kept in the `before` and `after` values.
+ * Password hashes and token hashes are left out.
+ */
+pub async fn snapshot(entity: Entity, id: &str, db: &mut Connection<MySQLDb>) -> Option<Value> {
+    let result = match entity {
+        Entity::Category => query_as::<_, models::category::Category>(
+            r#"SELECT id, name, description, icon, sort_order, parent_id, is_public, created_at, updated_at FROM category WHERE id = ?"#,
+        )
+        .bind(id)
+        .fetch_optional(&mut ***db)
+        .await
+        .map(|record| record.map(|record| json!(record))),
+        Entity::Site => query_as::<_, models::site::Site>(
+            r#"SELECT id, name, url, description, icon, sort_order, visit_count, created_at, updated_at FROM site WHERE id = ?"#,
+        )
+        .bind(id)
+        .fetch_optional(&mut ***db)
+        .await
+        .map(|record| record.map(|record| json!(record))),
+        Entity::User => query_as::<_, models::user::User>(
+            r#"SELECT username, nickname, password, email, email_verified, IFNULL(avatar, '') AS avatar, role, disabled FROM user WHERE username = ?"#,
+        )
+        .bind(id)
+        .fetch_optional(&mut ***db)
+        .await
+        .map(|record| record.map(|record| user_snapshot(&record))),
+        Entity::Session | Entity::AccessToken | Entity::Upload => Ok(None),
+    };
+
+    result.unwrap_or_else(|e| {
+        error!("Failed to snapshot {:?} {}: {}", entity, id, e);
+
+        None
+    })
+}
+
+fn user_snapshot(record: &models::user::User) -> Value {
+    json!({
+        "username": record.username,
+        "nickname": record.nickname,
+        "email": record.email,
+        "email_verified": record.email_verified,
+        "avatar": record.avatar,
+        "role": record.role,
+        "disabled": record.disabled,
+    })
+}
+
+/*
+ * Accepts `2024-01-31`, `2024-01-31T08:00:00` or RFC 3339. Times without an
+ * offset are taken as UTC, like the timestamps in the log.
+ */
+fn parse_time(value: &str) -> Result<NaiveDateTime, ServiceError> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.naive_utc());
+    }
+
+    if let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S") {
+        return Ok(time);
+    }
+
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .ok_or_else(|| ServiceError::BadRequest(format!("Invalid time: {}", value)))
+}
+
+fn to_response(record: AuditLog) -> response::audit::AuditLog {
+    response::audit::AuditLog {
+        id: record.id,
+        username: record.username,
+        session: record.session,
+        action: record.action,
+        entity: record.entity,
+        entity_id: record.entity_id,
+        remote_ip: record.remote_ip,
+        before: record.before_value,
+        after: record.after_value,
+        created_at: record.created_at,
+    }
+}
+
+/*
+ * The newest entries first. `from` is inclusive and `to` exclusive.
+ */
+pub async fn get_audit_log(
+    filter: &AuditFilter<'_>,
+    db: &mut Connection<MySQLDb>,
+) -> Result<WithTotal<response::audit::AuditLog>, ServiceError> {
+    let (limit, offset) = paginate(filter.page, filter.size);
+
+    let username = filter.user;
+
+    let action = filter.action;
+
+    let from = filter.from.map(parse_time).transpose()?;
+
+    let to = filter.to.map(parse_time).transpose()?;
+
+    let filter = r#"
+        WHERE (? IS NULL OR username = ?)
+          AND (? IS NULL OR action = ?)
+          AND (? IS NULL OR created_at >= ?)
+          AND (? IS NULL OR created_at < ?)
+    "#;
+
+    let total = query(&format!(
+        "SELECT COUNT(id) AS count FROM audit_log {}",
+        filter
+    ))
+    .bind(username)
+    .bind(username)
+    .bind(action)
+    .bind(action)
+    .bind(from)
+    .bind(from)
+    .bind(to)
+    .bind(to)
+    .fetch_one(&mut ***db)
+    .await?
+    .get::<i64, &str>("count");
+
+    let records = query_as::<_, AuditLog>(&format!(
+        "SELECT id, username, session, action, entity, entity_id, remote_ip, before_value, after_value, created_at FROM audit_log {} ORDER BY id DESC LIMIT ? OFFSET ?",
+        filter
+    ))
+    .bind(username)
+    .bind(username)
+    .bind(action)
+    .bind(action)
+    .bind(from)
+    .bind(from)
+    .bind(to)
+    .bind(to)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&mut ***db)
+    .await?;
+
+    Ok(WithTotal {
+        total,
+        data: records.into_iter().map(to_response).collect(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::guards::scope::CategoriesWrite;
+    use crate::models::user::{Role, Source, User};
+
+    use super::*;
+
+    #[test]
+    fn test_actor() {
+        let remote_ip = Ip(Some(String::from("203.0.113.42")));
+
+        let session = Authorized::<CategoriesWrite>::new(
+            String::from("alice"),
+            Some(String::from("alice:session")),
+        );
+
+        let actor = Actor::from((&session, &remote_ip));
+
+        assert_eq!(actor.username, Some("alice"));
+        assert_eq!(actor.session, Some("alice:session"));
+        assert_eq!(actor.remote_ip, Some("203.0.113.42"));
+
+        let token = Authorized::<CategoriesWrite>::new(String::from("alice"), None);
+
+        assert_eq!(Actor::from((&token, &remote_ip)).session, None);
+
+        let jwt = Middleware {
+            session: String::from("bob:session"),
+        };
+
+        let actor = Actor::from((&jwt, &Ip(None)));
+
+        assert_eq!(actor.username, Some("bob"));
+        assert_eq!(actor.session, Some("bob:session"));
+        assert_eq!(actor.remote_ip, None);
+    }
+
+    #[test]
+    fn test_user_snapshot() {
+        let snapshot = user_snapshot(&User {
+            username: String::from("alice"),
+            nickname: String::from("Alice"),
+            password: String::from("$argon2id$secret"),
+            email: String::from("alice@example.com"),
+            email_verified: true,
+            avatar: String::new(),
+            role: Role::Editor,
+            disabled: false,
+            source: Source::Local,
+        });
+
+        assert_eq!(snapshot["username"], "alice");
+        assert_eq!(snapshot["role"], "editor");
+        assert!(snapshot.get("password").is_none());
+        assert!(!snapshot.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let expected = NaiveDate::from_ymd_opt(2024, 1, 31)
+            .unwrap()
+            .and_hms_opt(8, 0, 0)
+            .unwrap();
+
+        assert_eq!(parse_time("2024-01-31T08:00:00").unwrap(), expected);
+        assert_eq!(parse_time("2024-01-31T10:00:00+02:00").unwrap(), expected);
+        assert_eq!(parse_time("2024-01-31T08:00:00Z").unwrap(), expected);
+        assert_eq!(
+            parse_time("2024-01-31").unwrap(),
+            expected - chrono::Duration::hours(8)
+        );
+        assert!(parse_time("yesterday").is_err());
+    }
+}
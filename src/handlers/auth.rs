@@ -1,156 +1,633 @@
-use bcrypt::verify;
-use jsonwebtoken::{encode, EncodingKey, Header};
-use log::error;
+use log::{error, warn};
 use rocket::futures::TryFutureExt;
-use rocket_db_pools::deadpool_redis::redis::AsyncCommands;
+use rocket_db_pools::deadpool_redis::redis::{self, aio::ConnectionLike, AsyncCommands};
 use rocket_db_pools::Connection;
-use sqlx::query_as;
+use sqlx::{query, query_as};
 use uuid::Uuid;
 
-#[cfg(feature = "turnstile")]
-use reqwest;
-
 use crate::config::Config;
 use crate::errors::ServiceError;
+use crate::handlers::audit::{self, Actor};
+#[cfg(feature = "ldap")]
+use crate::handlers::ldap::authenticate;
+use crate::handlers::rate_limit::{check, record_failure, reset};
+use crate::handlers::session::{
+    create_session, extend_session, refresh_key, revoke_session, touch_session, SessionUse,
+};
+use crate::handlers::totp::{get_secret, verify_code};
+use crate::models::audit_log::{Action, Entity};
+use crate::password::{hash_password, needs_rehash, verify_password};
 use crate::request;
+use crate::response::auth::{JwtToken, Login, TwoFactorChallenge};
 use crate::state::AppState;
-use crate::utils::calculate_expires;
+use crate::utils::{calculate_expires, hash_token};
 use crate::Claims;
 use crate::{models, MySQLDb, RedisDb};
 
-#[cfg(feature = "turnstile")]
-use crate::response::auth::TurnstileResponse;
+const CHALLENGE_TTL: usize = 5 * 60 * 1000;
+
+const CHALLENGE_MAX_ATTEMPTS: usize = 5;
 
 pub async fn login(
     user: &request::auth::User<'_>,
     state: &AppState,
     config: &Config,
-    _remote_ip: Option<String>,
+    remote_ip: Option<String>,
+    user_agent: Option<String>,
     db: &mut Connection<MySQLDb>,
     cache: &mut Connection<RedisDb>,
-) -> Result<String, ServiceError> {
-    #[cfg(feature = "turnstile")]
-    {
-        let token = match user.token {
-            Some(token) => token,
-            None => {
-                return Err(ServiceError::BadRequest(String::from(
-                    "Invalid challenge token",
-                )))
-            }
-        };
-
-        let secret = match &config.turnstile_secret {
-            Some(secret) => secret,
-            None => {
-                return Err(ServiceError::BadRequest(String::from(
-                    "Missing Turnstile secret",
-                )))
-            }
-        };
-
-        let url = match &config.turnstile_url {
-            Some(url) => url,
-            None => {
-                return Err(ServiceError::BadRequest(String::from(
-                    "Missing Turnstile URL",
-                )))
-            }
-        };
+) -> Result<Login, ServiceError> {
+    check(remote_ip.as_deref(), user.username, cache).await?;
+
+    if let Some(captcha) = &state.captcha {
+        let token = user.token.ok_or(ServiceError::BadRequest(String::from(
+            "Invalid challenge token",
+        )))?;
+
+        if !captcha.verify(token, remote_ip.as_deref(), cache).await? {
+            return Err(ServiceError::BadRequest(String::from(
+                "Invalid challenge token",
+            )));
+        }
+    }
 
-        let idempotency_key = Uuid::new_v4().to_string();
+    #[cfg(feature = "ldap")]
+    let directory_record = match &config.ldap {
+        Some(ldap) => {
+            authenticate(
+                user.username,
+                user.password,
+                ldap,
+                &state.password_policy,
+                db,
+            )
+            .await?
+        }
+        None => None,
+    };
 
-        let params: [(&str, Option<&str>); 4] = [
-            ("secret", Some(secret)),
-            ("response", Some(token)),
-            ("remoteip", _remote_ip.as_deref()),
-            ("idempotency_key", Some(&idempotency_key)),
-        ];
+    #[cfg(not(feature = "ldap"))]
+    let directory_record = None;
 
-        let client = reqwest::Client::new();
+    let record = match directory_record {
+        Some(record) => record,
+        None => authenticate_local(user, config, remote_ip.as_deref(), db, cache).await?,
+    };
 
-        let response = client
-            .post(url)
-            .form(&params)
-            .send()
-            .map_err(|e| {
-                error!("Failed to send request to Turnstile: {}", e);
+    reset(&record.username, cache).await?;
 
-                ServiceError::InternalServerError
-            })
-            .await?
-            .json::<TurnstileResponse>()
+    if record.disabled {
+        return Err(ServiceError::BadRequest(String::from(
+            "Account is disabled",
+        )));
+    }
+
+    if get_secret(&record.username, db).await?.is_some() {
+        let challenge = Uuid::new_v4().simple().to_string();
+
+        redis::pipe()
+            .atomic()
+            .hset(challenge_key(&challenge), "username", &record.username)
+            .ignore()
+            .pexpire(challenge_key(&challenge), CHALLENGE_TTL)
+            .ignore()
+            .query_async::<_, ()>(&mut **cache)
             .map_err(|e| {
-                error!("Failed to parse Turnstile response: {}", e);
+                error!("Failed to set two-factor challenge: {}", e);
 
                 ServiceError::InternalServerError
             })
             .await?;
 
-        if !response.success {
-            return Err(ServiceError::BadRequest(String::from(
-                "Invalid challenge token",
-            )));
-        }
+        return Ok(Login::Challenge(TwoFactorChallenge { challenge }));
     }
 
-    let record = query_as::<_, models::user::User>(
-        r#"SELECT username, nickname, password, avatar, email FROM user WHERE username = ?"#,
+    let token = start_session(
+        &record.username,
+        remote_ip.as_deref(),
+        user_agent.as_deref(),
+        state,
+        config,
+        db,
+        cache,
+    )
+    .await?;
+
+    Ok(Login::Authenticated(token))
+}
+
+async fn failed_login(username: &str, remote_ip: Option<&str>, db: &mut Connection<MySQLDb>) {
+    let actor = Actor {
+        username: None,
+        session: None,
+        remote_ip,
+    };
+
+    audit::record(
+        &actor,
+        Action::LoginFailed,
+        Entity::User,
+        Some(username),
+        None,
+        None,
+        db,
+    )
+    .await;
+}
+
+async fn authenticate_local(
+    user: &request::auth::User<'_>,
+    config: &Config,
+    remote_ip: Option<&str>,
+    db: &mut Connection<MySQLDb>,
+    cache: &mut Connection<RedisDb>,
+) -> Result<models::user::User, ServiceError> {
+    let record = match query_as::<_, models::user::User>(
+        r#"SELECT username, nickname, password, avatar, email, role, disabled FROM user WHERE username = ?"#,
     )
     .bind(user.username)
     .fetch_one(&mut ***db)
     .await
-    .map_err(|e| {
-        error!("Failed to query user: {}", e);
+    {
+        Ok(record) => record,
+        Err(e) => {
+            error!("Failed to query user: {}", e);
 
-        ServiceError::BadRequest(String::from("Invalid username or password"))
-    })?;
+            record_failure(remote_ip, user.username, config, cache).await?;
 
-    let valid = verify(user.password, &record.password).map_err(|e| {
-        error!("Failed to verify password: {}", e);
+            failed_login(user.username, remote_ip, db).await;
 
-        ServiceError::BadRequest(String::from("Invalid username or password"))
-    })?;
+            return Err(ServiceError::BadRequest(String::from(
+                "Invalid username or password",
+            )));
+        }
+    };
 
-    let session = Uuid::new_v4().to_string();
+    let valid = verify_password(user.password, &record.password)
+        .map_err(|_| ServiceError::BadRequest(String::from("Invalid username or password")))?;
 
-    let session = format!("{}:{}", record.username, session);
+    if !valid {
+        record_failure(remote_ip, user.username, config, cache).await?;
 
-    if valid {
-        let claims = Claims {
-            sub: session.clone(),
-            company: String::from("StartPage"),
-            exp: calculate_expires(&config.jwt.expires_in)? as usize,
-        };
+        failed_login(user.username, remote_ip, db).await;
 
-        let token = encode(
-            &Header::default(),
-            &claims,
-            &EncodingKey::from_secret(config.jwt.secret.as_bytes()),
-        )
+        return Err(ServiceError::BadRequest(String::from(
+            "Invalid username or password",
+        )));
+    }
+
+    if needs_rehash(&record.password) {
+        upgrade_password_hash(&record.username, user.password, db).await;
+    }
+
+    Ok(record)
+}
+
+/*
+ * Replaces a legacy hash now that the plain password is at hand. A failure
+ * only leaves the old hash in place, so it does not fail the login.
+ */
+async fn upgrade_password_hash(username: &str, password: &str, db: &mut Connection<MySQLDb>) {
+    let hashed = match hash_password(password) {
+        Ok(hashed) => hashed,
+        Err(_) => return,
+    };
+
+    if let Err(e) = query(r#"UPDATE user SET password = ? WHERE username = ?"#)
+        .bind(&hashed)
+        .bind(username)
+        .execute(&mut ***db)
+        .await
+    {
+        error!("Failed to upgrade password hash: {}", e);
+    }
+}
+
+pub async fn login_totp(
+    data: &request::auth::TotpLogin<'_>,
+    state: &AppState,
+    config: &Config,
+    remote_ip: Option<String>,
+    user_agent: Option<String>,
+    db: &mut Connection<MySQLDb>,
+    cache: &mut Connection<RedisDb>,
+) -> Result<JwtToken, ServiceError> {
+    let key = challenge_key(data.challenge);
+
+    let (username, attempts): (Option<String>, usize) = redis::pipe()
+        .atomic()
+        .hget(&key, "username")
+        .hincr(&key, "attempts", 1)
+        .query_async(&mut **cache)
         .map_err(|e| {
-            error!("Failed to encode token: {}", e);
+            error!("Failed to get two-factor challenge: {}", e);
 
             ServiceError::InternalServerError
-        })?;
+        })
+        .await?;
 
+    let username = match username {
+        Some(username) => username,
+        None => {
+            cache
+                .del::<_, ()>(&key)
+                .map_err(|e| {
+                    error!("Failed to delete two-factor challenge: {}", e);
+
+                    ServiceError::InternalServerError
+                })
+                .await?;
+
+            return Err(ServiceError::Unauthorized);
+        }
+    };
+
+    if attempts > CHALLENGE_MAX_ATTEMPTS {
         cache
-            .pset_ex(
-                session,
-                token.clone(),
-                state.jwt_expiration.num_milliseconds() as usize,
-            )
+            .del::<_, ()>(&key)
             .map_err(|e| {
-                error!("Failed to set token: {}", e);
+                error!("Failed to delete two-factor challenge: {}", e);
 
                 ServiceError::InternalServerError
             })
             .await?;
 
-        Ok(token)
-    } else {
-        Err(ServiceError::BadRequest(String::from(
-            "Invalid username or password",
-        )))
+        return Err(ServiceError::Unauthorized);
+    }
+
+    check(remote_ip.as_deref(), &username, cache).await?;
+
+    let secret = get_secret(&username, db)
+        .await?
+        .ok_or(ServiceError::Unauthorized)?;
+
+    if !verify_code(&username, &secret, data.code, config, db, cache).await? {
+        record_failure(remote_ip.as_deref(), &username, config, cache).await?;
+
+        failed_login(&username, remote_ip.as_deref(), db).await;
+
+        return Err(ServiceError::BadRequest(String::from(
+            "Invalid verification code",
+        )));
+    }
+
+    cache
+        .del::<_, ()>(&key)
+        .map_err(|e| {
+            error!("Failed to delete two-factor challenge: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    start_session(
+        &username,
+        remote_ip.as_deref(),
+        user_agent.as_deref(),
+        state,
+        config,
+        db,
+        cache,
+    )
+    .await
+}
+
+pub(crate) async fn start_session(
+    username: &str,
+    remote_ip: Option<&str>,
+    user_agent: Option<&str>,
+    state: &AppState,
+    config: &Config,
+    db: &mut Connection<MySQLDb>,
+    cache: &mut Connection<RedisDb>,
+) -> Result<JwtToken, ServiceError> {
+    let session = Uuid::new_v4().to_string();
+
+    let session = format!("{}:{}", username, session);
+
+    create_session(&session, remote_ip, user_agent, state, cache).await?;
+
+    let token = issue_token(&session, state, config, cache).await?;
+
+    let refresh_token = generate_refresh_token();
+
+    let hashed = hash_token(&refresh_token);
+
+    let ttl = state.refresh_expiration.num_milliseconds() as usize;
+
+    redis::pipe()
+        .atomic()
+        .pset_ex(refresh_key(&session), &hashed, ttl)
+        .ignore()
+        .pset_ex(refresh_token_key(&hashed), &session, ttl)
+        .ignore()
+        .query_async::<_, ()>(&mut **cache)
+        .map_err(|e| {
+            error!("Failed to set refresh token: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    let actor = Actor {
+        username: Some(username),
+        session: Some(&session),
+        remote_ip,
+    };
+
+    audit::record(
+        &actor,
+        Action::Login,
+        Entity::Session,
+        Some(&session),
+        None,
+        None,
+        db,
+    )
+    .await;
+
+    Ok(JwtToken {
+        token,
+        refresh_token,
+        csrf_token: generate_csrf_token(),
+    })
+}
+
+/*
+ * Refresh tokens are single-use: presenting one that has already been rotated
+ * means it has leaked, so the whole session it belongs to is revoked.
+ */
+pub async fn refresh(
+    refresh_token: &str,
+    remote_ip: Option<&str>,
+    user_agent: Option<&str>,
+    state: &AppState,
+    config: &Config,
+    cache: &mut Connection<RedisDb>,
+) -> Result<JwtToken, ServiceError> {
+    let hashed = hash_token(refresh_token);
+
+    let session = find_refresh_session(&hashed, &mut **cache)
+        .await?
+        .ok_or(ServiceError::Unauthorized)?;
+
+    let used = touch_session(
+        &session,
+        remote_ip,
+        user_agent,
+        &state.session_policy,
+        &mut **cache,
+    )
+    .await?;
+
+    if used != SessionUse::Active {
+        return Err(ServiceError::Unauthorized);
+    }
+
+    let ttl = state.refresh_expiration.num_milliseconds() as usize;
+
+    let next = match rotate_refresh_token(&session, &hashed, ttl, &mut **cache).await? {
+        Some(next) => next,
+        None => {
+            warn!("Refresh token reuse detected, revoking session {}", session);
+
+            revoke_session(&session, cache).await?;
+
+            return Err(ServiceError::Unauthorized);
+        }
+    };
+
+    extend_session(&session, state, cache).await?;
+
+    let token = issue_token(&session, state, config, cache).await?;
+
+    Ok(JwtToken {
+        token,
+        refresh_token: next,
+        csrf_token: generate_csrf_token(),
+    })
+}
+
+async fn find_refresh_session<C: ConnectionLike>(
+    hashed: &str,
+    connection: &mut C,
+) -> Result<Option<String>, ServiceError> {
+    redis::cmd("GET")
+        .arg(refresh_token_key(hashed))
+        .query_async::<_, Option<String>>(connection)
+        .map_err(|e| {
+            error!("Failed to get refresh token: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await
+}
+
+async fn rotate_refresh_token<C: ConnectionLike>(
+    session: &str,
+    hashed: &str,
+    ttl: usize,
+    connection: &mut C,
+) -> Result<Option<String>, ServiceError> {
+    let next = generate_refresh_token();
+
+    let next_hashed = hash_token(&next);
+
+    let rotated = redis::cmd("EVAL")
+        .arg(ROTATE_REFRESH_TOKEN)
+        .arg(2)
+        .arg(refresh_key(session))
+        .arg(refresh_token_key(&next_hashed))
+        .arg(hashed)
+        .arg(&next_hashed)
+        .arg(session)
+        .arg(ttl)
+        .query_async::<_, bool>(connection)
+        .map_err(|e| {
+            error!("Failed to rotate refresh token: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    Ok(rotated.then_some(next))
+}
+
+/*
+ * Replaces the current refresh token of a session with a new one, but only if
+ * the presented token is still the current one. The lookup entry of the old
+ * token is left to expire so that a replay can still be traced to its session.
+ */
+const ROTATE_REFRESH_TOKEN: &str = r#"
+    if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+        return 0
+    end
+
+    redis.call('SET', KEYS[1], ARGV[2], 'PX', ARGV[4])
+    redis.call('SET', KEYS[2], ARGV[3], 'PX', ARGV[4])
+
+    return 1
+"#;
+
+async fn issue_token(
+    session: &str,
+    state: &AppState,
+    config: &Config,
+    cache: &mut Connection<RedisDb>,
+) -> Result<String, ServiceError> {
+    let claims = Claims {
+        sub: String::from(session),
+        company: String::from("StartPage"),
+        exp: calculate_expires(&config.jwt.expires_in)? as usize,
+    };
+
+    let token = state.jwt_keys.encode(&claims).map_err(|e| {
+        error!("Failed to encode token: {}", e);
+
+        ServiceError::InternalServerError
+    })?;
+
+    cache
+        .pset_ex::<_, _, ()>(
+            session,
+            token.clone(),
+            state.jwt_expiration.num_milliseconds() as usize,
+        )
+        .map_err(|e| {
+            error!("Failed to set token: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    Ok(token)
+}
+
+fn generate_refresh_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+fn generate_csrf_token() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+fn refresh_token_key(hashed: &str) -> String {
+    format!("refresh_token:{}", hashed)
+}
+
+fn challenge_key(challenge: &str) -> String {
+    format!("totp_challenge:{}", challenge)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use rocket_db_pools::deadpool_redis::redis::{
+        Arg, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, Value,
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Store(HashMap<String, String>);
+
+    impl ConnectionLike for Store {
+        fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+            let args = cmd
+                .args_iter()
+                .map(|arg| match arg {
+                    Arg::Simple(arg) => String::from_utf8_lossy(arg).into_owned(),
+                    Arg::Cursor => String::new(),
+                })
+                .collect::<Vec<String>>();
+
+            let result = match args[0].as_str() {
+                "GET" => Ok(self
+                    .0
+                    .get(&args[1])
+                    .map_or(Value::Nil, |value| Value::Data(value.clone().into_bytes()))),
+                "EVAL" if args[1] == ROTATE_REFRESH_TOKEN => {
+                    if self.0.get(&args[3]) != Some(&args[5]) {
+                        Ok(Value::Int(0))
+                    } else {
+                        self.0.insert(args[3].clone(), args[6].clone());
+                        self.0.insert(args[4].clone(), args[7].clone());
+
+                        Ok(Value::Int(1))
+                    }
+                }
+                _ => Err(RedisError::from((
+                    ErrorKind::ClientError,
+                    "unsupported command",
+                ))),
+            };
+
+            Box::pin(async move { result })
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            _: &'a Pipeline,
+            _: usize,
+            _: usize,
+        ) -> RedisFuture<'a, Vec<Value>> {
+            Box::pin(async move {
+                Err(RedisError::from((
+                    ErrorKind::ClientError,
+                    "unsupported pipeline",
+                )))
+            })
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_refresh_token_reuse() {
+        let session = "alice:1";
+
+        let first = hash_token("first");
+
+        let mut store = Store::default();
+
+        store.0.insert(refresh_key(session), first.clone());
+        store
+            .0
+            .insert(refresh_token_key(&first), String::from(session));
+
+        let second = rotate_refresh_token(session, &first, 1000, &mut store)
+            .await
+            .unwrap()
+            .map(|token| hash_token(&token))
+            .unwrap();
+
+        // A replayed token still leads to its session, so that it can be revoked.
+        assert_eq!(
+            find_refresh_session(&first, &mut store).await.unwrap(),
+            Some(String::from(session))
+        );
+        assert_eq!(
+            rotate_refresh_token(session, &first, 1000, &mut store)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            find_refresh_session(&second, &mut store).await.unwrap(),
+            Some(String::from(session))
+        );
+        assert!(rotate_refresh_token(session, &second, 1000, &mut store)
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            find_refresh_session(&hash_token("unknown"), &mut store)
+                .await
+                .unwrap(),
+            None
+        );
     }
 }
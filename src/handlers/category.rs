@@ -1,140 +1,107 @@
-use log::error;
 use rocket_db_pools::Connection;
-use sqlx::{query, query_as, Row};
+use sqlx::{query, query_as, Acquire, FromRow, MySqlConnection, Row};
 use std::collections::{HashMap, HashSet};
 
 use crate::errors::ServiceError;
 use crate::models::category::Category;
-use crate::request::category::{CreateCategory, UpdateCategory};
+use crate::request::category::{
+    CategoryOrder, CreateCategory, DeleteStrategy, SiteOrder, SitesFilter, UpdateCategory,
+};
 use crate::response;
 use crate::response::WithTotal;
+use crate::utils::paginate;
 use crate::MySQLDb;
 
-fn build_sub_tree(
+/*
+ * The categories anonymous visitors see in `mixed` mode: the public ones whose
+ * ancestors are all public as well.
+ */
+pub(crate) const PUBLIC_CATEGORIES: &str = r#"
+    WITH public_category AS (
+        SELECT cc.descendant AS id FROM category_closure cc INNER JOIN category a ON a.id = cc.ancestor
+        GROUP BY cc.descendant HAVING MIN(a.is_public) = TRUE
+    )
+"#;
+
+fn to_response(category: Category, upload_url: &str) -> response::category::Category {
+    let mut item = response::category::Category::from(category);
+
+    if !item.icon.starts_with("http") && !item.icon.starts_with("https") {
+        item.icon = format!("{}/{}", upload_url, item.icon);
+    }
+
+    item
+}
+
+fn attach_children(
     node: &mut response::category::Category,
-    map: &HashMap<Option<i64>, Vec<response::category::Category>>,
+    children: &mut HashMap<i64, Vec<response::category::Category>>,
 ) {
-    if let Some(children) = map.get(&Some(node.id)) {
-        for child in children {
-            let mut child_clone = child.clone();
-            build_sub_tree(&mut child_clone, map);
-            node.children.get_or_insert(Vec::new()).push(child_clone);
+    if let Some(mut nodes) = children.remove(&node.id) {
+        for child in &mut nodes {
+            attach_children(child, children);
         }
+
+        node.children = Some(nodes);
     }
 }
 
-fn build_tree(arr: &Vec<Category>, upload_url: &str) -> Vec<response::category::Category> {
-    let mut map: HashMap<Option<i64>, Vec<response::category::Category>> = HashMap::new();
-    let mut categories = arr
-        .clone()
+/*
+ * Categories whose parent is not among them end up at the top.
+ */
+fn build_tree(categories: Vec<Category>, upload_url: &str) -> Vec<response::category::Category> {
+    let ids = categories
         .iter()
-        .map(|category| {
-            let icon = category.icon.clone();
-
-            let icon = if icon.starts_with("http") || icon.starts_with("https") {
-                icon
-            } else {
-                format!("{}/{}", upload_url, icon)
-            };
-
-            response::category::Category {
-                id: category.id,
-                name: category.name.clone(),
-                description: category.description.clone(),
-                icon,
-                parent_id: category.parent_id,
-                children: None,
-            }
-        })
-        .collect::<Vec<response::category::Category>>();
-
-    for category in arr {
-        let icon = category.icon.clone();
-
-        let icon = if icon.starts_with("http") || icon.starts_with("https") {
-            icon
-        } else {
-            format!("{}/{}", upload_url, icon)
-        };
+        .map(|category| category.id)
+        .collect::<HashSet<i64>>();
 
-        let item = response::category::Category {
-            id: category.id,
-            name: category.name.clone(),
-            description: category.description.clone(),
-            icon,
-            parent_id: category.parent_id,
-            children: None,
-        };
+    let mut roots = Vec::new();
 
-        map.entry(category.parent_id).or_default().push(item);
-    }
+    let mut children: HashMap<i64, Vec<response::category::Category>> = HashMap::new();
 
-    for category in &mut categories {
-        build_sub_tree(category, &map);
+    for category in categories {
+        match category
+            .parent_id
+            .filter(|parent_id| ids.contains(parent_id))
+        {
+            Some(parent_id) => children
+                .entry(parent_id)
+                .or_default()
+                .push(to_response(category, upload_url)),
+            None => roots.push(to_response(category, upload_url)),
+        }
     }
 
-    let mut child_ids = HashSet::new();
-    for category in &categories {
-        if let Some(children) = &category.children {
-            for child in children {
-                child_ids.insert(child.id);
-            }
-        }
+    for root in &mut roots {
+        attach_children(root, &mut children);
     }
 
-    categories
-        .into_iter()
-        .filter(|c| !child_ids.contains(&c.id))
-        .collect()
+    roots
 }
 
+/*
+ * The category tree, paged by top-level category so that every page holds
+ * whole subtrees; `total` counts the top-level categories. With `search` the
+ * matching categories are paged instead, nested where one lies below another.
+ */
 pub async fn get_categories(
     page: i64,
     size: i64,
     search: Option<&str>,
+    public_only: bool,
     upload_url: &str,
     db: &mut Connection<MySQLDb>,
 ) -> Result<WithTotal<response::category::Category>, ServiceError> {
-    let common_sql: &str = r#"
-        WITH RECURSIVE category_hierarchy AS (
-                SELECT
-                    id,
-                    name,
-                    description,
-                    icon,
-                    parent_id
-                FROM
-                    category
-                WHERE
-                    parent_id IS NULL
-                UNION ALL
-                SELECT
-                    c.id,
-                    c.name,
-                    c.description,
-                    c.icon,
-                    c.parent_id
-                FROM
-                    category c
-                INNER JOIN
-                    category_hierarchy ch ON ch.id = c.parent_id
-        )
-    "#;
-
     let total = match search {
         Some(search) => query(
             format!(
                 "{}{}",
-                common_sql,
-                r#"
-                SELECT
-                    COUNT(id) AS count
-                FROM
-                    category_hierarchy AS ch WHERE ch.name LIKE ? OR ch.description LIKE ?;
-                "#
+                PUBLIC_CATEGORIES,
+                r#"SELECT COUNT(id) AS count FROM category WHERE (? OR id IN (SELECT id FROM public_category)) AND (name LIKE ? OR description LIKE ?)"#
             )
             .as_str(),
         )
+        .bind(!public_only)
         .bind(format!("%{}%", search))
         .bind(format!("%{}%", search))
         .fetch_one(&mut ***db)
@@ -143,81 +110,65 @@ pub async fn get_categories(
         None => query(
             format!(
                 "{}{}",
-                common_sql,
-                r#"
-                SELECT
-                    COUNT(id) AS count
-                FROM
-                    category_hierarchy
-                "#
+                PUBLIC_CATEGORIES,
+                r#"SELECT COUNT(id) AS count FROM category WHERE parent_id IS NULL AND (? OR id IN (SELECT id FROM public_category))"#
             )
             .as_str(),
         )
+        .bind(!public_only)
         .fetch_one(&mut ***db)
         .await?
         .get::<i64, &str>("count"),
     };
 
-    let common_sql: &str = r#"
-        WITH RECURSIVE category_hierarchy AS (
-                SELECT
-                    id,
-                    name,
-                    description,
-                    icon,
-                    parent_id,
-                    sort_order,
-                    created_at,
-                    updated_at
-                FROM
-                    category
-                WHERE
-                    parent_id IS NULL
-                UNION ALL
-                SELECT
-                    c.id,
-                    c.name,
-                    c.description,
-                    c.icon,
-                    c.parent_id,
-                    c.sort_order,
-                    c.created_at,
-                    c.updated_at
-                FROM
-                    category c
-                INNER JOIN
-                    category_hierarchy ch ON ch.id = c.parent_id
-        )
-    "#;
-
     let categories = match search {
-        Some(search) => query_as::<_, Category>(format!("{}{}", common_sql, r#"
-            SELECT
-               id, name, description, icon, sort_order, parent_id, created_at, updated_at
-            FROM
-               category_hierarchy AS ch WHERE ch.name LIKE ? OR ch.description LIKE ? ORDER BY sort_order LIMIT ? OFFSET ?
-        "#).as_str())
+        Some(search) => query_as::<_, Category>(
+            format!(
+                "{}{}",
+                PUBLIC_CATEGORIES,
+                r#"SELECT id, name, description, icon, sort_order, parent_id, is_public, created_at, updated_at FROM category WHERE (? OR id IN (SELECT id FROM public_category)) AND (name LIKE ? OR description LIKE ?) ORDER BY sort_order, id LIMIT ? OFFSET ?"#
+            )
+            .as_str(),
+        )
+        .bind(!public_only)
         .bind(format!("%{}%", search))
         .bind(format!("%{}%", search))
         .bind(size)
         .bind(page * size)
         .fetch_all(&mut ***db)
         .await?,
-        None => query_as::<_, Category>(format!("{}{}", common_sql, r#"
-            SELECT
-               id, name, description, icon, sort_order, parent_id, created_at, updated_at
-            FROM
-               category_hierarchy AS ch ORDER BY sort_order LIMIT ? OFFSET ?
-        "#).as_str())
+        None => query_as::<_, Category>(
+            format!(
+                "{}{}",
+                PUBLIC_CATEGORIES,
+                r#"
+                SELECT
+                    c.id, c.name, c.description, c.icon, c.sort_order, c.parent_id, c.is_public, c.created_at, c.updated_at
+                FROM
+                    (SELECT id FROM category WHERE parent_id IS NULL AND (? OR id IN (SELECT id FROM public_category)) ORDER BY sort_order, id LIMIT ? OFFSET ?) AS root
+                INNER JOIN
+                    category_closure AS cc ON cc.ancestor = root.id
+                INNER JOIN
+                    category AS c ON c.id = cc.descendant
+                WHERE
+                    ? OR c.id IN (SELECT id FROM public_category)
+                ORDER BY
+                    cc.depth, c.sort_order, c.id
+                "#
+            )
+            .as_str(),
+        )
+        .bind(!public_only)
         .bind(size)
         .bind(page * size)
+        .bind(!public_only)
         .fetch_all(&mut ***db)
         .await?,
     };
 
     Ok(WithTotal {
         total,
-        data: build_tree(&categories, upload_url),
+        data: build_tree(categories, upload_url),
     })
 }
 
@@ -225,28 +176,49 @@ pub async fn get_categories_flat(
     page: i64,
     size: i64,
     search: Option<&str>,
+    public_only: bool,
     upload_url: &str,
     db: &mut Connection<MySQLDb>,
 ) -> Result<WithTotal<response::category::Category>, ServiceError> {
     let total = match search {
         Some(search) => query(
-            r#"SELECT COUNT(id) AS count FROM category WHERE name LIKE ? OR description LIKE ?"#,
+            format!(
+                "{}{}",
+                PUBLIC_CATEGORIES,
+                r#"SELECT COUNT(id) AS count FROM category WHERE (? OR id IN (SELECT id FROM public_category)) AND (name LIKE ? OR description LIKE ?)"#
+            )
+            .as_str(),
         )
+        .bind(!public_only)
         .bind(format!("%{}%", search))
         .bind(format!("%{}%", search))
         .fetch_one(&mut ***db)
         .await?
         .get::<i64, &str>("count"),
-        None => query(r#"SELECT COUNT(id) AS count FROM category"#)
-            .fetch_one(&mut ***db)
-            .await?
-            .get::<i64, &str>("count"),
+        None => query(
+            format!(
+                "{}{}",
+                PUBLIC_CATEGORIES,
+                r#"SELECT COUNT(id) AS count FROM category WHERE ? OR id IN (SELECT id FROM public_category)"#
+            )
+            .as_str(),
+        )
+        .bind(!public_only)
+        .fetch_one(&mut ***db)
+        .await?
+        .get::<i64, &str>("count"),
     };
 
     let categories = match search {
         Some(search) => query_as::<_, Category>(
-            r#"SELECT id, name, description, icon, sort_order, parent_id, created_at, updated_at FROM category WHERE name ORDER BY sort_order LIKE ? OR description LIKE ? LIMIT ? OFFSET ?"#,
+            format!(
+                "{}{}",
+                PUBLIC_CATEGORIES,
+                r#"SELECT id, name, description, icon, sort_order, parent_id, is_public, created_at, updated_at FROM category WHERE (? OR id IN (SELECT id FROM public_category)) AND (name LIKE ? OR description LIKE ?) ORDER BY sort_order LIMIT ? OFFSET ?"#
+            )
+            .as_str(),
         )
+        .bind(!public_only)
         .bind(format!("%{}%", search))
         .bind(format!("%{}%", search))
         .bind(size)
@@ -254,8 +226,14 @@ pub async fn get_categories_flat(
         .fetch_all(&mut ***db)
         .await?,
         None => query_as::<_, Category>(
-            r#"SELECT id, name, description, icon, sort_order, parent_id, created_at, updated_at FROM category ORDER BY sort_order LIMIT ? OFFSET ?"#,
+            format!(
+                "{}{}",
+                PUBLIC_CATEGORIES,
+                r#"SELECT id, name, description, icon, sort_order, parent_id, is_public, created_at, updated_at FROM category WHERE ? OR id IN (SELECT id FROM public_category) ORDER BY sort_order LIMIT ? OFFSET ?"#
+            )
+            .as_str(),
         )
+        .bind(!public_only)
         .bind(size)
         .bind(page * size)
         .fetch_all(&mut ***db)
@@ -265,216 +243,746 @@ pub async fn get_categories_flat(
     Ok(WithTotal {
         total,
         data: categories
-            .iter()
-            .map(|category| {
-                let icon = category.icon.clone();
-
-                let icon = if icon.starts_with("http") || icon.starts_with("https") {
-                    icon
-                } else {
-                    format!("{}/{}", upload_url, icon)
-                };
-
-                response::category::Category {
-                    id: category.id,
-                    name: category.name.clone(),
-                    description: category.description.clone(),
-                    icon,
-                    parent_id: category.parent_id,
-                    children: None,
-                }
-            })
+            .into_iter()
+            .map(|category| to_response(category, upload_url))
             .collect(),
     })
 }
 
-pub async fn update_category<'r>(
-    id: &'r str,
-    category: &'r UpdateCategory<'r>,
+async fn get_record(
+    id: i64,
+    public_only: bool,
     db: &mut Connection<MySQLDb>,
-) -> Result<(), ServiceError> {
-    let record = query_as::<_, Category>(
-        r#"SELECT id, name, description, icon, sort_order, parent_id, created_at, updated_at FROM category WHERE id = ?"#,
+) -> Result<Category, ServiceError> {
+    query_as::<_, Category>(
+        format!(
+            "{}{}",
+            PUBLIC_CATEGORIES,
+            r#"SELECT id, name, description, icon, sort_order, parent_id, is_public, created_at, updated_at FROM category WHERE id = ? AND (? OR id IN (SELECT id FROM public_category))"#
+        )
+        .as_str(),
     )
     .bind(id)
-    .fetch_one(&mut ***db)
-    .await
-    .map_err(|e| match e {
-        sqlx::Error::RowNotFound => ServiceError::BadRequest(String::from("Category not found")),
-        _ => ServiceError::DatabaseError(e),
-    })?;
+    .bind(!public_only)
+    .fetch_optional(&mut ***db)
+    .await?
+    .ok_or(ServiceError::NotFound)
+}
+
+/*
+ * The caller has already checked that the reader may see `id`, and so its
+ * ancestors.
+ */
+async fn ancestors_of(
+    id: i64,
+    upload_url: &str,
+    db: &mut Connection<MySQLDb>,
+) -> Result<Vec<response::category::Category>, ServiceError> {
+    let categories = query_as::<_, Category>(
+        r#"SELECT c.id, c.name, c.description, c.icon, c.sort_order, c.parent_id, c.is_public, c.created_at, c.updated_at FROM category_closure AS cc INNER JOIN category AS c ON c.id = cc.ancestor WHERE cc.descendant = ? AND cc.depth > 0 ORDER BY cc.depth DESC"#,
+    )
+    .bind(id)
+    .fetch_all(&mut ***db)
+    .await?;
+
+    Ok(categories
+        .into_iter()
+        .map(|category| to_response(category, upload_url))
+        .collect())
+}
+
+pub async fn get_ancestors(
+    id: i64,
+    public_only: bool,
+    upload_url: &str,
+    db: &mut Connection<MySQLDb>,
+) -> Result<Vec<response::category::Category>, ServiceError> {
+    get_record(id, public_only, db).await?;
+
+    ancestors_of(id, upload_url, db).await
+}
+
+pub async fn get_category(
+    id: i64,
+    public_only: bool,
+    upload_url: &str,
+    db: &mut Connection<MySQLDb>,
+) -> Result<response::category::CategoryWithPath, ServiceError> {
+    let category = get_record(id, public_only, db).await?;
+
+    let path = ancestors_of(id, upload_url, db).await?;
+
+    Ok(response::category::CategoryWithPath {
+        category: to_response(category, upload_url),
+        path,
+    })
+}
 
-    if category.parent_id == Some(record.id) {
+pub async fn get_descendants(
+    id: i64,
+    depth: Option<i64>,
+    public_only: bool,
+    upload_url: &str,
+    db: &mut Connection<MySQLDb>,
+) -> Result<Vec<response::category::Category>, ServiceError> {
+    if depth.is_some_and(|depth| depth < 1) {
         return Err(ServiceError::BadRequest(String::from(
-            "Category cannot be its own parent",
+            "Depth must be at least 1",
         )));
     }
 
-    let name = match category.name {
-        Some(name) => match name.len() {
-            0 => record.name,
-            _ => String::from(name),
-        },
-        None => record.name,
-    };
-
-    let description = match category.description {
-        Some(description) => match description.len() {
-            0 => record.description,
-            _ => String::from(description),
-        },
-        None => record.description,
-    };
+    get_record(id, public_only, db).await?;
 
-    let icon = match category.icon {
-        Some(icon) => match icon.len() {
-            0 => record.icon,
-            _ => String::from(icon),
-        },
-        None => record.icon,
-    };
+    let categories = query_as::<_, Category>(
+        format!(
+            "{}{}",
+            PUBLIC_CATEGORIES,
+            r#"SELECT c.id, c.name, c.description, c.icon, c.sort_order, c.parent_id, c.is_public, c.created_at, c.updated_at FROM category_closure AS cc INNER JOIN category AS c ON c.id = cc.descendant WHERE cc.ancestor = ? AND cc.depth > 0 AND (? IS NULL OR cc.depth <= ?) AND (? OR c.id IN (SELECT id FROM public_category)) ORDER BY cc.depth, c.sort_order, c.id"#
+        )
+        .as_str(),
+    )
+    .bind(id)
+    .bind(depth)
+    .bind(depth)
+    .bind(!public_only)
+    .fetch_all(&mut ***db)
+    .await?;
 
-    let order = if category.parent_id != record.parent_id {
-        match category.parent_id {
-            Some(parent_id) => match query(r#"SELECT MAX(c1.sort_order) AS sort_order FROM category AS c1 INNER JOIN category AS c2 ON c1.parent_id = c2.id WHERE c1.parent_id = ?"#).bind(parent_id).fetch_one(&mut ***db).await {
-                Ok(row) => match row.try_get::<i64, &str>("sort_order") {
-                    Ok(order) => order + 1,
-                    Err(_) => 0,
-                },
-                Err(_) => 0,
-            },
-            None => match query(r#"SELECT MAX(sort_order) AS sort_order FROM category"#)
-                .fetch_one(&mut ***db)
-                .await
-            {
-                Ok(row) => match row.try_get::<i64, &str>("sort_order") {
-                    Ok(order) => order + 1,
-                    Err(_) => 0,
-                },
-                Err(_) => 0,
-            },
-        }
-    } else {
-        record.sort_order
-    };
+    Ok(build_tree(categories, upload_url))
+}
 
-    let record = Category {
-        id: record.id,
-        name,
-        description,
-        icon,
-        parent_id: category.parent_id,
-        sort_order: record.sort_order,
-        created_at: record.created_at,
-        updated_at: record.updated_at,
-    };
+fn too_deep(max_depth: usize) -> ServiceError {
+    ServiceError::BadRequest(format!(
+        "Categories cannot be nested more than {} levels deep",
+        max_depth
+    ))
+}
 
-    query(r#"UPDATE category SET name = ?, description = ?, icon = ?, parent_id = ?, sort_order = ? WHERE id = ?"#)
-        .bind(&record.name)
-        .bind(&record.description)
-        .bind(&record.icon)
-        .bind(category.parent_id)
-        .bind(order)
-        .bind(record.id)
-        .execute(&mut ***db)
+/*
+ * The parent of every category. Inside a transaction the rows stay locked
+ * until it ends, so that concurrent moves cannot build a cycle between them.
+ */
+async fn load_tree(conn: &mut MySqlConnection) -> Result<HashMap<i64, Option<i64>>, ServiceError> {
+    let rows = query(r#"SELECT id, parent_id FROM category FOR UPDATE"#)
+        .fetch_all(&mut *conn)
         .await?;
 
-    Ok(())
+    Ok(rows
+        .iter()
+        .map(|row| {
+            (
+                row.get::<i64, &str>("id"),
+                row.get::<Option<i64>, &str>("parent_id"),
+            )
+        })
+        .collect())
 }
 
-pub async fn add_category(
-    category: &CreateCategory<'_>,
-    db: &mut Connection<MySQLDb>,
-) -> Result<(), ServiceError> {
-    let order = match category.parent_id {
-        Some(parent_id) => match query( r#"SELECT MAX(c1.sort_order) AS sort_order FROM category AS c1 INNER JOIN category AS c2 ON c1.parent_id = c2.id WHERE c1.parent_id = ?"#).bind(parent_id).fetch_one(&mut ***db).await {
-            Ok(row) => match row.try_get::<i64, &str>("sort_order") {
-                Ok(order) => order + 1,
-                Err(_) => 0,
-            },
-            Err(_) => 0,
-        },
-        None => match query(r#"SELECT MAX(sort_order) AS sort_order FROM category"#)
-            .fetch_one(&mut ***db)
-            .await
-        {
-            Ok(row) => match row.try_get::<i64, &str>("sort_order") {
-                Ok(order) => order + 1,
-                Err(_) => 0,
-            },
-            Err(_) => 0,
-        },
-    };
+fn depth(tree: &HashMap<i64, Option<i64>>, id: i64) -> usize {
+    let mut depth = 1;
 
-    query(r#"INSERT INTO category (name, description, icon, sort_order, parent_id) VALUES (?, ?, ?, ?, ?)"#)
-        .bind(category.name)
-        .bind(category.description)
-        .bind(category.icon)
-        .bind(order)
-        .bind(category.parent_id)
-        .execute(&mut ***db)
-        .await?;
+    let mut current = tree.get(&id).copied().flatten();
 
-    Ok(())
-}
+    // Bounded, in case the table already holds a cycle.
+    while let Some(parent_id) = current {
+        if depth > tree.len() {
+            break;
+        }
 
-pub async fn delete_category(id: &str, db: &mut Connection<MySQLDb>) -> Result<(), ServiceError> {
-    let sites_count =
-        query(r#"SELECT COUNT(site_id) AS count FROM category_site WHERE category_id = ?"#)
-            .bind(id)
-            .fetch_one(&mut ***db)
-            .await?
-            .get::<i64, &str>("count");
+        depth += 1;
 
-    if sites_count > 0 {
-        return Err(ServiceError::BadRequest(String::from("Category is in use")));
+        current = tree.get(&parent_id).copied().flatten();
     }
 
-    query(r#"DELETE FROM category WHERE id = ?"#)
-        .bind(id)
-        .execute(&mut ***db)
-        .await?;
+    depth
+}
 
-    Ok(())
+fn children_of(tree: &HashMap<i64, Option<i64>>) -> HashMap<i64, Vec<i64>> {
+    let mut children: HashMap<i64, Vec<i64>> = HashMap::new();
+
+    for (child, parent_id) in tree {
+        if let Some(parent_id) = parent_id {
+            children.entry(*parent_id).or_default().push(*child);
+        }
+    }
+
+    children
 }
 
-pub async fn get_sites(
-    category_id: &str,
-    search: Option<&str>,
-    upload_url: &str,
-    db: &mut Connection<MySQLDb>,
-) -> Result<Vec<response::site::Site>, ServiceError> {
-    let sites = match search {
-        Some(search) => query_as::<_, response::site::Site>(
-            r#"SELECT site.id, site.name, site.url, site.description, site.icon, site.visit_count FROM site INNER JOIN category_site ON site.id = category_site.site_id WHERE category_site.category_id = ? AND (site.name LIKE ? OR site.description LIKE ?) ORDER BY site.sort_order"#,
-        )
-        .bind(category_id)
-        .bind(format!("%{}%", search))
-        .bind(format!("%{}%", search))
-        .fetch_all(&mut ***db)
-        .await?,
-        None => query_as::<_, response::site::Site>(
-            r#"SELECT site.id, site.name, site.url, site.description, site.icon, site.visit_count FROM site INNER JOIN category_site ON site.id = category_site.site_id WHERE category_site.category_id = ? ORDER BY site.sort_order"#,
-        )
-        .bind(category_id)
-        .fetch_all(&mut ***db)
-        .await?,
-    };
+fn subtree(tree: &HashMap<i64, Option<i64>>, id: i64) -> Vec<i64> {
+    let children = children_of(tree);
 
-    Ok(sites
-        .iter()
-        .map(|site| {
-            let icon = site.icon.clone();
+    let mut ids = Vec::new();
 
-            let icon = if icon.starts_with("http") || icon.starts_with("https") {
-                icon
-            } else {
-                format!("{}/{}", upload_url, icon)
-            };
+    let mut stack = vec![id];
 
-            response::site::Site {
-                id: site.id,
-                name: site.name.clone(),
+    while let Some(current) = stack.pop() {
+        if ids.contains(&current) {
+            continue;
+        }
+
+        ids.push(current);
+
+        stack.extend(children.get(&current).into_iter().flatten());
+    }
+
+    ids
+}
+
+fn height(tree: &HashMap<i64, Option<i64>>, id: i64) -> usize {
+    let children = children_of(tree);
+
+    let mut visited = HashSet::new();
+
+    let mut stack = vec![(id, 1)];
+
+    let mut height = 0;
+
+    while let Some((current, level)) = stack.pop() {
+        if !visited.insert(current) {
+            continue;
+        }
+
+        height = height.max(level);
+
+        for child in children.get(&current).into_iter().flatten() {
+            stack.push((*child, level + 1));
+        }
+    }
+
+    height
+}
+
+fn check_move(
+    tree: &HashMap<i64, Option<i64>>,
+    id: i64,
+    parent_id: Option<i64>,
+    max_depth: Option<usize>,
+) -> Result<(), ServiceError> {
+    if !tree.contains_key(&id) {
+        return Err(ServiceError::BadRequest(String::from("Category not found")));
+    }
+
+    if let Some(parent_id) = parent_id {
+        if parent_id == id {
+            return Err(ServiceError::BadRequest(String::from(
+                "Category cannot be its own parent",
+            )));
+        }
+
+        if !tree.contains_key(&parent_id) {
+            return Err(ServiceError::BadRequest(String::from(
+                "Parent category not found",
+            )));
+        }
+
+        let mut current = Some(parent_id);
+
+        let mut steps = 0;
+
+        while let Some(ancestor) = current {
+            if ancestor == id {
+                return Err(ServiceError::BadRequest(String::from(
+                    "Category cannot be moved into its own subtree",
+                )));
+            }
+
+            steps += 1;
+
+            if steps > tree.len() {
+                break;
+            }
+
+            current = tree.get(&ancestor).copied().flatten();
+        }
+    }
+
+    if let Some(max_depth) = max_depth {
+        let parent_depth = parent_id.map_or(0, |parent_id| depth(tree, parent_id));
+
+        if parent_depth + height(tree, id) > max_depth {
+            return Err(too_deep(max_depth));
+        }
+    }
+
+    Ok(())
+}
+
+/*
+ * Numbers the children of `parent_id` from 0 in their current order, leaving
+ * out `moved`, or putting it last with `append`.
+ */
+async fn resequence(
+    parent_id: Option<i64>,
+    moved: i64,
+    append: bool,
+    conn: &mut MySqlConnection,
+) -> Result<(), ServiceError> {
+    let mut ids = query(
+        r#"SELECT id FROM category WHERE parent_id <=> ? AND id <> ? ORDER BY sort_order, id"#,
+    )
+    .bind(parent_id)
+    .bind(moved)
+    .fetch_all(&mut *conn)
+    .await?
+    .iter()
+    .map(|row| row.get::<i64, &str>("id"))
+    .collect::<Vec<i64>>();
+
+    if append {
+        ids.push(moved);
+    }
+
+    for (index, id) in ids.iter().enumerate() {
+        query(r#"UPDATE category SET sort_order = ? WHERE id = ?"#)
+            .bind(index as i64)
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn insert_closure(
+    id: i64,
+    parent_id: Option<i64>,
+    conn: &mut MySqlConnection,
+) -> Result<(), ServiceError> {
+    query(r#"INSERT INTO category_closure (ancestor, descendant, depth) SELECT ancestor, ?, depth + 1 FROM category_closure WHERE descendant = ? UNION ALL SELECT ?, ?, 0"#)
+        .bind(id)
+        .bind(parent_id)
+        .bind(id)
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+async fn move_closure(
+    id: i64,
+    parent_id: Option<i64>,
+    conn: &mut MySqlConnection,
+) -> Result<(), ServiceError> {
+    query(r#"DELETE old_path FROM category_closure AS old_path INNER JOIN category_closure AS below ON below.descendant = old_path.descendant LEFT JOIN category_closure AS inside ON inside.ancestor = below.ancestor AND inside.descendant = old_path.ancestor WHERE below.ancestor = ? AND inside.ancestor IS NULL"#)
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+
+    query(r#"INSERT INTO category_closure (ancestor, descendant, depth) SELECT above.ancestor, below.descendant, above.depth + below.depth + 1 FROM category_closure AS above CROSS JOIN category_closure AS below WHERE above.descendant = ? AND below.ancestor = ?"#)
+        .bind(parent_id)
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+async fn relocate(
+    id: i64,
+    parent_id: Option<i64>,
+    max_depth: Option<usize>,
+    conn: &mut MySqlConnection,
+) -> Result<(), ServiceError> {
+    let tree = load_tree(conn).await?;
+
+    check_move(&tree, id, parent_id, max_depth)?;
+
+    let old_parent_id = tree[&id];
+
+    if old_parent_id == parent_id {
+        return Ok(());
+    }
+
+    query(r#"UPDATE category SET parent_id = ? WHERE id = ?"#)
+        .bind(parent_id)
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+
+    move_closure(id, parent_id, conn).await?;
+
+    resequence(old_parent_id, id, false, conn).await?;
+
+    resequence(parent_id, id, true, conn).await?;
+
+    Ok(())
+}
+
+pub async fn move_category(
+    id: i64,
+    parent_id: Option<i64>,
+    max_depth: Option<usize>,
+    db: &mut Connection<MySQLDb>,
+) -> Result<(), ServiceError> {
+    let mut tx = (&mut ***db).begin().await?;
+
+    relocate(id, parent_id, max_depth, &mut tx).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn update_category<'r>(
+    id: &'r str,
+    category: &'r UpdateCategory<'r>,
+    max_depth: Option<usize>,
+    db: &mut Connection<MySQLDb>,
+) -> Result<(), ServiceError> {
+    let mut tx = (&mut ***db).begin().await?;
+
+    let record = query_as::<_, Category>(
+        r#"SELECT id, name, description, icon, sort_order, parent_id, is_public, created_at, updated_at FROM category WHERE id = ?"#,
+    )
+    .bind(id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::RowNotFound => ServiceError::BadRequest(String::from("Category not found")),
+        _ => ServiceError::DatabaseError(e),
+    })?;
+
+    let name = match category.name {
+        Some(name) => match name.len() {
+            0 => record.name,
+            _ => String::from(name),
+        },
+        None => record.name,
+    };
+
+    let description = match category.description {
+        Some(description) => match description.len() {
+            0 => record.description,
+            _ => String::from(description),
+        },
+        None => record.description,
+    };
+
+    let icon = match category.icon {
+        Some(icon) => match icon.len() {
+            0 => record.icon,
+            _ => String::from(icon),
+        },
+        None => record.icon,
+    };
+
+    if category.parent_id != record.parent_id {
+        relocate(record.id, category.parent_id, max_depth, &mut tx).await?;
+    }
+
+    query(r#"UPDATE category SET name = ?, description = ?, icon = ?, is_public = ? WHERE id = ?"#)
+        .bind(&name)
+        .bind(&description)
+        .bind(&icon)
+        .bind(category.is_public.unwrap_or(record.is_public))
+        .bind(record.id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn add_category(
+    category: &CreateCategory<'_>,
+    max_depth: Option<usize>,
+    db: &mut Connection<MySQLDb>,
+) -> Result<u64, ServiceError> {
+    let mut tx = (&mut ***db).begin().await?;
+
+    if let Some(parent_id) = category.parent_id {
+        // The parent and its ancestors, locked until the category is in.
+        let parent_depth = query(
+            r#"SELECT COUNT(ancestor) AS count FROM category_closure WHERE descendant = ? FOR UPDATE"#,
+        )
+        .bind(parent_id)
+        .fetch_one(&mut *tx)
+        .await?
+        .get::<i64, &str>("count") as usize;
+
+        if parent_depth == 0 {
+            return Err(ServiceError::BadRequest(String::from(
+                "Parent category not found",
+            )));
+        }
+
+        if let Some(max_depth) = max_depth {
+            if parent_depth >= max_depth {
+                return Err(too_deep(max_depth));
+            }
+        }
+    }
+
+    let order = match category.parent_id {
+        Some(parent_id) => match query( r#"SELECT MAX(c1.sort_order) AS sort_order FROM category AS c1 INNER JOIN category AS c2 ON c1.parent_id = c2.id WHERE c1.parent_id = ?"#).bind(parent_id).fetch_one(&mut *tx).await {
+            Ok(row) => match row.try_get::<i64, &str>("sort_order") {
+                Ok(order) => order + 1,
+                Err(_) => 0,
+            },
+            Err(_) => 0,
+        },
+        None => match query(r#"SELECT MAX(sort_order) AS sort_order FROM category"#)
+            .fetch_one(&mut *tx)
+            .await
+        {
+            Ok(row) => match row.try_get::<i64, &str>("sort_order") {
+                Ok(order) => order + 1,
+                Err(_) => 0,
+            },
+            Err(_) => 0,
+        },
+    };
+
+    let id = query(r#"INSERT INTO category (name, description, icon, sort_order, parent_id, is_public) VALUES (?, ?, ?, ?, ?, ?)"#)
+        .bind(category.name)
+        .bind(category.description)
+        .bind(category.icon)
+        .bind(order)
+        .bind(category.parent_id)
+        .bind(category.is_public.unwrap_or(false))
+        .execute(&mut *tx)
+        .await?
+        .last_insert_id();
+
+    insert_closure(id as i64, category.parent_id, &mut tx).await?;
+
+    tx.commit().await?;
+
+    Ok(id)
+}
+
+async fn is_public(category_id: &str, db: &mut Connection<MySQLDb>) -> Result<bool, ServiceError> {
+    let count = query(
+        format!(
+            "{}{}",
+            PUBLIC_CATEGORIES, r#"SELECT COUNT(id) AS count FROM public_category WHERE id = ?"#
+        )
+        .as_str(),
+    )
+    .bind(category_id)
+    .fetch_one(&mut ***db)
+    .await?
+    .get::<i64, &str>("count");
+
+    Ok(count > 0)
+}
+
+async fn site_ids(category_id: i64, conn: &mut MySqlConnection) -> Result<Vec<i64>, ServiceError> {
+    Ok(query(r#"SELECT site.id AS id FROM site INNER JOIN category_site ON site.id = category_site.site_id WHERE category_site.category_id = ? ORDER BY site.sort_order, site.id FOR UPDATE"#)
+        .bind(category_id)
+        .fetch_all(&mut *conn)
+        .await?
+        .iter()
+        .map(|row| row.get::<i64, &str>("id"))
+        .collect())
+}
+
+/*
+ * Deletes category `id`. With `refuse` it has to be empty; `cascade` deletes
+ * its subcategories and sites along with it, and `reassign` moves its direct
+ * subcategories and its sites to category `target` first.
+ */
+pub async fn delete_category(
+    id: i64,
+    strategy: DeleteStrategy,
+    target: Option<i64>,
+    max_depth: Option<usize>,
+    db: &mut Connection<MySQLDb>,
+) -> Result<response::category::DeletedCategory, ServiceError> {
+    let mut tx = (&mut ***db).begin().await?;
+
+    let tree = load_tree(&mut tx).await?;
+
+    let parent_id = match tree.get(&id) {
+        Some(parent_id) => *parent_id,
+        None => return Err(ServiceError::BadRequest(String::from("Category not found"))),
+    };
+
+    let children = query(r#"SELECT id FROM category WHERE parent_id = ? ORDER BY sort_order, id"#)
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await?
+        .iter()
+        .map(|row| row.get::<i64, &str>("id"))
+        .collect::<Vec<i64>>();
+
+    let sites = site_ids(id, &mut tx).await?;
+
+    let mut deleted = response::category::DeletedCategory {
+        deleted_categories: vec![id],
+        ..Default::default()
+    };
+
+    match strategy {
+        DeleteStrategy::Refuse => {
+            if !children.is_empty() {
+                return Err(ServiceError::BadRequest(String::from(
+                    "Category has subcategories",
+                )));
+            }
+
+            if !sites.is_empty() {
+                return Err(ServiceError::BadRequest(String::from("Category is in use")));
+            }
+        }
+        DeleteStrategy::Cascade => {
+            let categories = subtree(&tree, id);
+
+            let mut sites = Vec::new();
+
+            for category in &categories {
+                sites.extend(site_ids(*category, &mut tx).await?);
+
+                query(r#"DELETE FROM category_site WHERE category_id = ?"#)
+                    .bind(category)
+                    .execute(&mut *tx)
+                    .await?;
+
+                query(r#"DELETE FROM category WHERE id = ?"#)
+                    .bind(category)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+
+            // Sites also listed in a category outside the subtree are kept.
+            for site in sites {
+                let linked =
+                    query(r#"SELECT COUNT(site_id) AS count FROM category_site WHERE site_id = ?"#)
+                        .bind(site)
+                        .fetch_one(&mut *tx)
+                        .await?
+                        .get::<i64, &str>("count");
+
+                if linked == 0 {
+                    query(r#"DELETE FROM site WHERE id = ?"#)
+                        .bind(site)
+                        .execute(&mut *tx)
+                        .await?;
+
+                    deleted.deleted_sites.push(site);
+                }
+            }
+
+            deleted.deleted_categories = categories;
+        }
+        DeleteStrategy::Reassign => {
+            let target = target.ok_or_else(|| {
+                ServiceError::BadRequest(String::from("A target category is required"))
+            })?;
+
+            if !tree.contains_key(&target) {
+                return Err(ServiceError::BadRequest(String::from(
+                    "Target category not found",
+                )));
+            }
+
+            if subtree(&tree, id).contains(&target) {
+                return Err(ServiceError::BadRequest(String::from(
+                    "Target category cannot be inside the deleted one",
+                )));
+            }
+
+            for child in &children {
+                relocate(*child, Some(target), max_depth, &mut tx).await?;
+            }
+
+            // Sites already in the target keep their place there.
+            let linked = site_ids(target, &mut tx).await?;
+
+            let sites = sites
+                .into_iter()
+                .filter(|site| !linked.contains(site))
+                .collect::<Vec<i64>>();
+
+            let order = query(r#"SELECT IFNULL(MAX(site.sort_order) + 1, 0) AS sort_order FROM site INNER JOIN category_site ON site.id = category_site.site_id WHERE category_site.category_id = ?"#)
+                .bind(target)
+                .fetch_one(&mut *tx)
+                .await?
+                .get::<i64, &str>("sort_order");
+
+            for (index, site) in sites.iter().enumerate() {
+                query(r#"UPDATE site SET sort_order = ? WHERE id = ?"#)
+                    .bind(order + index as i64)
+                    .bind(site)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+
+            query(r#"DELETE FROM category_site WHERE category_id = ? AND site_id IN (SELECT site_id FROM (SELECT site_id FROM category_site WHERE category_id = ?) AS linked)"#)
+                .bind(id)
+                .bind(target)
+                .execute(&mut *tx)
+                .await?;
+
+            query(r#"UPDATE category_site SET category_id = ? WHERE category_id = ?"#)
+                .bind(target)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+
+            deleted.moved_categories = children;
+
+            deleted.moved_sites = sites;
+        }
+    }
+
+    query(r#"DELETE FROM category WHERE id = ?"#)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    resequence(parent_id, id, false, &mut tx).await?;
+
+    tx.commit().await?;
+
+    Ok(deleted)
+}
+
+pub async fn get_sites(
+    category_id: &str,
+    search: Option<&str>,
+    public_only: bool,
+    upload_url: &str,
+    db: &mut Connection<MySQLDb>,
+) -> Result<Vec<response::site::Site>, ServiceError> {
+    if public_only && !is_public(category_id, db).await? {
+        return Err(ServiceError::NotFound);
+    }
+
+    let sites = match search {
+        Some(search) => query_as::<_, response::site::Site>(
+            r#"SELECT site.id, site.name, site.url, site.description, site.icon, site.visit_count FROM site INNER JOIN category_site ON site.id = category_site.site_id WHERE category_site.category_id = ? AND (site.name LIKE ? OR site.description LIKE ?) ORDER BY site.sort_order"#,
+        )
+        .bind(category_id)
+        .bind(format!("%{}%", search))
+        .bind(format!("%{}%", search))
+        .fetch_all(&mut ***db)
+        .await?,
+        None => query_as::<_, response::site::Site>(
+            r#"SELECT site.id, site.name, site.url, site.description, site.icon, site.visit_count FROM site INNER JOIN category_site ON site.id = category_site.site_id WHERE category_site.category_id = ? ORDER BY site.sort_order"#,
+        )
+        .bind(category_id)
+        .fetch_all(&mut ***db)
+        .await?,
+    };
+
+    Ok(sites
+        .iter()
+        .map(|site| {
+            let icon = site.icon.clone();
+
+            let icon = if icon.starts_with("http") || icon.starts_with("https") {
+                icon
+            } else {
+                format!("{}/{}", upload_url, icon)
+            };
+
+            response::site::Site {
+                id: site.id,
+                name: site.name.clone(),
                 url: site.url.clone(),
                 description: site.description.clone(),
                 icon,
@@ -484,109 +992,538 @@ pub async fn get_sites(
         .collect())
 }
 
-pub async fn sort_categories(
-    active_id: i64,
-    over_id: Option<i64>,
-    parent_id: Option<i64>,
+fn group_sites(
+    placements: Vec<(i64, response::site::Site)>,
+    categories: &HashMap<i64, Category>,
+    upload_url: &str,
+) -> Vec<response::category::CategorySites> {
+    let mut groups: Vec<response::category::CategorySites> = Vec::new();
+
+    for (category_id, mut site) in placements {
+        let Some(category) = categories.get(&category_id) else {
+            continue;
+        };
+
+        if !site.icon.starts_with("http") && !site.icon.starts_with("https") {
+            site.icon = format!("{}/{}", upload_url, site.icon);
+        }
+
+        match groups.last_mut() {
+            Some(group) if group.category.id == category_id => group.sites.push(site),
+            _ => groups.push(response::category::CategorySites {
+                category: to_response(category.clone(), upload_url),
+                sites: vec![site],
+            }),
+        }
+    }
+
+    groups
+}
+
+/*
+ * The sites of category `category_id` and every category below it, grouped by
+ * category, level by level. A site listed in several of them only shows up in
+ * the first, and `total` counts each site once.
+ */
+pub async fn get_subtree_sites(
+    category_id: &str,
+    filter: &SitesFilter<'_>,
+    public_only: bool,
+    upload_url: &str,
     db: &mut Connection<MySQLDb>,
-) -> Result<(), ServiceError> {
-    let ids = match parent_id {
-        Some(parent_id) => {
-            query(r#"SELECT c1.id AS id FROM category AS c1 INNER JOIN category AS c2 ON c1.parent_id = c2.id WHERE c1.parent_id = ? ORDER BY c1.sort_order"#)
-                .bind(parent_id)
-                .fetch_all(&mut ***db)
-                .await?
+) -> Result<WithTotal<response::category::CategorySites>, ServiceError> {
+    let id = category_id.parse::<i64>()?;
+
+    get_record(id, public_only, db).await?;
+
+    let search = filter.search.map(|search| format!("%{}%", search));
+
+    let (limit, offset) = paginate(filter.page, filter.size);
+
+    let total = query(
+        format!(
+            "{}{}",
+            PUBLIC_CATEGORIES,
+            r#"SELECT COUNT(DISTINCT site.id) AS count FROM category_closure AS cc INNER JOIN category_site ON category_site.category_id = cc.descendant INNER JOIN site ON site.id = category_site.site_id WHERE cc.ancestor = ? AND (? OR cc.descendant IN (SELECT id FROM public_category)) AND (? IS NULL OR site.name LIKE ? OR site.description LIKE ?)"#
+        )
+        .as_str(),
+    )
+    .bind(id)
+    .bind(!public_only)
+    .bind(&search)
+    .bind(&search)
+    .bind(&search)
+    .fetch_one(&mut ***db)
+    .await?
+    .get::<i64, &str>("count");
+
+    let placements = query(
+        format!(
+            "{}{}",
+            PUBLIC_CATEGORIES,
+            r#"
+            , placed AS (
+                SELECT
+                    site.id, site.name, site.url, site.description, site.icon, site.visit_count, site.sort_order,
+                    c.id AS category_id, c.sort_order AS category_order, cc.depth,
+                    ROW_NUMBER() OVER (PARTITION BY site.id ORDER BY cc.depth, c.sort_order, c.id) AS placement
+                FROM
+                    category_closure AS cc
+                INNER JOIN
+                    category AS c ON c.id = cc.descendant
+                INNER JOIN
+                    category_site ON category_site.category_id = c.id
+                INNER JOIN
+                    site ON site.id = category_site.site_id
+                WHERE
+                    cc.ancestor = ?
+                    AND (? OR c.id IN (SELECT id FROM public_category))
+                    AND (? IS NULL OR site.name LIKE ? OR site.description LIKE ?)
+            )
+            SELECT
+                id, name, url, description, icon, visit_count, category_id
+            FROM
+                placed
+            WHERE
+                placement = 1
+            ORDER BY
+                depth, category_order, category_id, sort_order, id
+            LIMIT ? OFFSET ?
+            "#
+        )
+        .as_str(),
+    )
+    .bind(id)
+    .bind(!public_only)
+    .bind(&search)
+    .bind(&search)
+    .bind(&search)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&mut ***db)
+    .await?
+    .iter()
+    .map(|row| {
+        Ok((
+            row.get::<i64, &str>("category_id"),
+            response::site::Site::from_row(row)?,
+        ))
+    })
+    .collect::<Result<Vec<(i64, response::site::Site)>, sqlx::Error>>()?;
+
+    let category_ids = placements
+        .iter()
+        .map(|(category_id, _)| *category_id)
+        .collect::<HashSet<i64>>();
+
+    let mut categories = HashMap::new();
+
+    if !category_ids.is_empty() {
+        let sql = format!(
+            r#"SELECT id, name, description, icon, sort_order, parent_id, is_public, created_at, updated_at FROM category WHERE id IN ({})"#,
+            vec!["?"; category_ids.len()].join(", ")
+        );
+
+        let mut statement = query_as::<_, Category>(&sql);
+
+        for category_id in &category_ids {
+            statement = statement.bind(category_id);
         }
-        None => {
-            query(r#"SELECT id FROM category ORDER BY sort_order"#)
-                .fetch_all(&mut ***db)
-                .await?
+
+        for category in statement.fetch_all(&mut ***db).await? {
+            categories.insert(category.id, category);
         }
-    };
+    }
 
-    let ids = ids
-        .into_iter()
+    Ok(WithTotal {
+        total,
+        data: group_sites(placements, &categories, upload_url),
+    })
+}
+
+/*
+ * The children of `parent_id` in their order, locked until the transaction
+ * ends.
+ */
+async fn sibling_ids(
+    parent_id: Option<i64>,
+    conn: &mut MySqlConnection,
+) -> Result<Vec<i64>, ServiceError> {
+    Ok(
+        query(
+            r#"SELECT id FROM category WHERE parent_id <=> ? ORDER BY sort_order, id FOR UPDATE"#,
+        )
+        .bind(parent_id)
+        .fetch_all(&mut *conn)
+        .await?
+        .iter()
         .map(|row| row.get::<i64, &str>("id"))
-        .collect::<Vec<i64>>();
+        .collect(),
+    )
+}
 
-    let old_index = ids.iter().position(|id| *id == active_id).unwrap();
-    let new_index = match over_id {
-        Some(over_id) => ids.iter().position(|id| *id == over_id).unwrap(),
-        None => 0,
-    };
+async fn set_category_order(ids: &[i64], conn: &mut MySqlConnection) -> Result<(), ServiceError> {
+    for (index, id) in ids.iter().enumerate() {
+        query(r#"UPDATE category SET sort_order = ? WHERE id = ?"#)
+            .bind(index as i64)
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+    }
 
-    let mut ids = ids.clone();
-    ids.remove(old_index);
-    ids.insert(new_index, active_id);
+    Ok(())
+}
 
+async fn set_site_order(ids: &[i64], conn: &mut MySqlConnection) -> Result<(), ServiceError> {
     for (index, id) in ids.iter().enumerate() {
-        query(r#"UPDATE category SET sort_order = ? WHERE id = ?"#)
+        query(r#"UPDATE site SET sort_order = ? WHERE id = ?"#)
             .bind(index as i64)
             .bind(id)
-            .execute(&mut ***db)
+            .execute(&mut *conn)
             .await?;
     }
 
     Ok(())
 }
 
-pub async fn sort_category_sites(
-    id: i64,
+fn move_over(
+    ids: &mut Vec<i64>,
     active_id: i64,
     over_id: Option<i64>,
-    db: &mut Connection<MySQLDb>,
+    not_found: &str,
 ) -> Result<(), ServiceError> {
-    query(r#"
-                SELECT site.id as site_id
-                FROM site
-                INNER JOIN category
-                INNER JOIN category_site ON site.id = category_site.site_id AND category.id = category_site.category_id
-                WHERE site.id = ? AND category.id = ?;
-    "#)
-        .bind(active_id)
-        .bind(id)
-        .fetch_one(&mut ***db)
-        .await
-        .map_err(|e| {
-            error!("{}", e);
-
-            ServiceError::BadRequest(String::from("Site not found"))
-        })?;
-
-    let ids = query(
-        r#"
-                SELECT site.id as site_id
-                FROM site
-                INNER JOIN category
-                INNER JOIN category_site ON site.id = category_site.site_id AND category.id = category_site.category_id
-                WHERE category.id = ? ORDER BY site.sort_order;"#,
-    ).bind(id)
-        .fetch_all(&mut ***db)
-        .await?;
+    let position = |target: i64| {
+        ids.iter()
+            .position(|id| *id == target)
+            .ok_or_else(|| ServiceError::BadRequest(String::from(not_found)))
+    };
 
-    let ids = ids
-        .into_iter()
-        .map(|row| row.get::<i64, &str>("site_id"))
-        .collect::<Vec<i64>>();
+    let old_index = position(active_id)?;
 
-    let old_index = ids.iter().position(|id| *id == active_id).unwrap();
     let new_index = match over_id {
-        Some(over_id) => ids.iter().position(|id| *id == over_id).unwrap(),
+        Some(over_id) => position(over_id)?,
         None => 0,
     };
 
-    let mut ids = ids.clone();
-
     ids.remove(old_index);
     ids.insert(new_index, active_id);
 
-    for (index, id) in ids.iter().enumerate() {
-        query(r#"UPDATE site SET sort_order = ? WHERE id = ?"#)
-            .bind(index as i64)
-            .bind(id)
-            .execute(&mut ***db)
-            .await?;
+    Ok(())
+}
+
+/*
+ * Checks a new order against the list as it is now: `previous` has to match
+ * it, or the list changed since the client read it, and `order` has to hold
+ * the same ids.
+ */
+fn check_order(current: &[i64], previous: &[i64], order: &[i64]) -> Result<(), ServiceError> {
+    if current != previous {
+        return Err(ServiceError::Conflict(String::from(
+            "The order has changed in the meantime",
+        )));
+    }
+
+    let mut expected = current.to_vec();
+
+    expected.sort();
+
+    let mut given = order.to_vec();
+
+    given.sort();
+
+    if given != expected {
+        return Err(ServiceError::BadRequest(String::from(
+            "The order has to list every item exactly once",
+        )));
+    }
+
+    Ok(())
+}
+
+pub async fn sort_categories(
+    active_id: i64,
+    over_id: Option<i64>,
+    parent_id: Option<i64>,
+    db: &mut Connection<MySQLDb>,
+) -> Result<(), ServiceError> {
+    let mut tx = (&mut ***db).begin().await?;
+
+    let mut ids = sibling_ids(parent_id, &mut tx).await?;
+
+    move_over(&mut ids, active_id, over_id, "Category not found")?;
+
+    set_category_order(&ids, &mut tx).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn sort_category_sites(
+    id: i64,
+    active_id: i64,
+    over_id: Option<i64>,
+    db: &mut Connection<MySQLDb>,
+) -> Result<(), ServiceError> {
+    let mut tx = (&mut ***db).begin().await?;
+
+    let mut ids = site_ids(id, &mut tx).await?;
+
+    move_over(&mut ids, active_id, over_id, "Site not found")?;
+
+    set_site_order(&ids, &mut tx).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/*
+ * Applies the new order of every listed sibling list, all or none of them.
+ */
+pub async fn reorder_categories(
+    lists: &[CategoryOrder],
+    db: &mut Connection<MySQLDb>,
+) -> Result<(), ServiceError> {
+    let mut parents = HashSet::new();
+
+    if !lists.iter().all(|list| parents.insert(list.parent_id)) {
+        return Err(ServiceError::BadRequest(String::from(
+            "Each parent can only be listed once",
+        )));
+    }
+
+    let mut tx = (&mut ***db).begin().await?;
+
+    for list in lists {
+        let current = sibling_ids(list.parent_id, &mut tx).await?;
+
+        check_order(&current, &list.previous, &list.order)?;
+
+        set_category_order(&list.order, &mut tx).await?;
     }
 
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn reorder_category_sites(
+    id: i64,
+    order: &SiteOrder,
+    db: &mut Connection<MySQLDb>,
+) -> Result<(), ServiceError> {
+    let mut tx = (&mut ***db).begin().await?;
+
+    let current = site_ids(id, &mut tx).await?;
+
+    check_order(&current, &order.previous, &order.order)?;
+
+    set_site_order(&order.order, &mut tx).await?;
+
+    tx.commit().await?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /*
+     * 1 ─ 2 ─ 3
+     *   └ 4
+     * 5
+     */
+    fn tree() -> HashMap<i64, Option<i64>> {
+        HashMap::from([
+            (1, None),
+            (2, Some(1)),
+            (3, Some(2)),
+            (4, Some(1)),
+            (5, None),
+        ])
+    }
+
+    #[test]
+    fn test_check_move() {
+        let tree = tree();
+
+        assert!(check_move(&tree, 2, Some(5), None).is_ok());
+        assert!(check_move(&tree, 3, None, None).is_ok());
+        assert!(check_move(&tree, 2, Some(2), None).is_err());
+        assert!(check_move(&tree, 1, Some(3), None).is_err());
+        assert!(check_move(&tree, 2, Some(3), None).is_err());
+        assert!(check_move(&tree, 2, Some(6), None).is_err());
+        assert!(check_move(&tree, 6, None, None).is_err());
+    }
+
+    #[test]
+    fn test_subtree() {
+        let tree = tree();
+
+        let mut ids = subtree(&tree, 1);
+
+        ids.sort();
+
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+        assert_eq!(subtree(&tree, 5), vec![5]);
+    }
+
+    #[test]
+    fn test_check_move_depth() {
+        let tree = tree();
+
+        assert_eq!(depth(&tree, 3), 3);
+        assert_eq!(height(&tree, 1), 3);
+        assert!(check_move(&tree, 1, Some(5), Some(4)).is_ok());
+        assert!(check_move(&tree, 1, Some(5), Some(3)).is_err());
+        assert!(check_move(&tree, 5, Some(3), Some(3)).is_err());
+        assert!(check_move(&tree, 3, None, Some(1)).is_ok());
+    }
+
+    #[test]
+    fn test_build_tree() {
+        let categories = [
+            (1, None),
+            (5, None),
+            (2, Some(1)),
+            (4, Some(1)),
+            (3, Some(2)),
+        ]
+        .into_iter()
+        .map(|(id, parent_id)| Category {
+            id,
+            name: id.to_string(),
+            description: String::new(),
+            icon: String::from("icon.png"),
+            sort_order: 0,
+            parent_id,
+            is_public: false,
+            created_at: Default::default(),
+            updated_at: Default::default(),
+        })
+        .collect::<Vec<Category>>();
+
+        let tree = build_tree(categories.clone(), "/uploads");
+
+        assert_eq!(tree.iter().map(|c| c.id).collect::<Vec<i64>>(), vec![1, 5]);
+        assert_eq!(tree[0].icon, "/uploads/icon.png");
+
+        let children = tree[0].children.as_ref().unwrap();
+
+        assert_eq!(
+            children.iter().map(|c| c.id).collect::<Vec<i64>>(),
+            vec![2, 4]
+        );
+        assert_eq!(children[0].children.as_ref().unwrap()[0].id, 3);
+        assert!(children[1].children.is_none());
+        assert!(tree[1].children.is_none());
+
+        // A search result can miss the parents of the categories it found.
+        let tree = build_tree(categories[2..].to_vec(), "/uploads");
+
+        assert_eq!(tree.iter().map(|c| c.id).collect::<Vec<i64>>(), vec![2, 4]);
+    }
+
+    #[test]
+    fn test_check_order() {
+        assert!(check_order(&[1, 2, 3], &[1, 2, 3], &[3, 1, 2]).is_ok());
+        assert!(check_order(&[], &[], &[]).is_ok());
+        assert!(matches!(
+            check_order(&[1, 2, 3], &[2, 1, 3], &[3, 1, 2]),
+            Err(ServiceError::Conflict(_))
+        ));
+        assert!(matches!(
+            check_order(&[1, 2, 3], &[1, 2, 3], &[3, 1]),
+            Err(ServiceError::BadRequest(_))
+        ));
+        assert!(check_order(&[1, 2, 3], &[1, 2, 3], &[3, 1, 1]).is_err());
+        assert!(check_order(&[1, 2, 3], &[1, 2, 3], &[3, 1, 2, 4]).is_err());
+    }
+
+    #[test]
+    fn test_move_over() {
+        let mut ids = vec![1, 2, 3, 4];
+
+        move_over(&mut ids, 4, Some(2), "").unwrap();
+
+        assert_eq!(ids, vec![1, 4, 2, 3]);
+
+        move_over(&mut ids, 3, None, "").unwrap();
+
+        assert_eq!(ids, vec![3, 1, 4, 2]);
+        assert!(move_over(&mut ids, 5, None, "").is_err());
+        assert!(move_over(&mut ids, 1, Some(5), "").is_err());
+    }
+
+    fn category(id: i64, parent_id: Option<i64>) -> Category {
+        Category {
+            id,
+            name: id.to_string(),
+            description: String::new(),
+            icon: String::from("icon.png"),
+            sort_order: 0,
+            parent_id,
+            is_public: true,
+            created_at: Default::default(),
+            updated_at: Default::default(),
+        }
+    }
+
+    fn site(id: i64) -> response::site::Site {
+        response::site::Site {
+            id,
+            name: id.to_string(),
+            url: format!("https://{}.example.com", id),
+            description: String::new(),
+            icon: String::from("https://example.com/icon.png"),
+            visit_count: 0,
+        }
+    }
+
+    fn categories(ids: &[i64]) -> HashMap<i64, Category> {
+        ids.iter()
+            .map(|id| (*id, category(*id, (*id != 1).then_some(1))))
+            .collect()
+    }
+
+    fn grouped(groups: &[response::category::CategorySites]) -> Vec<(i64, Vec<i64>)> {
+        groups
+            .iter()
+            .map(|group| {
+                (
+                    group.category.id,
+                    group.sites.iter().map(|site| site.id).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_group_sites() {
+        let groups = group_sites(
+            vec![(1, site(10)), (2, site(11)), (2, site(12)), (3, site(13))],
+            &categories(&[1, 2, 3]),
+            "/uploads",
+        );
+
+        assert_eq!(
+            grouped(&groups),
+            vec![(1, vec![10]), (2, vec![11, 12]), (3, vec![13])]
+        );
+        assert_eq!(groups[0].category.icon, "/uploads/icon.png");
+        assert!(group_sites(Vec::new(), &categories(&[1]), "/uploads").is_empty());
+    }
+
+    #[test]
+    fn test_group_sites_skips_missing_categories() {
+        let groups = group_sites(
+            vec![(2, site(11)), (3, site(12)), (2, site(13))],
+            &categories(&[2]),
+            "/uploads",
+        );
+
+        assert_eq!(grouped(&groups), vec![(2, vec![11, 13])]);
+    }
+}
@@ -0,0 +1,377 @@
+use log::error;
+use rocket::futures::TryFutureExt;
+use rocket_db_pools::deadpool_redis::redis::{self, AsyncCommands};
+use rocket_db_pools::Connection;
+use serde::{Deserialize, Serialize};
+use sqlx::{query, Row};
+use uuid::Uuid;
+
+use crate::config::{Config, Smtp};
+use crate::errors::ServiceError;
+use crate::handlers::session::revoke_all_sessions;
+use crate::keys::JwtKeys;
+use crate::mailer::Mailer;
+use crate::password::hash_password;
+use crate::request::auth::ResetPassword;
+use crate::state::AppState;
+use crate::utils::{calculate_expires, parse_duration};
+use crate::{MySQLDb, RedisDb};
+
+const RESEND_INTERVAL: usize = 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Purpose {
+    Reset,
+    Verify,
+}
+
+impl Purpose {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Purpose::Reset => "reset",
+            Purpose::Verify => "verify",
+        }
+    }
+}
+
+/*
+ * The claims of an emailed link. `jti` names the Redis entry that makes the
+ * link single-use; it holds the address the link was sent to.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+struct EmailClaims {
+    sub: String,
+    purpose: Purpose,
+    jti: String,
+    exp: usize,
+}
+
+fn token_key(jti: &str) -> String {
+    format!("email_token:{}", jti)
+}
+
+fn cooldown_key(purpose: Purpose, username: &str) -> String {
+    format!("email_cooldown:{}:{}", purpose.as_str(), username)
+}
+
+fn settings<'a>(
+    state: &'a AppState,
+    config: &'a Config,
+) -> Result<(&'a Mailer, &'a Smtp), ServiceError> {
+    match (&state.mailer, &config.smtp) {
+        (Some(mailer), Some(smtp)) => Ok((mailer, smtp)),
+        _ => Err(ServiceError::NotFound),
+    }
+}
+
+fn invalid_link() -> ServiceError {
+    ServiceError::BadRequest(String::from("Invalid or expired link"))
+}
+
+/*
+ * Signs a link token for `username`, or returns `None` when one was sent for
+ * the same purpose within the last minute.
+ */
+async fn issue(
+    username: &str,
+    email: &str,
+    purpose: Purpose,
+    expires_in: &str,
+    state: &AppState,
+    cache: &mut Connection<RedisDb>,
+) -> Result<Option<String>, ServiceError> {
+    let first = redis::cmd("SET")
+        .arg(cooldown_key(purpose, username))
+        .arg(1)
+        .arg("NX")
+        .arg("PX")
+        .arg(RESEND_INTERVAL)
+        .query_async::<_, Option<String>>(&mut **cache)
+        .map_err(|e| {
+            error!("Failed to set email cooldown: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    if first.is_none() {
+        return Ok(None);
+    }
+
+    let jti = Uuid::new_v4().simple().to_string();
+
+    let claims = EmailClaims {
+        sub: String::from(username),
+        purpose,
+        jti: jti.clone(),
+        exp: calculate_expires(expires_in)? as usize,
+    };
+
+    let token = state.jwt_keys.encode(&claims).map_err(|e| {
+        error!("Failed to encode email token: {}", e);
+
+        ServiceError::InternalServerError
+    })?;
+
+    cache
+        .pset_ex::<_, _, ()>(
+            token_key(&jti),
+            email,
+            parse_duration(expires_in)?.num_milliseconds() as usize,
+        )
+        .map_err(|e| {
+            error!("Failed to set email token: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    Ok(Some(token))
+}
+
+fn decode(token: &str, purpose: Purpose, keys: &JwtKeys) -> Result<EmailClaims, ServiceError> {
+    keys.decode::<EmailClaims>(token)
+        .filter(|claims| claims.purpose == purpose)
+        .ok_or_else(invalid_link)
+}
+
+async fn redeem(
+    claims: &EmailClaims,
+    cache: &mut Connection<RedisDb>,
+) -> Result<String, ServiceError> {
+    cache
+        .get_del::<_, Option<String>>(token_key(&claims.jti))
+        .map_err(|e| {
+            error!("Failed to get email token: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?
+        .ok_or_else(invalid_link)
+}
+
+/*
+ * Mails a reset link to every enabled local account with this address.
+ * Succeeds either way, and the mail goes out in the background, so neither
+ * the answer nor its timing reveals which addresses are known. Directory
+ * accounts are skipped: a local password would outlive their being disabled
+ * in the directory.
+ */
+pub async fn forgot_password(
+    email: &str,
+    state: &AppState,
+    config: &Config,
+    db: &mut Connection<MySQLDb>,
+    cache: &mut Connection<RedisDb>,
+) -> Result<(), ServiceError> {
+    let (mailer, smtp) = settings(state, config)?;
+
+    if email.is_empty() {
+        return Ok(());
+    }
+
+    let usernames = query(
+        r#"SELECT username FROM user WHERE email = ? AND disabled = FALSE AND source = 'local'"#,
+    )
+    .bind(email)
+    .fetch_all(&mut ***db)
+    .await?
+    .iter()
+    .map(|row| row.get::<String, &str>("username"))
+    .collect::<Vec<String>>();
+
+    for username in usernames {
+        let token = match issue(
+            &username,
+            email,
+            Purpose::Reset,
+            &smtp.reset_expires_in,
+            state,
+            cache,
+        )
+        .await
+        {
+            Ok(Some(token)) => token,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Failed to issue reset link for {}: {}", username, e);
+
+                continue;
+            }
+        };
+
+        let body = format!(
+            "Hi {},\n\nfollow this link within {} to choose a new password:\n\n{}?token={}\n\nIf you did not ask for this, you can ignore this email.\n",
+            username, smtp.reset_expires_in, smtp.reset_url, token
+        );
+
+        let mailer = mailer.clone();
+
+        let to = String::from(email);
+
+        rocket::tokio::spawn(async move {
+            if let Err(e) = mailer
+                .send(&to, "Reset your StartPage password", body)
+                .await
+            {
+                error!("Failed to mail reset link to {}: {}", username, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+pub async fn reset_password(
+    data: &ResetPassword<'_>,
+    state: &AppState,
+    db: &mut Connection<MySQLDb>,
+    cache: &mut Connection<RedisDb>,
+) -> Result<String, ServiceError> {
+    let claims = decode(data.token, Purpose::Reset, &state.jwt_keys)?;
+
+    let username = claims.sub.as_str();
+
+    // Checked before the link is used up, so a rejected password can be retried.
+    state.password_policy.check(username, data.password)?;
+
+    let email = redeem(&claims, cache).await?;
+
+    let hashed_password = hash_password(data.password)?;
+
+    // A link sent to a previous address is no longer good, nor one for an
+    // account that has since come under a directory.
+    let updated = query(
+        r#"UPDATE user SET password = ? WHERE username = ? AND email = ? AND disabled = FALSE AND source = 'local'"#,
+    )
+    .bind(&hashed_password)
+    .bind(username)
+    .bind(&email)
+    .execute(&mut ***db)
+    .await?;
+
+    if updated.rows_affected() == 0 {
+        return Err(invalid_link());
+    }
+
+    revoke_all_sessions(username, cache).await?;
+
+    Ok(claims.sub)
+}
+
+pub async fn send_verification(
+    username: &str,
+    state: &AppState,
+    config: &Config,
+    db: &mut Connection<MySQLDb>,
+    cache: &mut Connection<RedisDb>,
+) -> Result<(), ServiceError> {
+    let (mailer, smtp) = settings(state, config)?;
+
+    let record = query(r#"SELECT email, email_verified FROM user WHERE username = ?"#)
+        .bind(username)
+        .fetch_one(&mut ***db)
+        .await?;
+
+    let email = record.get::<String, &str>("email");
+
+    if email.is_empty() {
+        return Err(ServiceError::BadRequest(String::from("No email address")));
+    }
+
+    if record.get::<bool, &str>("email_verified") {
+        return Err(ServiceError::BadRequest(String::from(
+            "Email is already verified",
+        )));
+    }
+
+    let token = issue(
+        username,
+        &email,
+        Purpose::Verify,
+        &smtp.verify_expires_in,
+        state,
+        cache,
+    )
+    .await?
+    .ok_or(ServiceError::TooManyRequests(
+        (RESEND_INTERVAL / 1000) as i64,
+    ))?;
+
+    mailer
+        .send(
+            &email,
+            "Verify your StartPage email",
+            format!(
+                "Hi {},\n\nfollow this link within {} to verify your email address:\n\n{}?token={}\n",
+                username, smtp.verify_expires_in, smtp.verify_url, token
+            ),
+        )
+        .await
+}
+
+pub async fn verify_email(
+    token: &str,
+    state: &AppState,
+    db: &mut Connection<MySQLDb>,
+    cache: &mut Connection<RedisDb>,
+) -> Result<(), ServiceError> {
+    let claims = decode(token, Purpose::Verify, &state.jwt_keys)?;
+
+    let email = redeem(&claims, cache).await?;
+
+    let current =
+        query(r#"SELECT COUNT(username) AS count FROM user WHERE username = ? AND email = ?"#)
+            .bind(&claims.sub)
+            .bind(&email)
+            .fetch_one(&mut ***db)
+            .await?
+            .get::<i64, &str>("count");
+
+    if current == 0 {
+        return Err(invalid_link());
+    }
+
+    query(r#"UPDATE user SET email_verified = TRUE WHERE username = ?"#)
+        .bind(&claims.sub)
+        .execute(&mut ***db)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Jwt;
+    use crate::Claims;
+
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let keys = JwtKeys::load(&Jwt::default()).unwrap();
+
+        let reset = keys
+            .encode(&EmailClaims {
+                sub: String::from("alice"),
+                purpose: Purpose::Reset,
+                jti: String::from("jti"),
+                exp: calculate_expires("30m").unwrap() as usize,
+            })
+            .unwrap();
+
+        let session = keys
+            .encode(&Claims {
+                sub: String::from("alice:session"),
+                company: String::from("StartPage"),
+                exp: calculate_expires("30m").unwrap() as usize,
+            })
+            .unwrap();
+
+        assert_eq!(decode(&reset, Purpose::Reset, &keys).unwrap().sub, "alice");
+        assert!(decode(&reset, Purpose::Verify, &keys).is_err());
+        assert!(decode(&session, Purpose::Reset, &keys).is_err());
+        assert!(keys.decode::<Claims>(&reset).is_none());
+    }
+}
@@ -0,0 +1,285 @@
+use ldap3::{ldap_escape, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry};
+use log::{error, warn};
+use rocket::tokio::time::timeout;
+use rocket_db_pools::Connection;
+use sqlx::{query, query_as, Acquire};
+use uuid::Uuid;
+
+use crate::config::Ldap;
+use crate::errors::ServiceError;
+use crate::handlers::user::{ensure_other_admin, insert_user, valid_username, NAME_MAX_LENGTH};
+use crate::models::user::{Role, Source};
+use crate::password::PasswordPolicy;
+use crate::request::user::CreateUser;
+use crate::utils::parse_duration;
+use crate::{models, MySQLDb};
+
+struct DirectoryUser {
+    nickname: Option<String>,
+    email: Option<String>,
+    avatar: Option<String>,
+    role: Role,
+}
+
+fn first_value(entry: &SearchEntry, attribute: &str) -> Option<String> {
+    entry.attrs.get(attribute)?.first().cloned()
+}
+
+fn map_role(groups: &[String], config: &Ldap) -> Role {
+    config
+        .groups
+        .iter()
+        .filter(|group| groups.iter().any(|dn| dn.eq_ignore_ascii_case(&group.dn)))
+        .map(|group| group.role)
+        .max()
+        .unwrap_or(config.default_role)
+}
+
+async fn bind(
+    username: &str,
+    password: &str,
+    config: &Ldap,
+    limit: std::time::Duration,
+) -> Result<Option<DirectoryUser>, LdapError> {
+    let settings = LdapConnSettings::new()
+        .set_starttls(config.starttls)
+        .set_conn_timeout(limit);
+
+    let (connection, mut ldap) = LdapConnAsync::with_settings(settings, &config.url).await?;
+
+    ldap3::drive!(connection);
+
+    ldap.simple_bind(&config.bind_dn, &config.bind_password)
+        .await?
+        .success()?;
+
+    let filter = config
+        .user_filter
+        .replace("{username}", &ldap_escape(username));
+
+    let mut attributes = vec![
+        config.nickname_attribute.as_str(),
+        config.email_attribute.as_str(),
+        config.group_attribute.as_str(),
+    ];
+
+    if let Some(avatar) = &config.avatar_attribute {
+        attributes.push(avatar.as_str());
+    }
+
+    let (mut entries, _) = ldap
+        .search(&config.base_dn, Scope::Subtree, &filter, attributes)
+        .await?
+        .success()?;
+
+    if entries.len() != 1 {
+        ldap.unbind().await?;
+
+        return Ok(None);
+    }
+
+    let entry = SearchEntry::construct(entries.remove(0));
+
+    let bound = ldap
+        .simple_bind(&entry.dn, password)
+        .await?
+        .success()
+        .is_ok();
+
+    ldap.unbind().await?;
+
+    if !bound {
+        return Ok(None);
+    }
+
+    let groups = entry
+        .attrs
+        .get(&config.group_attribute)
+        .cloned()
+        .unwrap_or_default();
+
+    Ok(Some(DirectoryUser {
+        nickname: first_value(&entry, &config.nickname_attribute),
+        email: first_value(&entry, &config.email_attribute),
+        avatar: config
+            .avatar_attribute
+            .as_ref()
+            .and_then(|attribute| first_value(&entry, attribute)),
+        role: map_role(&groups, config),
+    }))
+}
+
+async fn find_user(
+    username: &str,
+    db: &mut Connection<MySQLDb>,
+) -> Result<Option<models::user::User>, ServiceError> {
+    let record = query_as::<_, models::user::User>(
+        r#"SELECT username, nickname, password, avatar, email, role, disabled, source FROM user WHERE username = ?"#,
+    )
+    .bind(username)
+    .fetch_optional(&mut ***db)
+    .await?;
+
+    Ok(record)
+}
+
+/*
+ * `None` means the directory does not vouch for this login, and the caller
+ * should try the local password instead; an unreachable directory is treated
+ * the same, so local accounts keep working during an outage. A directory user
+ * with the name of a local account is not let in as that account.
+ */
+pub async fn authenticate(
+    username: &str,
+    password: &str,
+    config: &Ldap,
+    policy: &PasswordPolicy,
+    db: &mut Connection<MySQLDb>,
+) -> Result<Option<models::user::User>, ServiceError> {
+    // An empty password would make an unauthenticated bind, which succeeds.
+    if password.is_empty() {
+        return Ok(None);
+    }
+
+    let limit = parse_duration(&config.timeout)?
+        .to_std()
+        .map_err(|_| ServiceError::FormatError(String::from("Invalid LDAP timeout")))?;
+
+    // Bounds the whole exchange, so a directory that hangs mid-way cannot
+    // stall the login either.
+    let directory_user = match timeout(limit, bind(username, password, config, limit)).await {
+        Ok(Ok(Some(directory_user))) => directory_user,
+        Ok(Ok(None)) => return Ok(None),
+        Ok(Err(e)) => {
+            error!("Failed to authenticate against LDAP: {}", e);
+
+            return Ok(None);
+        }
+        Err(_) => {
+            error!("LDAP did not answer within {}", config.timeout);
+
+            return Ok(None);
+        }
+    };
+
+    let nickname = directory_user
+        .nickname
+        .map(|nickname| nickname.chars().take(NAME_MAX_LENGTH).collect())
+        .unwrap_or_else(|| String::from(username));
+
+    let email = directory_user.email.unwrap_or_default();
+
+    match find_user(username, db).await? {
+        Some(record) if record.source != Source::Ldap => {
+            warn!("LDAP user {} matches a local account", username);
+
+            return Ok(None);
+        }
+        Some(record) => {
+            let mut tx = (&mut ***db).begin().await?;
+
+            let demoted = record.role == Role::Admin && directory_user.role != Role::Admin;
+
+            let role = match demoted {
+                true if ensure_other_admin(username, &mut tx).await.is_err() => {
+                    warn!("Keeping {} an admin, there is no other one", username);
+
+                    record.role
+                }
+                _ => directory_user.role,
+            };
+
+            query(
+                r#"UPDATE user SET nickname = ?, email = ?, avatar = COALESCE(?, avatar), role = ? WHERE username = ?"#,
+            )
+            .bind(&nickname)
+            .bind(&email)
+            .bind(&directory_user.avatar)
+            .bind(role)
+            .bind(username)
+            .execute(&mut *tx)
+            .await?;
+
+            tx.commit().await?;
+        }
+        None if config.auto_provision && !valid_username(username) => {
+            warn!(
+                "LDAP user {} cannot be provisioned under that name",
+                username
+            );
+
+            return Err(ServiceError::BadRequest(format!(
+                "User names are limited to {} characters, ask an admin for an account",
+                NAME_MAX_LENGTH
+            )));
+        }
+        None if config.auto_provision => {
+            let password = Uuid::new_v4().simple().to_string();
+
+            insert_user(
+                &CreateUser {
+                    username,
+                    nickname: &nickname,
+                    password: &password,
+                    email: &email,
+                    avatar: directory_user.avatar.as_deref(),
+                    role: directory_user.role,
+                },
+                Source::Ldap,
+                policy,
+                db,
+            )
+            .await?;
+        }
+        None => {
+            warn!("LDAP user {} has no account", username);
+
+            return Ok(None);
+        }
+    }
+
+    find_user(username, db).await
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::LdapGroup;
+
+    use super::*;
+
+    #[test]
+    fn test_map_role() {
+        let config = Ldap {
+            groups: vec![
+                LdapGroup {
+                    dn: String::from("cn=editors,ou=groups,dc=example,dc=org"),
+                    role: Role::Editor,
+                },
+                LdapGroup {
+                    dn: String::from("cn=admins,ou=groups,dc=example,dc=org"),
+                    role: Role::Admin,
+                },
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(map_role(&[], &config), Role::Viewer);
+        assert_eq!(
+            map_role(
+                &[String::from("CN=Editors,OU=Groups,DC=example,DC=org")],
+                &config
+            ),
+            Role::Editor
+        );
+        assert_eq!(
+            map_role(
+                &[
+                    String::from("cn=editors,ou=groups,dc=example,dc=org"),
+                    String::from("cn=admins,ou=groups,dc=example,dc=org"),
+                ],
+                &config
+            ),
+            Role::Admin
+        );
+    }
+}
@@ -0,0 +1,805 @@
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{error, warn};
+use openidconnect::core::{
+    CoreAuthenticationFlow, CoreClient, CoreGenderClaim, CoreIdTokenClaims, CoreProviderMetadata,
+};
+use openidconnect::reqwest::async_http_client;
+use openidconnect::{
+    AdditionalClaims, AuthorizationCode, ClientId, ClientSecret, CsrfToken, HttpRequest,
+    HttpResponse, IdTokenClaims, IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier,
+    RedirectUrl, Scope, TokenResponse,
+};
+use rocket::futures::TryFutureExt;
+use rocket_db_pools::deadpool_redis::redis::{self, aio::ConnectionLike};
+use rocket_db_pools::Connection;
+use sqlx::{query, query_as, Row};
+use uuid::Uuid;
+
+use crate::config::{Config, Oidc};
+use crate::errors::ServiceError;
+use crate::handlers::auth::start_session;
+use crate::handlers::user::{insert_user, valid_username, NAME_MAX_LENGTH};
+use crate::models::user::Source;
+use crate::request::auth::OidcCallback;
+use crate::request::user::CreateUser;
+use crate::response::auth::JwtToken;
+use crate::state::AppState;
+use crate::{models, MySQLDb, RedisDb};
+
+const STATE_TTL: usize = 10 * 60 * 1000;
+
+/*
+ * How long discovered provider metadata, signing keys included, is reused
+ * before it is fetched again, so that rotated keys are picked up.
+ */
+const METADATA_TTL: Duration = Duration::from_secs(60 * 60);
+
+static METADATA: Mutex<Option<(String, Instant, CoreProviderMetadata)>> = Mutex::new(None);
+
+fn state_key(state: &str) -> String {
+    format!("oidc_state:{}", state)
+}
+
+fn settings(config: &Config) -> Result<&Oidc, ServiceError> {
+    config.oidc.as_ref().ok_or(ServiceError::NotFound)
+}
+
+fn normalize_issuer(issuer: &str) -> String {
+    String::from(issuer.trim_end_matches('/'))
+}
+
+async fn build_client<F, HC, RE>(oidc: &Oidc, http_client: HC) -> Result<CoreClient, ServiceError>
+where
+    F: Future<Output = Result<HttpResponse, RE>>,
+    HC: Fn(HttpRequest) -> F,
+    RE: std::error::Error + 'static,
+{
+    let issuer_url = IssuerUrl::new(oidc.issuer_url.clone()).map_err(|e| {
+        error!("Invalid OIDC issuer URL: {}", e);
+
+        ServiceError::InternalServerError
+    })?;
+
+    let redirect_url = RedirectUrl::new(oidc.redirect_url.clone()).map_err(|e| {
+        error!("Invalid OIDC redirect URL: {}", e);
+
+        ServiceError::InternalServerError
+    })?;
+
+    let metadata = provider_metadata(issuer_url, http_client).await?;
+
+    let client = CoreClient::from_provider_metadata(
+        metadata,
+        ClientId::new(oidc.client_id.clone()),
+        oidc.client_secret.clone().map(ClientSecret::new),
+    )
+    .set_redirect_uri(redirect_url);
+
+    Ok(client)
+}
+
+async fn provider_metadata<F, HC, RE>(
+    issuer_url: IssuerUrl,
+    http_client: HC,
+) -> Result<CoreProviderMetadata, ServiceError>
+where
+    F: Future<Output = Result<HttpResponse, RE>>,
+    HC: Fn(HttpRequest) -> F,
+    RE: std::error::Error + 'static,
+{
+    let cached = METADATA
+        .lock()
+        .ok()
+        .and_then(|cached| cached.clone())
+        .filter(|(issuer, fetched_at, _)| {
+            *issuer == issuer_url.as_str() && fetched_at.elapsed() < METADATA_TTL
+        });
+
+    if let Some((_, _, metadata)) = cached {
+        return Ok(metadata);
+    }
+
+    let issuer = issuer_url.to_string();
+
+    let metadata = CoreProviderMetadata::discover_async(issuer_url, http_client)
+        .await
+        .map_err(|e| {
+            error!("Failed to discover OIDC provider: {}", e);
+
+            ServiceError::InternalServerError
+        })?;
+
+    if let Ok(mut cached) = METADATA.lock() {
+        *cached = Some((issuer, Instant::now(), metadata.clone()));
+    }
+
+    Ok(metadata)
+}
+
+/*
+ * The name a new account is provisioned under. An email only counts once the
+ * provider has verified it.
+ */
+fn claimed_username<AC: AdditionalClaims>(
+    claims: &IdTokenClaims<AC, CoreGenderClaim>,
+    claim: &str,
+) -> Option<String> {
+    match claim {
+        "preferred_username" => claims
+            .preferred_username()
+            .map(|username| username.to_string()),
+        "email" => claims
+            .email()
+            .filter(|_| claims.email_verified() == Some(true))
+            .map(|email| email.to_string()),
+        "sub" => Some(claims.subject().to_string()),
+        _ => None,
+    }
+}
+
+pub async fn authorize_url(
+    config: &Config,
+    cache: &mut Connection<RedisDb>,
+) -> Result<String, ServiceError> {
+    begin(settings(config)?, async_http_client, &mut **cache).await
+}
+
+async fn begin<C, F, HC, RE>(
+    oidc: &Oidc,
+    http_client: HC,
+    connection: &mut C,
+) -> Result<String, ServiceError>
+where
+    C: ConnectionLike,
+    F: Future<Output = Result<HttpResponse, RE>>,
+    HC: Fn(HttpRequest) -> F,
+    RE: std::error::Error + 'static,
+{
+    let client = build_client(oidc, http_client).await?;
+
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+    let mut request = client
+        .authorize_url(
+            CoreAuthenticationFlow::AuthorizationCode,
+            CsrfToken::new_random,
+            Nonce::new_random,
+        )
+        .set_pkce_challenge(pkce_challenge);
+
+    for scope in &oidc.scopes {
+        request = request.add_scope(Scope::new(scope.clone()));
+    }
+
+    let (url, csrf_token, nonce) = request.url();
+
+    let key = state_key(csrf_token.secret());
+
+    redis::pipe()
+        .atomic()
+        .hset_multiple(
+            &key,
+            &[
+                ("verifier", pkce_verifier.secret()),
+                ("nonce", nonce.secret()),
+            ],
+        )
+        .ignore()
+        .pexpire(&key, STATE_TTL)
+        .ignore()
+        .query_async::<_, ()>(connection)
+        .map_err(|e| {
+            error!("Failed to set OIDC state: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    Ok(url.to_string())
+}
+
+/*
+ * The state of a flow is good for one callback only.
+ */
+async fn complete<C, F, HC, RE>(
+    oidc: &Oidc,
+    code: &str,
+    csrf_state: &str,
+    http_client: HC,
+    connection: &mut C,
+) -> Result<CoreIdTokenClaims, ServiceError>
+where
+    C: ConnectionLike,
+    F: Future<Output = Result<HttpResponse, RE>>,
+    HC: Fn(HttpRequest) -> F + Copy,
+    RE: std::error::Error + 'static,
+{
+    let key = state_key(csrf_state);
+
+    let (verifier, nonce): (Option<String>, Option<String>) = redis::pipe()
+        .atomic()
+        .hget(&key, "verifier")
+        .hget(&key, "nonce")
+        .del(&key)
+        .ignore()
+        .query_async(connection)
+        .map_err(|e| {
+            error!("Failed to get OIDC state: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    let (verifier, nonce) = match (verifier, nonce) {
+        (Some(verifier), Some(nonce)) => (verifier, nonce),
+        _ => return Err(ServiceError::Unauthorized),
+    };
+
+    let client = build_client(oidc, http_client).await?;
+
+    let response = client
+        .exchange_code(AuthorizationCode::new(String::from(code)))
+        .set_pkce_verifier(PkceCodeVerifier::new(verifier))
+        .request_async(http_client)
+        .await
+        .map_err(|e| {
+            error!("Failed to exchange OIDC code: {}", e);
+
+            ServiceError::Unauthorized
+        })?;
+
+    let id_token = response.id_token().ok_or_else(|| {
+        error!("OIDC provider did not return an ID token");
+
+        ServiceError::Unauthorized
+    })?;
+
+    let claims = id_token
+        .claims(&client.id_token_verifier(), &Nonce::new(nonce))
+        .map_err(|e| {
+            error!("Failed to verify OIDC ID token: {}", e);
+
+            ServiceError::Unauthorized
+        })?;
+
+    Ok(claims.clone())
+}
+
+/*
+ * Unknown identities are never matched to an existing account by name or
+ * email, that takes an admin linking them.
+ */
+pub async fn callback(
+    data: &OidcCallback<'_>,
+    state: &AppState,
+    config: &Config,
+    remote_ip: Option<String>,
+    user_agent: Option<String>,
+    db: &mut Connection<MySQLDb>,
+    cache: &mut Connection<RedisDb>,
+) -> Result<JwtToken, ServiceError> {
+    let oidc = settings(config)?;
+
+    let claims = complete(oidc, data.code, data.state, async_http_client, &mut **cache).await?;
+
+    let issuer = normalize_issuer(claims.issuer());
+
+    let subject = claims.subject().to_string();
+
+    let record = match find_identity(&issuer, &subject, db).await? {
+        Some(username) => find_user(&username, db)
+            .await?
+            .ok_or(ServiceError::NotFound)?,
+        None if oidc.auto_provision => {
+            let username = claimed_username(&claims, &oidc.username_claim).ok_or_else(|| {
+                ServiceError::BadRequest(format!("Missing {} claim", oidc.username_claim))
+            })?;
+
+            if !valid_username(&username) {
+                return Err(ServiceError::BadRequest(format!(
+                    "The {} claim is not a valid username of at most {} characters, ask an admin to link an account",
+                    oidc.username_claim, NAME_MAX_LENGTH
+                )));
+            }
+
+            let nickname = claims
+                .name()
+                .and_then(|name| name.get(None))
+                .map(|name| name.chars().take(NAME_MAX_LENGTH).collect())
+                .unwrap_or_else(|| username.clone());
+
+            let email = claims
+                .email()
+                .filter(|_| claims.email_verified() == Some(true))
+                .map(|email| email.to_string())
+                .unwrap_or_default();
+
+            // The password is never handed out, so the account can only log in
+            // through the provider.
+            let password = Uuid::new_v4().simple().to_string();
+
+            insert_user(
+                &CreateUser {
+                    username: &username,
+                    nickname: &nickname,
+                    password: &password,
+                    email: &email,
+                    avatar: None,
+                    role: oidc.default_role,
+                },
+                Source::Oidc,
+                &state.password_policy,
+                db,
+            )
+            .await
+            .map_err(|e| match e {
+                ServiceError::AlreadyExists(_) => {
+                    warn!(
+                        "OIDC identity {} claims existing user {}",
+                        subject, username
+                    );
+
+                    ServiceError::Conflict(String::from(
+                        "An account with this name exists, ask an admin to link it",
+                    ))
+                }
+                e => e,
+            })?;
+
+            link_identity(&issuer, &subject, &username, db).await?;
+
+            find_user(&username, db)
+                .await?
+                .ok_or(ServiceError::NotFound)?
+        }
+        None => {
+            return Err(ServiceError::BadRequest(String::from(
+                "No account for this identity",
+            )))
+        }
+    };
+
+    if record.disabled {
+        return Err(ServiceError::BadRequest(String::from(
+            "Account is disabled",
+        )));
+    }
+
+    start_session(
+        &record.username,
+        remote_ip.as_deref(),
+        user_agent.as_deref(),
+        state,
+        config,
+        db,
+        cache,
+    )
+    .await
+}
+
+async fn find_identity(
+    issuer: &str,
+    subject: &str,
+    db: &mut Connection<MySQLDb>,
+) -> Result<Option<String>, ServiceError> {
+    Ok(
+        query(r#"SELECT username FROM user_identity WHERE issuer = ? AND subject = ?"#)
+            .bind(issuer)
+            .bind(subject)
+            .fetch_optional(&mut ***db)
+            .await?
+            .map(|row| row.get::<String, &str>("username")),
+    )
+}
+
+async fn link_identity(
+    issuer: &str,
+    subject: &str,
+    username: &str,
+    db: &mut Connection<MySQLDb>,
+) -> Result<(), ServiceError> {
+    query(r#"INSERT INTO user_identity (issuer, subject, username, created_at) VALUES (?, ?, ?, UTC_TIMESTAMP())"#)
+        .bind(issuer)
+        .bind(subject)
+        .bind(username)
+        .execute(&mut ***db)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(e) if e.is_unique_violation() => ServiceError::AlreadyExists(
+                String::from("Identity is already linked"),
+            ),
+            e => ServiceError::DatabaseError(e),
+        })?;
+
+    Ok(())
+}
+
+pub async fn link_user(
+    username: &str,
+    subject: &str,
+    config: &Config,
+    db: &mut Connection<MySQLDb>,
+) -> Result<(), ServiceError> {
+    let oidc = settings(config)?;
+
+    if subject.is_empty() {
+        return Err(ServiceError::BadRequest(String::from("Invalid subject")));
+    }
+
+    find_user(username, db)
+        .await?
+        .ok_or(ServiceError::NotFound)?;
+
+    link_identity(&normalize_issuer(&oidc.issuer_url), subject, username, db).await
+}
+
+pub async fn unlink_user(
+    username: &str,
+    config: &Config,
+    db: &mut Connection<MySQLDb>,
+) -> Result<(), ServiceError> {
+    let oidc = settings(config)?;
+
+    query(r#"DELETE FROM user_identity WHERE issuer = ? AND username = ?"#)
+        .bind(normalize_issuer(&oidc.issuer_url))
+        .bind(username)
+        .execute(&mut ***db)
+        .await?;
+
+    Ok(())
+}
+
+async fn find_user(
+    username: &str,
+    db: &mut Connection<MySQLDb>,
+) -> Result<Option<models::user::User>, ServiceError> {
+    let record = query_as::<_, models::user::User>(
+        r#"SELECT username, nickname, password, avatar, email, role, disabled FROM user WHERE username = ?"#,
+    )
+    .bind(username)
+    .fetch_optional(&mut ***db)
+    .await?;
+
+    Ok(record)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use chrono::{Duration, Utc};
+    use openidconnect::core::{
+        CoreIdToken, CoreJsonWebKeySet, CoreJwsSigningAlgorithm, CoreResponseType,
+        CoreRsaPrivateSigningKey, CoreSubjectIdentifierType,
+    };
+    use openidconnect::http::header::CONTENT_TYPE;
+    use openidconnect::http::{HeaderMap, HeaderValue, StatusCode};
+    use openidconnect::url::{form_urlencoded, Url};
+    use openidconnect::{
+        Audience, AuthUrl, EmptyAdditionalClaims, EmptyAdditionalProviderMetadata, EndUserEmail,
+        EndUserUsername, JsonWebKeyId, JsonWebKeySetUrl, PrivateSigningKey, ResponseTypes,
+        StandardClaims, SubjectIdentifier, TokenUrl,
+    };
+    use rocket_db_pools::deadpool_redis::redis::{
+        Arg, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, Value,
+    };
+    use rsa::pkcs1::EncodeRsaPrivateKey;
+    use rsa::pkcs8::LineEnding;
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    const ISSUER: &str = "https://idp.example.com/realm/";
+
+    // A provider that signs an ID token for subject 42 once the code verifier
+    // matches the challenge of the pending authorization.
+    struct Provider {
+        key: CoreRsaPrivateSigningKey,
+        pending: Mutex<Option<(String, String)>>,
+    }
+
+    impl Provider {
+        fn new() -> Self {
+            let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+
+            let pem = key.to_pkcs1_pem(LineEnding::LF).unwrap();
+
+            Provider {
+                key: CoreRsaPrivateSigningKey::from_pem(
+                    &pem,
+                    Some(JsonWebKeyId::new(String::from("test"))),
+                )
+                .unwrap(),
+                pending: Mutex::new(None),
+            }
+        }
+
+        fn respond(&self, request: HttpRequest) -> HttpResponse {
+            match request.url.path() {
+                "/realm/.well-known/openid-configuration" => json(
+                    StatusCode::OK,
+                    serde_json::to_value(
+                        CoreProviderMetadata::new(
+                            IssuerUrl::new(String::from(ISSUER)).unwrap(),
+                            AuthUrl::new(format!("{}authorize", ISSUER)).unwrap(),
+                            JsonWebKeySetUrl::new(format!("{}jwks", ISSUER)).unwrap(),
+                            vec![ResponseTypes::new(vec![CoreResponseType::Code])],
+                            vec![CoreSubjectIdentifierType::Public],
+                            vec![CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256],
+                            EmptyAdditionalProviderMetadata {},
+                        )
+                        .set_token_endpoint(Some(
+                            TokenUrl::new(format!("{}token", ISSUER)).unwrap(),
+                        )),
+                    )
+                    .unwrap(),
+                ),
+                "/realm/jwks" => json(
+                    StatusCode::OK,
+                    serde_json::to_value(CoreJsonWebKeySet::new(vec![self
+                        .key
+                        .as_verification_key()]))
+                    .unwrap(),
+                ),
+                "/realm/token" => self.token(&request.body),
+                _ => json(StatusCode::NOT_FOUND, serde_json::json!({})),
+            }
+        }
+
+        fn token(&self, body: &[u8]) -> HttpResponse {
+            let form = form_urlencoded::parse(body)
+                .into_owned()
+                .collect::<HashMap<String, String>>();
+
+            let (challenge, nonce) = self.pending.lock().unwrap().clone().unwrap();
+
+            let verified = form
+                .get("code_verifier")
+                .map(|verifier| URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())));
+
+            if verified != Some(challenge) {
+                return json(
+                    StatusCode::BAD_REQUEST,
+                    serde_json::json!({ "error": "invalid_grant" }),
+                );
+            }
+
+            let claims = CoreIdTokenClaims::new(
+                IssuerUrl::new(String::from(ISSUER)).unwrap(),
+                vec![Audience::new(String::from("startpage"))],
+                Utc::now() + Duration::minutes(5),
+                Utc::now(),
+                StandardClaims::new(SubjectIdentifier::new(String::from("42"))),
+                EmptyAdditionalClaims {},
+            )
+            .set_nonce(Some(Nonce::new(nonce)));
+
+            let id_token = CoreIdToken::new(
+                claims,
+                &self.key,
+                CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+                None,
+                None,
+            )
+            .unwrap();
+
+            json(
+                StatusCode::OK,
+                serde_json::json!({
+                    "access_token": "access",
+                    "token_type": "Bearer",
+                    "id_token": id_token,
+                }),
+            )
+        }
+    }
+
+    fn json(status_code: StatusCode, body: serde_json::Value) -> HttpResponse {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        HttpResponse {
+            status_code,
+            headers,
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    #[derive(Default)]
+    struct Store(HashMap<String, HashMap<String, String>>);
+
+    impl Store {
+        fn run(&mut self, cmd: &Cmd) -> Result<Value, RedisError> {
+            let args = cmd
+                .args_iter()
+                .map(|arg| match arg {
+                    Arg::Simple(arg) => String::from_utf8_lossy(arg).into_owned(),
+                    Arg::Cursor => String::new(),
+                })
+                .collect::<Vec<String>>();
+
+            match args[0].as_str() {
+                "HSET" | "HMSET" => {
+                    let hash = self.0.entry(args[1].clone()).or_default();
+
+                    for field in args[2..].chunks(2) {
+                        hash.insert(field[0].clone(), field[1].clone());
+                    }
+
+                    Ok(Value::Okay)
+                }
+                "HGET" => Ok(self
+                    .0
+                    .get(&args[1])
+                    .and_then(|hash| hash.get(&args[2]))
+                    .map_or(Value::Nil, |value| Value::Data(value.clone().into_bytes()))),
+                "DEL" => Ok(Value::Int(self.0.remove(&args[1]).map_or(0, |_| 1))),
+                "PEXPIRE" => Ok(Value::Int(1)),
+                _ => Err(RedisError::from((
+                    ErrorKind::ClientError,
+                    "unsupported command",
+                ))),
+            }
+        }
+    }
+
+    impl ConnectionLike for Store {
+        fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+            let result = self.run(cmd);
+
+            Box::pin(async move { result })
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            pipeline: &'a Pipeline,
+            offset: usize,
+            _: usize,
+        ) -> RedisFuture<'a, Vec<Value>> {
+            let result = pipeline
+                .cmd_iter()
+                .map(|cmd| self.run(cmd))
+                .collect::<Result<Vec<Value>, RedisError>>()
+                .map(|values| match offset {
+                    0 => values,
+                    _ => vec![Value::Bulk(values)],
+                });
+
+            Box::pin(async move { result })
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    async fn authorize<HC, F>(
+        oidc: &Oidc,
+        http: HC,
+        provider: &Provider,
+        store: &mut Store,
+    ) -> String
+    where
+        F: Future<Output = Result<HttpResponse, Infallible>>,
+        HC: Fn(HttpRequest) -> F,
+    {
+        let url = Url::parse(&begin(oidc, http, store).await.unwrap()).unwrap();
+
+        let query = url
+            .query_pairs()
+            .into_owned()
+            .collect::<HashMap<String, String>>();
+
+        assert_eq!(query["code_challenge_method"], "S256");
+
+        *provider.pending.lock().unwrap() =
+            Some((query["code_challenge"].clone(), query["nonce"].clone()));
+
+        query["state"].clone()
+    }
+
+    #[rocket::async_test]
+    async fn test_flow() {
+        let provider = Provider::new();
+
+        let http = |request: HttpRequest| {
+            let response = provider.respond(request);
+
+            async move { Ok::<HttpResponse, Infallible>(response) }
+        };
+
+        let oidc = Oidc {
+            issuer_url: String::from(ISSUER),
+            client_id: String::from("startpage"),
+            redirect_url: String::from("https://startpage.example.com/api/auth/oidc/callback"),
+            ..Default::default()
+        };
+
+        let mut store = Store::default();
+
+        let state = authorize(&oidc, http, &provider, &mut store).await;
+
+        assert!(matches!(
+            complete(&oidc, "code", "forged", http, &mut store).await,
+            Err(ServiceError::Unauthorized)
+        ));
+
+        let claims = complete(&oidc, "code", &state, http, &mut store)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            normalize_issuer(claims.issuer()),
+            "https://idp.example.com/realm"
+        );
+        assert_eq!(
+            normalize_issuer(claims.issuer()),
+            normalize_issuer(&oidc.issuer_url)
+        );
+        assert_eq!(claims.subject().as_str(), "42");
+
+        // The state is good for a single callback.
+        assert!(matches!(
+            complete(&oidc, "code", &state, http, &mut store).await,
+            Err(ServiceError::Unauthorized)
+        ));
+
+        let state = authorize(&oidc, http, &provider, &mut store).await;
+
+        store
+            .0
+            .get_mut(&state_key(&state))
+            .unwrap()
+            .insert(String::from("verifier"), "forged".repeat(8));
+
+        assert!(matches!(
+            complete(&oidc, "code", &state, http, &mut store).await,
+            Err(ServiceError::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn test_claimed_username() {
+        let claims: CoreIdTokenClaims = IdTokenClaims::new(
+            IssuerUrl::new(String::from("http://localhost:8080/default")).unwrap(),
+            vec![Audience::new(String::from("startpage"))],
+            Utc::now() + Duration::minutes(5),
+            Utc::now(),
+            StandardClaims::new(SubjectIdentifier::new(String::from("42")))
+                .set_preferred_username(Some(EndUserUsername::new(String::from("alice"))))
+                .set_email(Some(EndUserEmail::new(String::from("alice@example.com")))),
+            EmptyAdditionalClaims {},
+        );
+
+        assert_eq!(
+            claimed_username(&claims, "preferred_username"),
+            Some(String::from("alice"))
+        );
+        assert_eq!(claimed_username(&claims, "email"), None);
+        assert_eq!(claimed_username(&claims, "sub"), Some(String::from("42")));
+        assert_eq!(claimed_username(&claims, "nickname"), None);
+
+        let claims: CoreIdTokenClaims = IdTokenClaims::new(
+            IssuerUrl::new(String::from("http://localhost:8080/default")).unwrap(),
+            vec![Audience::new(String::from("startpage"))],
+            Utc::now() + Duration::minutes(5),
+            Utc::now(),
+            StandardClaims::new(SubjectIdentifier::new(String::from("42")))
+                .set_email(Some(EndUserEmail::new(String::from("alice@example.com"))))
+                .set_email_verified(Some(true)),
+            EmptyAdditionalClaims {},
+        );
+
+        assert_eq!(
+            claimed_username(&claims, "email"),
+            Some(String::from("alice@example.com"))
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use log::{error, warn};
+use rocket::futures::TryFutureExt;
+use rocket_db_pools::deadpool_redis::redis::{self, AsyncCommands};
+use rocket_db_pools::Connection;
+
+use crate::config::Config;
+use crate::errors::ServiceError;
+use crate::utils::parse_duration;
+use crate::RedisDb;
+
+/*
+ * Counts a failed login and, once the threshold in ARGV[2] is reached, locks
+ * KEYS[2] out for ARGV[3] milliseconds doubled per extra failure, capped at
+ * ARGV[4]. The counter outlives the lockout so the backoff keeps growing.
+ */
+const RECORD_FAILURE: &str = r#"
+    local failures = redis.call('INCR', KEYS[1])
+    local window = tonumber(ARGV[1])
+
+    if failures == 1 then
+        redis.call('PEXPIRE', KEYS[1], window)
+    end
+
+    local threshold = tonumber(ARGV[2])
+
+    if failures < threshold then
+        return 0
+    end
+
+    local lockout = tonumber(ARGV[3]) * 2 ^ (failures - threshold)
+    lockout = math.floor(math.min(lockout, tonumber(ARGV[4])))
+
+    redis.call('SET', KEYS[2], 1, 'PX', lockout)
+    redis.call('PEXPIRE', KEYS[1], lockout + window)
+
+    return lockout
+"#;
+
+fn failures_key(scope: &str, value: &str) -> String {
+    format!("login_failures:{}:{}", scope, value)
+}
+
+fn lock_key(scope: &str, value: &str) -> String {
+    format!("login_lock:{}:{}", scope, value)
+}
+
+fn scopes(remote_ip: Option<&str>, username: &str) -> Vec<(&'static str, String)> {
+    let mut scopes = vec![("user", username.to_lowercase())];
+
+    if let Some(remote_ip) = remote_ip {
+        scopes.push(("ip", String::from(remote_ip)));
+    }
+
+    scopes
+}
+
+pub async fn check(
+    remote_ip: Option<&str>,
+    username: &str,
+    cache: &mut Connection<RedisDb>,
+) -> Result<(), ServiceError> {
+    let mut pipe = redis::pipe();
+
+    for (scope, value) in scopes(remote_ip, username) {
+        pipe.pttl(lock_key(scope, &value));
+    }
+
+    let ttls: Vec<i64> = pipe
+        .query_async(&mut **cache)
+        .map_err(|e| {
+            error!("Failed to check login lockout: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    let retry_after = ttls.into_iter().max().unwrap_or(-2);
+
+    if retry_after > 0 {
+        return Err(ServiceError::TooManyRequests((retry_after + 999) / 1000));
+    }
+
+    Ok(())
+}
+
+pub async fn record_failure(
+    remote_ip: Option<&str>,
+    username: &str,
+    config: &Config,
+    cache: &mut Connection<RedisDb>,
+) -> Result<(), ServiceError> {
+    let window = parse_duration(&config.rate_limit.window)?.num_milliseconds();
+    let lockout = parse_duration(&config.rate_limit.lockout)?.num_milliseconds();
+    let max_lockout = parse_duration(&config.rate_limit.max_lockout)?.num_milliseconds();
+
+    for (scope, value) in scopes(remote_ip, username) {
+        let threshold = match scope {
+            "ip" => config.rate_limit.ip_threshold,
+            _ => config.rate_limit.username_threshold,
+        };
+
+        let locked = redis::cmd("EVAL")
+            .arg(RECORD_FAILURE)
+            .arg(2)
+            .arg(failures_key(scope, &value))
+            .arg(lock_key(scope, &value))
+            .arg(window)
+            .arg(threshold)
+            .arg(lockout)
+            .arg(max_lockout)
+            .query_async::<_, i64>(&mut **cache)
+            .map_err(|e| {
+                error!("Failed to record login failure: {}", e);
+
+                ServiceError::InternalServerError
+            })
+            .await?;
+
+        if locked > 0 {
+            warn!("Locked out login {} {} for {}ms", scope, value, locked);
+        }
+    }
+
+    Ok(())
+}
+
+/*
+ * The counter of the client IP is kept, so that one valid account cannot be
+ * used to keep guessing the passwords of others.
+ */
+pub async fn reset(username: &str, cache: &mut Connection<RedisDb>) -> Result<(), ServiceError> {
+    cache
+        .del::<_, ()>(failures_key("user", &username.to_lowercase()))
+        .map_err(|e| {
+            error!("Failed to reset login failures: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,528 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Utc};
+use ipnet::IpNet;
+use log::error;
+use rocket::futures::TryFutureExt;
+use rocket_db_pools::deadpool_redis::redis::{self, aio::ConnectionLike};
+use rocket_db_pools::Connection;
+
+use crate::config;
+use crate::errors::ServiceError;
+use crate::response;
+use crate::state::AppState;
+use crate::utils::parse_duration;
+use crate::RedisDb;
+
+/*
+ * Checks a session on use and updates its last-seen time. KEYS[1] is its
+ * metadata and KEYS[2] its user's index; ARGV[1] is the session and ARGV[2]
+ * the current time. A session idle for longer than ARGV[3] seconds, unless
+ * that is 0, is revoked and returns -1. With ARGV[4] set, an IP prefix or
+ * user agent other than ARGV[5] and ARGV[6] returns -2. A session without
+ * metadata cannot be checked, so it is revoked and returns 0. The key
+ * suffixes must match `refresh_key`.
+ */
+const TOUCH_SESSION: &str = r#"
+    if redis.call('EXISTS', KEYS[1]) == 0 then
+        redis.call('DEL', ARGV[1], ARGV[1] .. ':refresh')
+        redis.call('ZREM', KEYS[2], ARGV[1])
+
+        return 0
+    end
+
+    local idle_timeout = tonumber(ARGV[3])
+    local last_seen = tonumber(redis.call('HGET', KEYS[1], 'last_seen'))
+
+    if idle_timeout > 0 and last_seen and tonumber(ARGV[2]) - last_seen > idle_timeout then
+        redis.call('DEL', ARGV[1], ARGV[1] .. ':refresh', KEYS[1])
+        redis.call('ZREM', KEYS[2], ARGV[1])
+
+        return -1
+    end
+
+    if ARGV[4] == '1' then
+        local ip_prefix = redis.call('HGET', KEYS[1], 'ip_prefix') or ''
+        local user_agent = redis.call('HGET', KEYS[1], 'user_agent') or ''
+
+        if ip_prefix ~= ARGV[5] or user_agent ~= ARGV[6] then
+            return -2
+        end
+    end
+
+    redis.call('HSET', KEYS[1], 'last_seen', ARGV[2])
+
+    return 1
+"#;
+
+pub struct SessionPolicy {
+    pub idle_timeout: Option<Duration>,
+    bind_fingerprint: bool,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+}
+
+impl SessionPolicy {
+    pub fn from_config(config: &config::Session) -> Result<Self, ServiceError> {
+        if config.ipv4_prefix > 32 || config.ipv6_prefix > 128 {
+            return Err(ServiceError::FormatError(String::from(
+                "Invalid session IP prefix length",
+            )));
+        }
+
+        let idle_timeout = config
+            .idle_timeout
+            .as_deref()
+            .map(parse_duration)
+            .transpose()?;
+
+        Ok(Self {
+            idle_timeout,
+            bind_fingerprint: config.bind_fingerprint,
+            ipv4_prefix: config.ipv4_prefix,
+            ipv6_prefix: config.ipv6_prefix,
+        })
+    }
+
+    /*
+     * The network a client IP belongs to, e.g. `203.0.113.0/24`, so that a
+     * device moving between addresses of its provider keeps its session.
+     */
+    pub fn ip_prefix(&self, remote_ip: &str) -> Option<String> {
+        let ip = remote_ip.parse::<IpAddr>().ok()?;
+
+        let prefix = match ip {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        };
+
+        Some(IpNet::new(ip, prefix).ok()?.trunc().to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionUse {
+    Active,
+    Idle,
+    Elsewhere,
+}
+
+/*
+ * Revokes every session in a user's index except the one given in ARGV[1],
+ * which may be empty to revoke them all. The key suffixes must match
+ * `refresh_key` and `meta_key`.
+ */
+const REVOKE_SESSIONS: &str = r#"
+    local sessions = redis.call('ZRANGE', KEYS[1], 0, -1)
+    local revoked = 0
+
+    for _, session in ipairs(sessions) do
+        if session ~= ARGV[1] then
+            redis.call('DEL', session, session .. ':refresh', session .. ':meta')
+            redis.call('ZREM', KEYS[1], session)
+            revoked = revoked + 1
+        end
+    end
+
+    return revoked
+"#;
+
+pub fn refresh_key(session: &str) -> String {
+    format!("{}:refresh", session)
+}
+
+pub fn meta_key(session: &str) -> String {
+    format!("{}:meta", session)
+}
+
+fn index_key(username: &str) -> String {
+    format!("sessions:{}", username)
+}
+
+fn username_of(session: &str) -> &str {
+    match session.split_once(':') {
+        Some((username, _)) => username,
+        None => session,
+    }
+}
+
+fn parse_timestamp(value: Option<&String>) -> Option<DateTime<Utc>> {
+    let timestamp = value?.parse::<i64>().ok()?;
+
+    DateTime::from_timestamp(timestamp, 0)
+}
+
+pub async fn create_session(
+    session: &str,
+    remote_ip: Option<&str>,
+    user_agent: Option<&str>,
+    state: &AppState,
+    cache: &mut Connection<RedisDb>,
+) -> Result<(), ServiceError> {
+    let now = Utc::now();
+
+    let ttl = state.refresh_expiration.num_milliseconds() as usize;
+
+    let mut fields = vec![
+        ("created_at", now.timestamp().to_string()),
+        ("last_seen", now.timestamp().to_string()),
+    ];
+
+    if let Some(remote_ip) = remote_ip {
+        fields.push(("ip", String::from(remote_ip)));
+
+        if let Some(ip_prefix) = state.session_policy.ip_prefix(remote_ip) {
+            fields.push(("ip_prefix", ip_prefix));
+        }
+    }
+
+    if let Some(user_agent) = user_agent {
+        fields.push(("user_agent", String::from(user_agent)));
+    }
+
+    let index = index_key(username_of(session));
+
+    redis::pipe()
+        .atomic()
+        .hset_multiple(meta_key(session), &fields)
+        .ignore()
+        .pexpire(meta_key(session), ttl)
+        .ignore()
+        .zadd(
+            &index,
+            session,
+            (now + state.refresh_expiration).timestamp_millis(),
+        )
+        .ignore()
+        .pexpire(&index, ttl)
+        .ignore()
+        .query_async::<_, ()>(&mut **cache)
+        .map_err(|e| {
+            error!("Failed to create session: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    Ok(())
+}
+
+/*
+ * Takes any connection, as the JWT guard has no `Connection<RedisDb>` of its
+ * own.
+ */
+pub async fn touch_session<C: ConnectionLike>(
+    session: &str,
+    remote_ip: Option<&str>,
+    user_agent: Option<&str>,
+    policy: &SessionPolicy,
+    connection: &mut C,
+) -> Result<SessionUse, ServiceError> {
+    let ip_prefix = remote_ip
+        .and_then(|remote_ip| policy.ip_prefix(remote_ip))
+        .unwrap_or_default();
+
+    let result = redis::cmd("EVAL")
+        .arg(TOUCH_SESSION)
+        .arg(2)
+        .arg(meta_key(session))
+        .arg(index_key(username_of(session)))
+        .arg(session)
+        .arg(Utc::now().timestamp())
+        .arg(
+            policy
+                .idle_timeout
+                .map_or(0, |timeout| timeout.num_seconds()),
+        )
+        .arg(if policy.bind_fingerprint { "1" } else { "0" })
+        .arg(ip_prefix)
+        .arg(user_agent.unwrap_or_default())
+        .query_async::<_, i64>(connection)
+        .map_err(|e| {
+            error!("Failed to touch session: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    Ok(match result {
+        1 => SessionUse::Active,
+        -2 => SessionUse::Elsewhere,
+        _ => SessionUse::Idle,
+    })
+}
+
+pub async fn extend_session(
+    session: &str,
+    state: &AppState,
+    cache: &mut Connection<RedisDb>,
+) -> Result<(), ServiceError> {
+    let ttl = state.refresh_expiration.num_milliseconds() as usize;
+
+    let index = index_key(username_of(session));
+
+    redis::pipe()
+        .atomic()
+        .pexpire(meta_key(session), ttl)
+        .ignore()
+        .cmd("ZADD")
+        .arg(&index)
+        .arg("XX")
+        .arg((Utc::now() + state.refresh_expiration).timestamp_millis())
+        .arg(session)
+        .ignore()
+        .pexpire(&index, ttl)
+        .ignore()
+        .query_async::<_, ()>(&mut **cache)
+        .map_err(|e| {
+            error!("Failed to extend session: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_sessions(
+    username: &str,
+    current: &str,
+    cache: &mut Connection<RedisDb>,
+) -> Result<Vec<response::session::Session>, ServiceError> {
+    let index = index_key(username);
+
+    let (sessions,): (Vec<String>,) = redis::pipe()
+        .atomic()
+        .zrembyscore(&index, "-inf", Utc::now().timestamp_millis())
+        .ignore()
+        .zrange(&index, 0, -1)
+        .query_async(&mut **cache)
+        .map_err(|e| {
+            error!("Failed to get sessions: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    if sessions.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut pipe = redis::pipe();
+
+    for session in &sessions {
+        pipe.hgetall(meta_key(session));
+    }
+
+    let metas: Vec<HashMap<String, String>> = pipe
+        .query_async(&mut **cache)
+        .map_err(|e| {
+            error!("Failed to get sessions: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    let prefix = format!("{}:", username);
+
+    let mut result = sessions
+        .iter()
+        .zip(metas)
+        .filter(|(_, meta)| !meta.is_empty())
+        .filter_map(|(session, meta)| {
+            let id = session.strip_prefix(&prefix)?;
+
+            Some(response::session::Session {
+                id: String::from(id),
+                created_at: parse_timestamp(meta.get("created_at")),
+                last_seen: parse_timestamp(meta.get("last_seen")),
+                ip: meta.get("ip").cloned(),
+                user_agent: meta.get("user_agent").cloned(),
+                current: session == current,
+            })
+        })
+        .collect::<Vec<response::session::Session>>();
+
+    result.sort_by_key(|session| Reverse(session.last_seen));
+
+    Ok(result)
+}
+
+pub async fn revoke_session(
+    session: &str,
+    cache: &mut Connection<RedisDb>,
+) -> Result<(), ServiceError> {
+    redis::pipe()
+        .atomic()
+        .del(&[
+            String::from(session),
+            refresh_key(session),
+            meta_key(session),
+        ])
+        .ignore()
+        .zrem(index_key(username_of(session)), session)
+        .ignore()
+        .query_async::<_, ()>(&mut **cache)
+        .map_err(|e| {
+            error!("Failed to revoke session: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    Ok(())
+}
+
+async fn revoke_sessions_except(
+    username: &str,
+    keep: &str,
+    cache: &mut Connection<RedisDb>,
+) -> Result<usize, ServiceError> {
+    let revoked = redis::cmd("EVAL")
+        .arg(REVOKE_SESSIONS)
+        .arg(1)
+        .arg(index_key(username))
+        .arg(keep)
+        .query_async::<_, usize>(&mut **cache)
+        .map_err(|e| {
+            error!("Failed to revoke sessions: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    Ok(revoked)
+}
+
+pub async fn revoke_other_sessions(
+    username: &str,
+    current: &str,
+    cache: &mut Connection<RedisDb>,
+) -> Result<usize, ServiceError> {
+    revoke_sessions_except(username, current, cache).await
+}
+
+pub async fn revoke_all_sessions(
+    username: &str,
+    cache: &mut Connection<RedisDb>,
+) -> Result<usize, ServiceError> {
+    revoke_sessions_except(username, "", cache).await
+}
+
+#[cfg(test)]
+mod test {
+    use rocket_db_pools::deadpool_redis::redis::{
+        Arg, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, Value,
+    };
+
+    use super::*;
+
+    // Only knows whether a session has metadata, as if neither the idle
+    // timeout nor the fingerprint were checked.
+    #[derive(Default)]
+    struct Store(HashMap<String, String>);
+
+    impl ConnectionLike for Store {
+        fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+            let args = cmd
+                .args_iter()
+                .map(|arg| match arg {
+                    Arg::Simple(arg) => String::from_utf8_lossy(arg).into_owned(),
+                    Arg::Cursor => String::new(),
+                })
+                .collect::<Vec<String>>();
+
+            let result = match args[0].as_str() {
+                "EVAL" if args[1] == TOUCH_SESSION => match self.0.contains_key(&args[3]) {
+                    true => Ok(Value::Int(1)),
+                    false => {
+                        self.0.remove(&args[5]);
+                        self.0.remove(&refresh_key(&args[5]));
+
+                        Ok(Value::Int(0))
+                    }
+                },
+                _ => Err(RedisError::from((
+                    ErrorKind::ClientError,
+                    "unsupported command",
+                ))),
+            };
+
+            Box::pin(async move { result })
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            _: &'a Pipeline,
+            _: usize,
+            _: usize,
+        ) -> RedisFuture<'a, Vec<Value>> {
+            Box::pin(async move {
+                Err(RedisError::from((
+                    ErrorKind::ClientError,
+                    "unsupported pipeline",
+                )))
+            })
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_touch_session_without_metadata() {
+        let policy = SessionPolicy::from_config(&config::Session::default()).unwrap();
+
+        let mut store = Store::default();
+
+        store
+            .0
+            .insert(String::from("alice:1"), String::from("token"));
+        store
+            .0
+            .insert(refresh_key("alice:1"), String::from("refresh"));
+
+        assert_eq!(
+            touch_session("alice:1", None, None, &policy, &mut store)
+                .await
+                .unwrap(),
+            SessionUse::Idle
+        );
+        assert!(store.0.is_empty());
+
+        store
+            .0
+            .insert(String::from("alice:2"), String::from("token"));
+        store.0.insert(meta_key("alice:2"), String::new());
+
+        assert_eq!(
+            touch_session("alice:2", None, None, &policy, &mut store)
+                .await
+                .unwrap(),
+            SessionUse::Active
+        );
+    }
+
+    #[test]
+    fn test_ip_prefix() {
+        let policy = SessionPolicy::from_config(&config::Session::default()).unwrap();
+
+        assert_eq!(
+            policy.ip_prefix("203.0.113.42").as_deref(),
+            Some("203.0.113.0/24")
+        );
+        assert_eq!(
+            policy.ip_prefix("2001:db8:1:2:3:4:5:6").as_deref(),
+            Some("2001:db8:1:2::/64")
+        );
+        assert_eq!(policy.ip_prefix("unknown"), None);
+        assert!(SessionPolicy::from_config(&config::Session {
+            ipv4_prefix: 33,
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -2,6 +2,7 @@ use rocket_db_pools::Connection;
 use sqlx::{query, query_as, Row};
 
 use crate::errors::ServiceError;
+use crate::handlers::category::PUBLIC_CATEGORIES;
 use crate::models::category::Category;
 use crate::models::site::Site;
 use crate::request::site::{CreateSite, UpdateSite};
@@ -14,31 +15,52 @@ pub async fn get_sites(
     page: i64,
     size: i64,
     search: Option<&str>,
+    public_only: bool,
     upload_url: &str,
     db: &mut Connection<MySQLDb>,
 ) -> Result<WithTotal<SiteWithCategory>, ServiceError> {
     let count = match search {
-        Some(search) => {
-            query(r#"SELECT COUNT(id) AS count FROM site WHERE NAME LIKE ? OR description LIKE ?"#)
-                .bind(format!("%{}%", search))
-                .bind(format!("%{}%", search))
-                .fetch_one(&mut ***db)
-                .await?
-                .get::<i64, &str>("count")
-        }
-        None => query(r#"SELECT COUNT(id) AS count FROM site"#)
-            .fetch_one(&mut ***db)
-            .await?
-            .get::<i64, &str>("count"),
+        Some(search) => query(
+            format!(
+                "{}{}",
+                PUBLIC_CATEGORIES,
+                r#"SELECT COUNT(DISTINCT site.id) AS count FROM site INNER JOIN category_site ON site.id = category_site.site_id WHERE (? OR category_site.category_id IN (SELECT id FROM public_category)) AND (site.name LIKE ? OR site.description LIKE ?)"#
+            )
+            .as_str(),
+        )
+        .bind(!public_only)
+        .bind(format!("%{}%", search))
+        .bind(format!("%{}%", search))
+        .fetch_one(&mut ***db)
+        .await?
+        .get::<i64, &str>("count"),
+        None => query(
+            format!(
+                "{}{}",
+                PUBLIC_CATEGORIES,
+                r#"SELECT COUNT(DISTINCT site.id) AS count FROM site INNER JOIN category_site ON site.id = category_site.site_id WHERE ? OR category_site.category_id IN (SELECT id FROM public_category)"#
+            )
+            .as_str(),
+        )
+        .bind(!public_only)
+        .fetch_one(&mut ***db)
+        .await?
+        .get::<i64, &str>("count"),
     };
 
     let sites = match search {
         Some(search) => query_as::<_, SiteWithCategory>(
-            r#"SELECT site.id AS id, site.name AS name, site.url AS url, site.icon AS icon, site.description AS description, site.visit_count as visit_count, category.name AS category FROM site
+            format!(
+                "{}{}",
+                PUBLIC_CATEGORIES,
+                r#"SELECT site.id AS id, site.name AS name, site.url AS url, site.icon AS icon, site.description AS description, site.visit_count as visit_count, category.name AS category FROM site
                 INNER JOIN category
-                INNER JOIN category_site ON site.id = category_site.site_id AND category.id = category_site.category_id WHERE site.name LIKE ? OR site.description LIKE ? LIMIT ? OFFSET ?
-                "#,
+                INNER JOIN category_site ON site.id = category_site.site_id AND category.id = category_site.category_id WHERE (? OR category.id IN (SELECT id FROM public_category)) AND (site.name LIKE ? OR site.description LIKE ?) LIMIT ? OFFSET ?
+                "#
+            )
+            .as_str(),
         )
+        .bind(!public_only)
         .bind(format!("%{}%", search))
         .bind(format!("%{}%", search))
         .bind(size)
@@ -46,11 +68,17 @@ pub async fn get_sites(
         .fetch_all(&mut ***db)
         .await?,
         None => query_as::<_, SiteWithCategory>(
-            r#"SELECT site.id AS id, site.name AS name, site.url AS url, site.icon AS icon, site.description AS description, site.visit_count as visit_count, category.name AS category FROM site
+            format!(
+                "{}{}",
+                PUBLIC_CATEGORIES,
+                r#"SELECT site.id AS id, site.name AS name, site.url AS url, site.icon AS icon, site.description AS description, site.visit_count as visit_count, category.name AS category FROM site
                 INNER JOIN category
-                INNER JOIN category_site ON site.id = category_site.site_id AND category.id = category_site.category_id LIMIT ? OFFSET ?
-                "#,
+                INNER JOIN category_site ON site.id = category_site.site_id AND category.id = category_site.category_id WHERE ? OR category.id IN (SELECT id FROM public_category) LIMIT ? OFFSET ?
+                "#
+            )
+            .as_str(),
         )
+        .bind(!public_only)
         .bind(size)
         .bind(page * size)
         .fetch_all(&mut ***db)
@@ -87,9 +115,9 @@ pub async fn get_sites(
 pub async fn add_site(
     site: &CreateSite<'_>,
     db: &mut Connection<MySQLDb>,
-) -> Result<(), ServiceError> {
+) -> Result<u64, ServiceError> {
     query_as::<_, Category>(
-        r#"SELECT id, name, description, icon, sort_order, parent_id, created_at, updated_at FROM category WHERE id = ?"#,
+        r#"SELECT id, name, description, icon, sort_order, parent_id, is_public, created_at, updated_at FROM category WHERE id = ?"#,
     )
     .bind(site.category)
     .fetch_one(&mut ***db)
@@ -129,7 +157,7 @@ pub async fn add_site(
         .execute(&mut ***db)
         .await?;
 
-    Ok(())
+    Ok(id)
 }
 
 pub async fn update_site(
@@ -228,7 +256,12 @@ pub async fn update_site(
             .execute(&mut ***db)
             .await?;
 
-        query(r#"UPDATE category_site SET category_id = ? WHERE site_id = ?"#)
+        query(r#"DELETE FROM category_site WHERE site_id = ?"#)
+            .bind(record.id)
+            .execute(&mut ***db)
+            .await?;
+
+        query(r#"INSERT INTO category_site (category_id, site_id) VALUES (?, ?)"#)
             .bind(category)
             .bind(record.id)
             .execute(&mut ***db)
@@ -252,16 +285,55 @@ pub async fn delete_site(id: &str, db: &mut Connection<MySQLDb>) -> Result<(), S
     Ok(())
 }
 
-pub async fn analytics(id: &str, db: &mut Connection<MySQLDb>) -> Result<(), ServiceError> {
-    query(r#"UPDATE site SET visit_count = visit_count + 1 WHERE id = ?"#)
-        .bind(id)
-        .execute(&mut ***db)
-        .await?;
+pub async fn analytics(
+    id: i64,
+    public_only: bool,
+    db: &mut Connection<MySQLDb>,
+) -> Result<(), ServiceError> {
+    let result = query(
+        format!(
+            "{}{}",
+            PUBLIC_CATEGORIES,
+            r#"UPDATE site SET visit_count = visit_count + 1 WHERE id = ? AND (? OR id IN (SELECT site_id FROM category_site WHERE category_id IN (SELECT id FROM public_category)))"#
+        )
+        .as_str(),
+    )
+    .bind(id)
+    .bind(!public_only)
+    .execute(&mut ***db)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ServiceError::NotFound);
+    }
 
     Ok(())
 }
 
-pub async fn get_site(id: i64, db: &mut Connection<MySQLDb>) -> Result<SiteResponse, ServiceError> {
+pub async fn get_site(
+    id: i64,
+    public_only: bool,
+    db: &mut Connection<MySQLDb>,
+) -> Result<SiteResponse, ServiceError> {
+    if public_only {
+        let count = query(
+            format!(
+                "{}{}",
+                PUBLIC_CATEGORIES,
+                r#"SELECT COUNT(site_id) AS count FROM category_site WHERE site_id = ? AND category_id IN (SELECT id FROM public_category)"#
+            )
+            .as_str(),
+        )
+        .bind(id)
+        .fetch_one(&mut ***db)
+        .await?
+        .get::<i64, &str>("count");
+
+        if count == 0 {
+            return Err(ServiceError::NotFound);
+        }
+    }
+
     let record = query_as::<_, Site>(
         r#"SELECT id, name, url, description, icon, sort_order, visit_count, created_at, updated_at FROM site WHERE id = ?"#,
     )
@@ -0,0 +1,334 @@
+use log::error;
+use rand::distributions::{Distribution, Uniform};
+use rocket::futures::TryFutureExt;
+use rocket_db_pools::deadpool_redis::redis::{self, AsyncCommands};
+use rocket_db_pools::Connection;
+use sha2::{Digest, Sha256};
+use sqlx::{query, Acquire, Row};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::config::Config;
+use crate::errors::ServiceError;
+use crate::password::{hash_password, verify_password};
+use crate::request::user::DisableTotp;
+use crate::response;
+use crate::{MySQLDb, RedisDb};
+
+const ENROLLMENT_TTL: usize = 10 * 60 * 1000;
+
+/*
+ * A code stays claimed for the whole window `check_current` accepts it in,
+ * so it cannot be replayed by someone watching over the user's shoulder.
+ */
+const USED_CODE_TTL: usize = 90 * 1000;
+
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+fn enrollment_key(username: &str) -> String {
+    format!("totp_enrollment:{}", username)
+}
+
+fn used_code_key(username: &str, code: &str) -> String {
+    format!("totp_used:{}:{}", username, code)
+}
+
+fn build_totp(secret: &str, username: &str, config: &Config) -> Result<TOTP, ServiceError> {
+    let secret = Secret::Encoded(String::from(secret))
+        .to_bytes()
+        .map_err(|e| {
+            error!("Failed to decode TOTP secret: {:?}", e);
+
+            ServiceError::InternalServerError
+        })?;
+
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        30,
+        secret,
+        Some(config.totp.issuer.clone()),
+        String::from(username),
+    )
+    .map_err(|e| {
+        error!("Failed to create TOTP: {}", e);
+
+        ServiceError::InternalServerError
+    })
+}
+
+fn generate_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+
+    let range = Uniform::from(0..RECOVERY_CODE_ALPHABET.len());
+
+    let code = (0..10)
+        .map(|_| RECOVERY_CODE_ALPHABET[range.sample(&mut rng)] as char)
+        .collect::<String>();
+
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn hash_recovery_code(code: &str) -> Result<String, ServiceError> {
+    hash_password(&normalize_recovery_code(code))
+}
+
+fn matches_recovery_code(code: &str, hashed: &str) -> Result<bool, ServiceError> {
+    let normalized = normalize_recovery_code(code);
+
+    match hashed.starts_with("$argon2") {
+        true => verify_password(&normalized, hashed),
+        // Codes handed out before they were hashed with Argon2id.
+        false => Ok(format!("{:x}", Sha256::digest(normalized.as_bytes())) == hashed),
+    }
+}
+
+pub async fn get_secret(
+    username: &str,
+    db: &mut Connection<MySQLDb>,
+) -> Result<Option<String>, ServiceError> {
+    let secret = query(r#"SELECT totp_secret FROM user WHERE username = ?"#)
+        .bind(username)
+        .fetch_one(&mut ***db)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => ServiceError::NotFound,
+            _ => ServiceError::DatabaseError(e),
+        })?
+        .try_get::<Option<String>, &str>("totp_secret")?;
+
+    Ok(secret)
+}
+
+pub async fn enroll(
+    username: &str,
+    config: &Config,
+    db: &mut Connection<MySQLDb>,
+    cache: &mut Connection<RedisDb>,
+) -> Result<response::user::TotpEnrollment, ServiceError> {
+    if get_secret(username, db).await?.is_some() {
+        return Err(ServiceError::BadRequest(String::from(
+            "Two-factor authentication is already enabled",
+        )));
+    }
+
+    let secret = match Secret::generate_secret().to_encoded() {
+        Secret::Encoded(secret) => secret,
+        Secret::Raw(_) => return Err(ServiceError::InternalServerError),
+    };
+
+    let totp = build_totp(&secret, username, config)?;
+
+    cache
+        .pset_ex::<_, _, ()>(enrollment_key(username), &secret, ENROLLMENT_TTL)
+        .map_err(|e| {
+            error!("Failed to set TOTP enrollment: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    Ok(response::user::TotpEnrollment {
+        secret,
+        otpauth_url: totp.get_url(),
+    })
+}
+
+pub async fn confirm(
+    username: &str,
+    code: &str,
+    config: &Config,
+    db: &mut Connection<MySQLDb>,
+    cache: &mut Connection<RedisDb>,
+) -> Result<response::user::RecoveryCodes, ServiceError> {
+    let secret = cache
+        .get::<_, Option<String>>(enrollment_key(username))
+        .map_err(|e| {
+            error!("Failed to get TOTP enrollment: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?
+        .ok_or(ServiceError::BadRequest(String::from(
+            "No pending two-factor enrollment",
+        )))?;
+
+    let totp = build_totp(&secret, username, config)?;
+
+    if !totp.check_current(code).unwrap_or(false) {
+        return Err(ServiceError::BadRequest(String::from(
+            "Invalid verification code",
+        )));
+    }
+
+    let recovery_codes = (0..config.totp.recovery_codes)
+        .map(|_| generate_recovery_code())
+        .collect::<Vec<String>>();
+
+    let mut tx = (&mut ***db).begin().await?;
+
+    query(r#"UPDATE user SET totp_secret = ? WHERE username = ?"#)
+        .bind(&secret)
+        .bind(username)
+        .execute(&mut *tx)
+        .await?;
+
+    query(r#"DELETE FROM recovery_code WHERE username = ?"#)
+        .bind(username)
+        .execute(&mut *tx)
+        .await?;
+
+    for recovery_code in &recovery_codes {
+        query(r#"INSERT INTO recovery_code (username, code) VALUES (?, ?)"#)
+            .bind(username)
+            .bind(hash_recovery_code(recovery_code)?)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    cache
+        .del::<_, ()>(enrollment_key(username))
+        .map_err(|e| {
+            error!("Failed to delete TOTP enrollment: {}", e);
+
+            ServiceError::InternalServerError
+        })
+        .await?;
+
+    Ok(response::user::RecoveryCodes { recovery_codes })
+}
+
+pub async fn disable(
+    username: &str,
+    data: &DisableTotp<'_>,
+    config: &Config,
+    db: &mut Connection<MySQLDb>,
+    cache: &mut Connection<RedisDb>,
+) -> Result<(), ServiceError> {
+    let secret = get_secret(username, db)
+        .await?
+        .ok_or(ServiceError::BadRequest(String::from(
+            "Two-factor authentication is not enabled",
+        )))?;
+
+    let hashed = query(r#"SELECT password FROM user WHERE username = ?"#)
+        .bind(username)
+        .fetch_one(&mut ***db)
+        .await?
+        .try_get::<String, &str>("password")?;
+
+    if !verify_password(data.password, &hashed)? {
+        return Err(ServiceError::Unauthorized);
+    }
+
+    if !verify_code(username, &secret, data.code, config, db, cache).await? {
+        return Err(ServiceError::Unauthorized);
+    }
+
+    let mut tx = (&mut ***db).begin().await?;
+
+    query(r#"UPDATE user SET totp_secret = NULL WHERE username = ?"#)
+        .bind(username)
+        .execute(&mut *tx)
+        .await?;
+
+    query(r#"DELETE FROM recovery_code WHERE username = ?"#)
+        .bind(username)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/*
+ * Accepts either a current TOTP code or one of the unused recovery codes,
+ * which is burned on success.
+ */
+pub async fn verify_code(
+    username: &str,
+    secret: &str,
+    code: &str,
+    config: &Config,
+    db: &mut Connection<MySQLDb>,
+    cache: &mut Connection<RedisDb>,
+) -> Result<bool, ServiceError> {
+    let totp = build_totp(secret, username, config)?;
+
+    if totp.check_current(code).unwrap_or(false) {
+        let claimed = redis::cmd("SET")
+            .arg(used_code_key(username, code))
+            .arg(1)
+            .arg("NX")
+            .arg("PX")
+            .arg(USED_CODE_TTL)
+            .query_async::<_, Option<String>>(&mut **cache)
+            .map_err(|e| {
+                error!("Failed to claim TOTP code: {}", e);
+
+                ServiceError::InternalServerError
+            })
+            .await?;
+
+        return Ok(claimed.is_some());
+    }
+
+    if normalize_recovery_code(code).len() != 10 {
+        return Ok(false);
+    }
+
+    let codes =
+        query(r#"SELECT id, code FROM recovery_code WHERE username = ? AND used_at IS NULL"#)
+            .bind(username)
+            .fetch_all(&mut ***db)
+            .await?;
+
+    for row in codes {
+        if !matches_recovery_code(code, &row.try_get::<String, &str>("code")?)? {
+            continue;
+        }
+
+        let result =
+            query(r#"UPDATE recovery_code SET used_at = NOW() WHERE id = ? AND used_at IS NULL"#)
+                .bind(row.try_get::<i32, &str>("id")?)
+                .execute(&mut ***db)
+                .await?;
+
+        return Ok(result.rows_affected() > 0);
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hash_recovery_code() {
+        let code = generate_recovery_code();
+
+        let hashed = hash_recovery_code(&code).unwrap();
+
+        assert_eq!(code.len(), 11);
+        assert!(hashed.starts_with("$argon2id$"));
+        assert_ne!(hashed, hash_recovery_code(&code).unwrap());
+        assert!(matches_recovery_code(&code.to_uppercase().replace('-', ""), &hashed).unwrap());
+        assert!(!matches_recovery_code(&generate_recovery_code(), &hashed).unwrap());
+        assert!(matches_recovery_code(
+            "ABCDE-FGHJK",
+            &format!("{:x}", Sha256::digest(b"abcdefghjk"))
+        )
+        .unwrap());
+    }
+}
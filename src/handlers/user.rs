@@ -1,13 +1,32 @@
-use bcrypt::{hash, verify, DEFAULT_COST};
-use log::error;
 use rocket_db_pools::Connection;
-use sqlx::{query, query_as};
+use sqlx::{query, query_as, Acquire, MySqlConnection, Row};
 
 use crate::errors::ServiceError;
-use crate::request::user::{UpdatePassword, UpdateUser};
+use crate::models::user::{Role, Source};
+use crate::password::{hash_password, verify_password, PasswordPolicy};
+use crate::request::user::{CreateUser, UpdateAccount, UpdatePassword, UpdateUser};
 use crate::response;
+use crate::response::WithTotal;
 use crate::{models, MySQLDb};
 
+fn to_response(user: models::user::User, upload_url: &str) -> response::user::User {
+    let avatar = if user.avatar.starts_with("http") || user.avatar.starts_with("https") {
+        user.avatar
+    } else {
+        format!("{}/{}", upload_url, user.avatar)
+    };
+
+    response::user::User {
+        username: user.username,
+        nickname: user.nickname,
+        avatar,
+        email: user.email,
+        email_verified: user.email_verified,
+        role: user.role,
+        disabled: user.disabled,
+    }
+}
+
 pub async fn get_user(
     username: &str,
     upload_url: &str,
@@ -22,27 +41,223 @@ pub async fn get_user(
             _ => ServiceError::InternalServerError,
         })?;
 
-    let avatar = if user.avatar.starts_with("http") || user.avatar.starts_with("https") {
-        user.avatar
-    } else {
-        format!("{}/{}", upload_url, user.avatar)
+    Ok(to_response(user, upload_url))
+}
+
+pub async fn get_role(username: &str, db: &mut Connection<MySQLDb>) -> Result<Role, ServiceError> {
+    find_role(username, db).await
+}
+
+async fn find_role(username: &str, conn: &mut MySqlConnection) -> Result<Role, ServiceError> {
+    let role = query(r#"SELECT role FROM user WHERE username = ?"#)
+        .bind(username)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => ServiceError::NotFound,
+            _ => ServiceError::DatabaseError(e),
+        })?
+        .try_get::<Role, &str>("role")?;
+
+    Ok(role)
+}
+
+pub async fn get_users(
+    page: i64,
+    size: i64,
+    search: Option<&str>,
+    upload_url: &str,
+    db: &mut Connection<MySQLDb>,
+) -> Result<WithTotal<response::user::User>, ServiceError> {
+    let total = match search {
+        Some(search) => query(
+            r#"SELECT COUNT(username) AS count FROM user WHERE username LIKE ? OR nickname LIKE ? OR email LIKE ?"#,
+        )
+        .bind(format!("%{}%", search))
+        .bind(format!("%{}%", search))
+        .bind(format!("%{}%", search))
+        .fetch_one(&mut ***db)
+        .await?
+        .get::<i64, &str>("count"),
+        None => query(r#"SELECT COUNT(username) AS count FROM user"#)
+            .fetch_one(&mut ***db)
+            .await?
+            .get::<i64, &str>("count"),
     };
 
-    Ok(response::user::User {
-        username: user.username,
-        nickname: user.nickname,
-        avatar,
-        email: user.email,
+    let users = match search {
+        Some(search) => query_as::<_, models::user::User>(
+            r#"SELECT username, nickname, password, email, email_verified, IFNULL(avatar, '') AS avatar, role, disabled FROM user WHERE username LIKE ? OR nickname LIKE ? OR email LIKE ? ORDER BY username LIMIT ? OFFSET ?"#,
+        )
+        .bind(format!("%{}%", search))
+        .bind(format!("%{}%", search))
+        .bind(format!("%{}%", search))
+        .bind(size)
+        .bind(page * size)
+        .fetch_all(&mut ***db)
+        .await?,
+        None => query_as::<_, models::user::User>(
+            r#"SELECT username, nickname, password, email, email_verified, IFNULL(avatar, '') AS avatar, role, disabled FROM user ORDER BY username LIMIT ? OFFSET ?"#,
+        )
+        .bind(size)
+        .bind(page * size)
+        .fetch_all(&mut ***db)
+        .await?,
+    };
+
+    Ok(WithTotal {
+        total,
+        data: users
+            .into_iter()
+            .map(|user| to_response(user, upload_url))
+            .collect(),
     })
 }
 
+pub async fn create_user(
+    user: &CreateUser<'_>,
+    policy: &PasswordPolicy,
+    db: &mut Connection<MySQLDb>,
+) -> Result<(), ServiceError> {
+    insert_user(user, Source::Local, policy, db).await
+}
+
+pub(crate) const NAME_MAX_LENGTH: usize = 20;
+
+pub(crate) fn valid_username(username: &str) -> bool {
+    !username.is_empty() && username.chars().count() <= NAME_MAX_LENGTH && !username.contains(':')
+}
+
+pub(crate) async fn insert_user(
+    user: &CreateUser<'_>,
+    source: Source,
+    policy: &PasswordPolicy,
+    db: &mut Connection<MySQLDb>,
+) -> Result<(), ServiceError> {
+    if !valid_username(user.username) {
+        return Err(ServiceError::BadRequest(String::from("Invalid username")));
+    }
+
+    policy.check(user.username, user.password)?;
+
+    let exists = query(r#"SELECT COUNT(username) AS count FROM user WHERE username = ?"#)
+        .bind(user.username)
+        .fetch_one(&mut ***db)
+        .await?
+        .get::<i64, &str>("count");
+
+    if exists > 0 {
+        return Err(ServiceError::AlreadyExists(String::from(
+            "User already exists",
+        )));
+    }
+
+    let hashed_password = hash_password(user.password)?;
+
+    query(
+        r#"INSERT INTO user (username, nickname, password, email, avatar, role, source) VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+    )
+    .bind(user.username)
+    .bind(user.nickname)
+    .bind(&hashed_password)
+    .bind(user.email)
+    .bind(user.avatar.unwrap_or_default())
+    .bind(user.role)
+    .bind(source)
+    .execute(&mut ***db)
+    .await?;
+
+    Ok(())
+}
+
+/*
+ * The admin rows stay locked until the caller's transaction ends, so two
+ * admins cannot demote each other at the same time.
+ */
+pub(crate) async fn ensure_other_admin(
+    username: &str,
+    conn: &mut MySqlConnection,
+) -> Result<(), ServiceError> {
+    let admins =
+        query(r#"SELECT username FROM user WHERE role = 'admin' AND disabled = FALSE FOR UPDATE"#)
+            .fetch_all(&mut *conn)
+            .await?;
+
+    let others = admins
+        .iter()
+        .filter(|admin| admin.get::<String, &str>("username") != username)
+        .count();
+
+    if others == 0 {
+        return Err(ServiceError::BadRequest(String::from(
+            "At least one enabled admin is required",
+        )));
+    }
+
+    Ok(())
+}
+
+pub async fn update_account(
+    username: &str,
+    account: &UpdateAccount,
+    db: &mut Connection<MySQLDb>,
+) -> Result<(), ServiceError> {
+    let mut tx = (&mut ***db).begin().await?;
+
+    let role = find_role(username, &mut tx).await?;
+
+    let demoted = role == Role::Admin && account.role.is_some_and(|role| role != Role::Admin);
+
+    if demoted || account.disabled == Some(true) {
+        ensure_other_admin(username, &mut tx).await?;
+    }
+
+    if let Some(role) = account.role {
+        query(r#"UPDATE user SET role = ? WHERE username = ?"#)
+            .bind(role)
+            .bind(username)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    if let Some(disabled) = account.disabled {
+        query(r#"UPDATE user SET disabled = ? WHERE username = ?"#)
+            .bind(disabled)
+            .bind(username)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn delete_user(username: &str, db: &mut Connection<MySQLDb>) -> Result<(), ServiceError> {
+    let mut tx = (&mut ***db).begin().await?;
+
+    find_role(username, &mut tx).await?;
+
+    ensure_other_admin(username, &mut tx).await?;
+
+    // Access tokens, recovery codes and linked identities go with it.
+    query(r#"DELETE FROM user WHERE username = ?"#)
+        .bind(username)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
 pub async fn update_user(
     name: &'_ str,
     user: &UpdateUser<'_>,
     db: &mut Connection<MySQLDb>,
 ) -> Result<(), ServiceError> {
     let record = query_as::<_, models::user::User>(
-        "SELECT username, password, email, avatar, nickname FROM user WHERE username = ?",
+        "SELECT username, password, email, email_verified, avatar, nickname, role, disabled FROM user WHERE username = ?",
     )
     .bind(name)
     .fetch_one(&mut ***db)
@@ -53,11 +268,32 @@ pub async fn update_user(
         None => record.username,
     };
 
+    if username != name {
+        if !valid_username(&username) {
+            return Err(ServiceError::BadRequest(String::from("Invalid username")));
+        }
+
+        let exists = query(r#"SELECT COUNT(username) AS count FROM user WHERE username = ?"#)
+            .bind(&username)
+            .fetch_one(&mut ***db)
+            .await?
+            .get::<i64, &str>("count");
+
+        if exists > 0 {
+            return Err(ServiceError::AlreadyExists(String::from(
+                "User already exists",
+            )));
+        }
+    }
+
     let email = match user.email {
         Some(email) => String::from(email),
-        None => record.email,
+        None => record.email.clone(),
     };
 
+    // A new address has to be verified again.
+    let email_verified = record.email_verified && email == record.email;
+
     let avatar = match user.avatar {
         Some(avatar) => String::from(avatar),
         None => record.avatar,
@@ -68,9 +304,11 @@ pub async fn update_user(
         None => record.nickname,
     };
 
-    query("UPDATE user SET username = ?, email = ?, avatar = ?, nickname = ? WHERE username = ?")
+    // Access tokens, recovery codes and linked identities follow a rename.
+    query("UPDATE user SET username = ?, email = ?, email_verified = ?, avatar = ?, nickname = ? WHERE username = ?")
         .bind(&username)
         .bind(&email)
+        .bind(email_verified)
         .bind(&avatar)
         .bind(&nickname)
         .bind(name)
@@ -83,26 +321,25 @@ pub async fn update_user(
 pub async fn update_user_password(
     name: &'_ str,
     user: &UpdatePassword<'_>,
+    policy: &PasswordPolicy,
     db: &mut Connection<MySQLDb>,
 ) -> Result<(), ServiceError> {
     let record = query_as::<_, models::user::User>(
-        "SELECT username, password, email, avatar, nickname FROM user WHERE username = ?",
+        "SELECT username, password, email, avatar, nickname, role, disabled FROM user WHERE username = ?",
     )
     .bind(name)
     .fetch_one(&mut ***db)
     .await?;
 
-    let valid = verify(user.password, &record.password).map_err(|e| {
-        error!("{}", e);
-
-        ServiceError::BadRequest(String::from("Invalid password"))
-    })?;
+    let valid = verify_password(user.password, &record.password)?;
 
     if !valid {
         return Err(ServiceError::Unauthorized);
     }
 
-    let hashed_password = hash(user.new_password, DEFAULT_COST).unwrap();
+    policy.check(name, user.new_password)?;
+
+    let hashed_password = hash_password(user.new_password)?;
 
     query("UPDATE user SET password = ? WHERE username = ?")
         .bind(&hashed_password)
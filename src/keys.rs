@@ -0,0 +1,357 @@
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+    EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType,
+    PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use log::warn;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::traits::PublicKeyParts;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::config::{Jwt, JwtKey};
+use crate::errors::ServiceError;
+
+struct VerifyingKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+pub struct JwtKeys {
+    header: Header,
+    signing: EncodingKey,
+    verifying: Vec<VerifyingKey>,
+    jwks: JwkSet,
+}
+
+fn key_error(kid: &str, message: impl Display) -> ServiceError {
+    ServiceError::FormatError(format!("JWT key {}: {}", kid, message))
+}
+
+fn read_pem(kid: &str, path: &Path) -> Result<String, ServiceError> {
+    fs::read_to_string(path).map_err(|e| key_error(kid, format!("{}: {}", path.display(), e)))
+}
+
+fn encode_component(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn public_jwk(key: &JwtKey, pem: &str, private: bool) -> Result<Jwk, ServiceError> {
+    let (key_algorithm, algorithm) =
+        match key.algorithm {
+            Algorithm::RS256 => {
+                let public = match private {
+                    true => RsaPrivateKey::from_pkcs8_pem(pem)
+                        .map(|private| private.to_public_key())
+                        .map_err(|e| key_error(&key.kid, e))?,
+                    false => RsaPublicKey::from_public_key_pem(pem)
+                        .map_err(|e| key_error(&key.kid, e))?,
+                };
+
+                (
+                    KeyAlgorithm::RS256,
+                    AlgorithmParameters::RSA(RSAKeyParameters {
+                        key_type: RSAKeyType::RSA,
+                        n: encode_component(&public.n().to_bytes_be()),
+                        e: encode_component(&public.e().to_bytes_be()),
+                    }),
+                )
+            }
+            Algorithm::ES256 => {
+                let public = match private {
+                    true => p256::SecretKey::from_pkcs8_pem(pem)
+                        .map(|private| private.public_key())
+                        .map_err(|e| key_error(&key.kid, e))?,
+                    false => p256::PublicKey::from_public_key_pem(pem)
+                        .map_err(|e| key_error(&key.kid, e))?,
+                };
+
+                let point = public.to_encoded_point(false);
+
+                let (x, y) = match (point.x(), point.y()) {
+                    (Some(x), Some(y)) => (x, y),
+                    _ => return Err(key_error(&key.kid, "invalid EC point")),
+                };
+
+                (
+                    KeyAlgorithm::ES256,
+                    AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                        key_type: EllipticCurveKeyType::EC,
+                        curve: EllipticCurve::P256,
+                        x: encode_component(x),
+                        y: encode_component(y),
+                    }),
+                )
+            }
+            Algorithm::EdDSA => {
+                let public = match private {
+                    true => ed25519_dalek::SigningKey::from_pkcs8_pem(pem)
+                        .map(|private| private.verifying_key())
+                        .map_err(|e| key_error(&key.kid, e))?,
+                    false => ed25519_dalek::VerifyingKey::from_public_key_pem(pem)
+                        .map_err(|e| key_error(&key.kid, e))?,
+                };
+
+                (
+                    KeyAlgorithm::EdDSA,
+                    AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                        key_type: OctetKeyPairType::OctetKeyPair,
+                        curve: EllipticCurve::Ed25519,
+                        x: encode_component(public.as_bytes()),
+                    }),
+                )
+            }
+            algorithm => {
+                return Err(key_error(
+                    &key.kid,
+                    format!("unsupported algorithm {:?}", algorithm),
+                ))
+            }
+        };
+
+    Ok(Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(key_algorithm),
+            key_id: Some(key.kid.clone()),
+            ..Default::default()
+        },
+        algorithm,
+    })
+}
+
+fn encoding_key(key: &JwtKey, pem: &str) -> Result<EncodingKey, ServiceError> {
+    let encoding = match key.algorithm {
+        Algorithm::RS256 => EncodingKey::from_rsa_pem(pem.as_bytes()),
+        Algorithm::ES256 => EncodingKey::from_ec_pem(pem.as_bytes()),
+        Algorithm::EdDSA => EncodingKey::from_ed_pem(pem.as_bytes()),
+        algorithm => {
+            return Err(key_error(
+                &key.kid,
+                format!("unsupported algorithm {:?}", algorithm),
+            ))
+        }
+    };
+
+    encoding.map_err(|e| key_error(&key.kid, e))
+}
+
+impl JwtKeys {
+    pub fn load(config: &Jwt) -> Result<Self, ServiceError> {
+        if config.keys.is_empty() {
+            if config.secret.is_empty() || config.secret == Jwt::default().secret {
+                warn!(
+                    "Signing tokens with a guessable JWT secret, configure jwt.secret or jwt.keys"
+                );
+            }
+
+            return Ok(Self {
+                header: Header::new(Algorithm::HS256),
+                signing: EncodingKey::from_secret(config.secret.as_bytes()),
+                verifying: vec![VerifyingKey {
+                    kid: None,
+                    algorithm: Algorithm::HS256,
+                    key: DecodingKey::from_secret(config.secret.as_bytes()),
+                }],
+                jwks: JwkSet { keys: Vec::new() },
+            });
+        }
+
+        let mut signing = None;
+
+        let mut verifying = Vec::new();
+
+        let mut jwks = Vec::new();
+
+        let mut kids = HashSet::new();
+
+        for key in &config.keys {
+            if !kids.insert(&key.kid) {
+                return Err(key_error(&key.kid, "duplicate kid"));
+            }
+
+            let jwk = match (&key.private_key, &key.public_key) {
+                (Some(path), _) => {
+                    let pem = read_pem(&key.kid, path)?;
+
+                    if signing.is_none() {
+                        let mut header = Header::new(key.algorithm);
+
+                        header.kid = Some(key.kid.clone());
+
+                        signing = Some((header, encoding_key(key, &pem)?));
+                    }
+
+                    public_jwk(key, &pem, true)?
+                }
+                (None, Some(path)) => public_jwk(key, &read_pem(&key.kid, path)?, false)?,
+                (None, None) => {
+                    return Err(key_error(&key.kid, "needs a private_key or a public_key"))
+                }
+            };
+
+            verifying.push(VerifyingKey {
+                kid: Some(key.kid.clone()),
+                algorithm: key.algorithm,
+                key: DecodingKey::from_jwk(&jwk).map_err(|e| key_error(&key.kid, e))?,
+            });
+
+            jwks.push(jwk);
+        }
+
+        let (header, signing) = signing.ok_or_else(|| {
+            ServiceError::FormatError(String::from("No JWT key has a private_key to sign with"))
+        })?;
+
+        Ok(Self {
+            header,
+            signing,
+            verifying,
+            jwks: JwkSet { keys: jwks },
+        })
+    }
+
+    pub(crate) fn encode<T: Serialize>(
+        &self,
+        claims: &T,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
+        encode(&self.header, claims, &self.signing)
+    }
+
+    pub(crate) fn decode<T: DeserializeOwned>(&self, token: &str) -> Option<T> {
+        let header = decode_header(token).ok()?;
+
+        let key = self
+            .verifying
+            .iter()
+            .find(|key| key.kid == header.kid && key.algorithm == header.alg)?;
+
+        decode::<T>(token, &key.key, &Validation::new(key.algorithm))
+            .ok()
+            .map(|data| data.claims)
+    }
+
+    pub fn jwks(&self) -> &JwkSet {
+        &self.jwks
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::path::PathBuf;
+
+    use chrono::{Duration, Utc};
+    use p256::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+    use rand::RngCore;
+
+    use crate::Claims;
+
+    use super::*;
+
+    fn write_pem(name: &str, pem: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("startpage-{}-{}.pem", name, std::process::id()));
+
+        fs::write(&path, pem).unwrap();
+
+        path
+    }
+
+    fn claims() -> Claims {
+        Claims {
+            sub: String::from("alice:session"),
+            company: String::from("StartPage"),
+            exp: (Utc::now() + Duration::minutes(5)).timestamp() as usize,
+        }
+    }
+
+    #[test]
+    fn test_rotate_keys() {
+        let current = p256::SecretKey::random(&mut rand::thread_rng());
+
+        let mut seed = [0u8; 32];
+
+        rand::thread_rng().fill_bytes(&mut seed);
+
+        let retired = ed25519_dalek::SigningKey::from_bytes(&seed);
+
+        let current_path = write_pem("current", &current.to_pkcs8_pem(LineEnding::LF).unwrap());
+
+        let retired_path = write_pem("retired", &retired.to_pkcs8_pem(LineEnding::LF).unwrap());
+
+        let retired_public_path = write_pem(
+            "retired-public",
+            &retired
+                .verifying_key()
+                .to_public_key_pem(LineEnding::LF)
+                .unwrap(),
+        );
+
+        let before = JwtKeys::load(&Jwt {
+            keys: vec![JwtKey {
+                kid: String::from("retired"),
+                algorithm: Algorithm::EdDSA,
+                private_key: Some(retired_path),
+                public_key: None,
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let after = JwtKeys::load(&Jwt {
+            keys: vec![
+                JwtKey {
+                    kid: String::from("current"),
+                    algorithm: Algorithm::ES256,
+                    private_key: Some(current_path),
+                    public_key: None,
+                },
+                JwtKey {
+                    kid: String::from("retired"),
+                    algorithm: Algorithm::EdDSA,
+                    private_key: None,
+                    public_key: Some(retired_public_path),
+                },
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let old_token = before.encode(&claims()).unwrap();
+        let new_token = after.encode(&claims()).unwrap();
+
+        assert_eq!(
+            decode_header(&new_token).unwrap().kid.as_deref(),
+            Some("current")
+        );
+        assert!(after.decode::<Claims>(&old_token).is_some());
+        assert!(after.decode::<Claims>(&new_token).is_some());
+        assert!(before.decode::<Claims>(&new_token).is_none());
+        assert_eq!(after.jwks().keys.len(), 2);
+        assert!(after.jwks().find("retired").is_some());
+    }
+
+    #[test]
+    fn test_secret_fallback() {
+        let keys = JwtKeys::load(&Jwt::default()).unwrap();
+
+        let token = keys.encode(&claims()).unwrap();
+
+        assert!(keys.decode::<Claims>(&token).is_some());
+        assert!(keys.jwks().keys.is_empty());
+    }
+}
@@ -21,10 +21,14 @@ pub struct MySQLDb(MySqlPool);
 #[database("cache")]
 pub struct RedisDb(deadpool_redis::Pool);
 
+pub mod captcha;
 pub mod errors;
 pub mod guards;
 pub mod handlers;
+pub mod keys;
+pub mod mailer;
 pub mod models;
+pub mod password;
 pub mod routes;
 pub mod state;
 
@@ -0,0 +1,169 @@
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::error;
+
+use crate::config::{Smtp, SmtpTls};
+use crate::errors::ServiceError;
+
+#[derive(Clone)]
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl Mailer {
+    pub fn from_config(config: &Smtp) -> Result<Self, ServiceError> {
+        let builder = match config.tls {
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)
+                .map_err(|e| ServiceError::FormatError(format!("SMTP relay: {}", e)))?,
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)
+                .map_err(|e| ServiceError::FormatError(format!("SMTP relay: {}", e)))?,
+        };
+
+        let builder = builder.port(config.port);
+
+        let builder = match (&config.username, &config.password) {
+            (Some(username), Some(password)) => {
+                builder.credentials(Credentials::new(username.clone(), password.clone()))
+            }
+            _ => builder,
+        };
+
+        let from = config
+            .from
+            .parse::<Mailbox>()
+            .map_err(|e| ServiceError::FormatError(format!("SMTP from: {}", e)))?;
+
+        Ok(Self {
+            transport: builder.build(),
+            from,
+        })
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), ServiceError> {
+        let to = to
+            .parse::<Mailbox>()
+            .map_err(|_| ServiceError::BadRequest(String::from("Invalid email address")))?;
+
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|e| {
+                error!("Failed to build mail: {}", e);
+
+                ServiceError::InternalServerError
+            })?;
+
+        self.transport.send(message).await.map_err(|e| {
+            error!("Failed to send mail: {}", e);
+
+            ServiceError::InternalServerError
+        })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    use super::*;
+
+    fn smtp_sink() -> (u16, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let port = listener.local_addr().unwrap().port();
+
+        let (sender, receiver) = channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+
+            let mut data = String::new();
+
+            let mut in_data = false;
+
+            loop {
+                let mut line = String::new();
+
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+
+                        stream.write_all(b"250 OK\r\n").unwrap();
+                    } else {
+                        data.push_str(&line);
+                    }
+
+                    continue;
+                }
+
+                let command = line.to_uppercase();
+
+                if command.starts_with("DATA") {
+                    in_data = true;
+
+                    stream.write_all(b"354 Go ahead\r\n").unwrap();
+                } else if command.starts_with("QUIT") {
+                    stream.write_all(b"221 Bye\r\n").unwrap();
+
+                    break;
+                } else {
+                    stream.write_all(b"250 OK\r\n").unwrap();
+                }
+            }
+
+            sender.send(data).unwrap();
+        });
+
+        (port, receiver)
+    }
+
+    #[rocket::async_test]
+    async fn test_send() {
+        let (port, receiver) = smtp_sink();
+
+        let mailer = Mailer::from_config(&Smtp {
+            host: String::from("127.0.0.1"),
+            port,
+            tls: SmtpTls::None,
+            ..Default::default()
+        })
+        .unwrap();
+
+        mailer
+            .send(
+                "alice@example.com",
+                "Reset your password",
+                String::from("https://startpage.example.com/reset-password?token=abc"),
+            )
+            .await
+            .unwrap();
+
+        drop(mailer);
+
+        let message = receiver.recv().unwrap();
+
+        assert!(message.contains("To: alice@example.com"));
+        assert!(message.contains("Subject: Reset your password"));
+        assert!(message.contains("reset-password?token=abc"));
+    }
+}
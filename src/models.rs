@@ -1,3 +1,5 @@
+pub mod access_token;
+pub mod audit_log;
 pub mod category;
 pub(crate) mod category_site;
 pub mod site;
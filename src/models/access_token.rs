@@ -0,0 +1,105 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::models::user::Role;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "categories:read")]
+    CategoriesRead,
+    #[serde(rename = "categories:write")]
+    CategoriesWrite,
+    #[serde(rename = "sites:read")]
+    SitesRead,
+    #[serde(rename = "sites:write")]
+    SitesWrite,
+    #[serde(rename = "upload")]
+    Upload,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::CategoriesRead => "categories:read",
+            Scope::CategoriesWrite => "categories:write",
+            Scope::SitesRead => "sites:read",
+            Scope::SitesWrite => "sites:write",
+            Scope::Upload => "upload",
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        match self {
+            Scope::CategoriesRead | Scope::SitesRead => Role::Viewer,
+            Scope::CategoriesWrite | Scope::SitesWrite | Scope::Upload => Role::Editor,
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "categories:read" => Ok(Scope::CategoriesRead),
+            "categories:write" => Ok(Scope::CategoriesWrite),
+            "sites:read" => Ok(Scope::SitesRead),
+            "sites:write" => Ok(Scope::SitesWrite),
+            "upload" => Ok(Scope::Upload),
+            _ => Err(format!("Unknown scope: {}", s)),
+        }
+    }
+}
+
+pub fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    scopes
+        .split(',')
+        .filter_map(|scope| scope.trim().parse().ok())
+        .collect()
+}
+
+pub fn join_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(Scope::as_str)
+        .collect::<Vec<&str>>()
+        .join(",")
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct AccessToken {
+    pub id: i64,
+    pub username: String,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: String,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scopes() {
+        let scopes = parse_scopes("sites:read, sites:write,upload,unknown");
+
+        assert_eq!(
+            scopes,
+            vec![Scope::SitesRead, Scope::SitesWrite, Scope::Upload]
+        );
+        assert_eq!(join_scopes(&scopes), "sites:read,sites:write,upload");
+    }
+}
@@ -0,0 +1,51 @@
+use chrono::NaiveDateTime;
+use rocket::FromFormField;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{FromRow, Type};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type, FromFormField)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum Action {
+    #[field(value = "login")]
+    Login,
+    #[field(value = "login_failed")]
+    LoginFailed,
+    #[field(value = "logout")]
+    Logout,
+    #[field(value = "create")]
+    Create,
+    #[field(value = "update")]
+    Update,
+    #[field(value = "delete")]
+    Delete,
+    #[field(value = "sort")]
+    Sort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum Entity {
+    User,
+    Session,
+    AccessToken,
+    Category,
+    Site,
+    Upload,
+}
+
+#[derive(Debug, FromRow)]
+pub struct AuditLog {
+    pub id: i64,
+    pub username: Option<String>,
+    pub session: Option<String>,
+    pub action: Action,
+    pub entity: Entity,
+    pub entity_id: Option<String>,
+    pub remote_ip: Option<String>,
+    pub before_value: Option<Value>,
+    pub after_value: Option<Value>,
+    pub created_at: NaiveDateTime,
+}
@@ -10,6 +10,7 @@ pub struct Category {
     pub icon: String,
     pub sort_order: i64,
     pub parent_id: Option<i64>,
+    pub is_public: bool,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
 }
@@ -1,5 +1,28 @@
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{FromRow, Type};
+
+/*
+ * Roles are ordered by privilege, so that `role >= Role::Editor` reads as
+ * "at least an editor".
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Editor,
+    Admin,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Source {
+    #[default]
+    Local,
+    Ldap,
+    Oidc,
+}
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct User {
@@ -7,5 +30,11 @@ pub struct User {
     pub nickname: String,
     pub password: String,
     pub email: String,
+    #[sqlx(default)]
+    pub email_verified: bool,
     pub avatar: String,
+    pub role: Role,
+    pub disabled: bool,
+    #[sqlx(default)]
+    pub source: Source,
 }
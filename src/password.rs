@@ -0,0 +1,151 @@
+use std::collections::HashSet;
+use std::fs;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use log::error;
+
+use crate::config::Password;
+use crate::errors::ServiceError;
+
+const ARGON2ID_PREFIX: &str = "$argon2id$";
+
+pub fn hash_password(password: &str) -> Result<String, ServiceError> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    let hashed = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| {
+            error!("Failed to hash password: {}", e);
+
+            ServiceError::InternalServerError
+        })?;
+
+    Ok(hashed.to_string())
+}
+
+/*
+ * Verifies a password against an Argon2 hash, or a bcrypt hash from before
+ * the switch to Argon2id.
+ */
+pub fn verify_password(password: &str, hashed: &str) -> Result<bool, ServiceError> {
+    if !hashed.starts_with("$argon2") {
+        return bcrypt::verify(password, hashed).map_err(|e| {
+            error!("Failed to verify password: {}", e);
+
+            ServiceError::BadRequest(String::from("Invalid password"))
+        });
+    }
+
+    let parsed = PasswordHash::new(hashed).map_err(|e| {
+        error!("Failed to parse password hash: {}", e);
+
+        ServiceError::InternalServerError
+    })?;
+
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_ok())
+}
+
+pub fn needs_rehash(hashed: &str) -> bool {
+    !hashed.starts_with(ARGON2ID_PREFIX)
+}
+
+pub struct PasswordPolicy {
+    min_length: usize,
+    max_length: usize,
+    breached: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    pub fn from_config(config: &Password) -> Result<Self, ServiceError> {
+        let breached = match &config.breached_list {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|e| {
+                    ServiceError::FormatError(format!("Failed to read {}: {}", path.display(), e))
+                })?
+                .lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect(),
+            None => HashSet::new(),
+        };
+
+        Ok(Self {
+            min_length: config.min_length,
+            max_length: config.max_length,
+            breached,
+        })
+    }
+
+    pub fn check(&self, username: &str, password: &str) -> Result<(), ServiceError> {
+        let length = password.chars().count();
+
+        if length < self.min_length {
+            return Err(ServiceError::BadRequest(format!(
+                "Password must be at least {} characters long",
+                self.min_length
+            )));
+        }
+
+        if length > self.max_length {
+            return Err(ServiceError::BadRequest(format!(
+                "Password must be at most {} characters long",
+                self.max_length
+            )));
+        }
+
+        if password.eq_ignore_ascii_case(username) {
+            return Err(ServiceError::BadRequest(String::from(
+                "Password must not be the username",
+            )));
+        }
+
+        if self.breached.contains(password) {
+            return Err(ServiceError::BadRequest(String::from(
+                "Password has appeared in a data breach",
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_verify_password() {
+        let hashed = hash_password("correct horse").unwrap();
+
+        assert!(!needs_rehash(&hashed));
+        assert!(verify_password("correct horse", &hashed).unwrap());
+        assert!(!verify_password("battery staple", &hashed).unwrap());
+
+        let legacy = bcrypt::hash("correct horse", 4).unwrap();
+
+        assert!(needs_rehash(&legacy));
+        assert!(verify_password("correct horse", &legacy).unwrap());
+    }
+
+    #[test]
+    fn test_password_policy() {
+        let policy = PasswordPolicy {
+            min_length: 8,
+            max_length: 16,
+            breached: HashSet::from([String::from("password123")]),
+        };
+
+        assert!(policy.check("alice", "short").is_err());
+        assert!(policy
+            .check("alice", "much too long to be accepted")
+            .is_err());
+        assert!(policy.check("alice12345", "Alice12345").is_err());
+        assert!(policy.check("alice", "password123").is_err());
+        assert!(policy.check("alice", "correct horse").is_ok());
+    }
+}
@@ -1,3 +1,5 @@
+pub mod access_token;
+pub mod audit;
 pub mod auth;
 pub mod category;
 pub mod site;
@@ -0,0 +1,10 @@
+use serde::Deserialize;
+
+use crate::models::access_token::Scope;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateAccessToken<'r> {
+    pub name: &'r str,
+    pub scopes: Vec<Scope>,
+    pub expires_in: Option<&'r str>,
+}
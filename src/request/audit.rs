@@ -0,0 +1,13 @@
+use rocket::FromForm;
+
+use crate::models::audit_log::Action;
+
+#[derive(Debug, FromForm)]
+pub struct AuditFilter<'r> {
+    pub page: Option<i64>,
+    pub size: Option<i64>,
+    pub user: Option<&'r str>,
+    pub action: Option<Action>,
+    pub from: Option<&'r str>,
+    pub to: Option<&'r str>,
+}
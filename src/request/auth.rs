@@ -6,3 +6,37 @@ pub struct User<'r> {
     pub password: &'r str,
     pub token: Option<&'r str>,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct Refresh<'r> {
+    pub refresh_token: Option<&'r str>,
+}
+
+#[cfg(feature = "oidc")]
+#[derive(Debug, rocket::FromForm)]
+pub struct OidcCallback<'r> {
+    pub code: &'r str,
+    pub state: &'r str,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpLogin<'r> {
+    pub challenge: &'r str,
+    pub code: &'r str,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPassword<'r> {
+    pub email: &'r str,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPassword<'r> {
+    pub token: &'r str,
+    pub password: &'r str,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmail<'r> {
+    pub token: &'r str,
+}
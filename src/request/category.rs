@@ -1,3 +1,4 @@
+use rocket::{FromForm, FromFormField};
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
@@ -6,6 +7,7 @@ pub struct UpdateCategory<'r> {
     pub description: Option<&'r str>,
     pub icon: Option<&'r str>,
     pub parent_id: Option<i64>,
+    pub is_public: Option<bool>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -14,6 +16,29 @@ pub struct CreateCategory<'r> {
     pub description: &'r str,
     pub icon: &'r str,
     pub parent_id: Option<i64>,
+    pub is_public: Option<bool>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, FromFormField)]
+pub enum DeleteStrategy {
+    #[default]
+    #[field(value = "refuse")]
+    Refuse,
+    #[field(value = "cascade")]
+    Cascade,
+    #[field(value = "reassign")]
+    Reassign,
+}
+
+#[derive(Debug, FromForm)]
+pub struct DeleteOptions {
+    pub strategy: Option<DeleteStrategy>,
+    pub target: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoveCategory {
+    pub parent_id: Option<i64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -22,3 +47,29 @@ pub struct SortCategory {
     pub over: Option<i64>,
     pub parent_id: Option<i64>,
 }
+
+/*
+ * The complete new order of the subcategories of `parent_id`, or of the
+ * top-level categories without one. `previous` is the order the client last
+ * read; the list is left alone when it has changed since.
+ */
+#[derive(Debug, Deserialize)]
+pub struct CategoryOrder {
+    pub parent_id: Option<i64>,
+    pub previous: Vec<i64>,
+    pub order: Vec<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SiteOrder {
+    pub previous: Vec<i64>,
+    pub order: Vec<i64>,
+}
+
+#[derive(Debug, FromForm)]
+pub struct SitesFilter<'r> {
+    pub search: Option<&'r str>,
+    pub include_descendants: Option<bool>,
+    pub page: Option<i64>,
+    pub size: Option<i64>,
+}
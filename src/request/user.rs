@@ -1,5 +1,7 @@
 use serde::Deserialize;
 
+use crate::models::user::Role;
+
 #[derive(Debug, Deserialize)]
 pub struct UpdateUser<'r> {
     pub username: Option<&'r str>,
@@ -13,3 +15,35 @@ pub struct UpdatePassword<'r> {
     pub password: &'r str,
     pub new_password: &'r str,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmTotp<'r> {
+    pub code: &'r str,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisableTotp<'r> {
+    pub password: &'r str,
+    pub code: &'r str,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUser<'r> {
+    pub username: &'r str,
+    pub nickname: &'r str,
+    pub password: &'r str,
+    pub email: &'r str,
+    pub avatar: Option<&'r str>,
+    pub role: Role,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateAccount {
+    pub role: Option<Role>,
+    pub disabled: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LinkIdentity<'r> {
+    pub subject: &'r str,
+}
@@ -1,12 +1,47 @@
+use rocket::http::{Header, Status};
+use rocket::response::Responder;
 use serde::Serialize;
 
+use crate::errors::ServiceError;
+
 #[derive(Debug, Serialize)]
 pub struct WithTotal<T> {
     pub total: i64,
     pub data: Vec<T>,
 }
 
+#[derive(Debug)]
+pub struct ErrorResponse {
+    pub status: Status,
+    pub retry_after: Option<i64>,
+}
+
+impl From<ServiceError> for ErrorResponse {
+    fn from(error: ServiceError) -> Self {
+        Self {
+            status: error.status(),
+            retry_after: error.retry_after(),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ErrorResponse {
+    fn respond_to(self, _: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        match self.retry_after {
+            Some(retry_after) => rocket::Response::build()
+                .status(self.status)
+                .header(Header::new("Retry-After", retry_after.to_string()))
+                .ok(),
+            None => Err(self.status),
+        }
+    }
+}
+
+pub mod access_token;
+pub mod audit;
 pub mod auth;
+pub mod captcha;
 pub mod category;
+pub mod session;
 pub mod site;
 pub mod user;
@@ -0,0 +1,40 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::models::access_token::{parse_scopes, AccessToken as AccessTokenModel, Scope};
+
+#[derive(Debug, Serialize)]
+pub struct AccessToken {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+/*
+ * Only returned once, when the token is created; afterwards just its hash is
+ * kept.
+ */
+#[derive(Debug, Serialize)]
+pub struct CreatedAccessToken {
+    #[serde(flatten)]
+    pub access_token: AccessToken,
+    pub token: String,
+}
+
+impl From<AccessTokenModel> for AccessToken {
+    fn from(token: AccessTokenModel) -> Self {
+        Self {
+            id: token.id,
+            name: token.name,
+            prefix: token.prefix,
+            scopes: parse_scopes(&token.scopes),
+            expires_at: token.expires_at,
+            last_used_at: token.last_used_at,
+            created_at: token.created_at,
+        }
+    }
+}
@@ -0,0 +1,19 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::models::audit_log::{Action, Entity};
+
+#[derive(Debug, Serialize)]
+pub struct AuditLog {
+    pub id: i64,
+    pub username: Option<String>,
+    pub session: Option<String>,
+    pub action: Action,
+    pub entity: Entity,
+    pub entity_id: Option<String>,
+    pub remote_ip: Option<String>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub created_at: NaiveDateTime,
+}
@@ -1,57 +1,106 @@
 use cookie::time::Duration;
-use rocket::http::{ContentType, Cookie};
+use rocket::http::{ContentType, Cookie, SameSite, Status};
+use rocket::response::status::Custom;
 use rocket::response::Responder;
+use rocket::serde::json::Json;
+use serde::Serialize;
 
-#[cfg(feature = "turnstile")]
-use serde::Deserialize;
+use crate::guards::csrf::CSRF_COOKIE;
+use crate::guards::jwt::TOKEN_COOKIE;
 
 const COOKIE_MAX_AGE: i64 = 2147483647;
 
-#[derive(Debug)]
+const REFRESH_COOKIE_PATH: &str = "/api/auth";
+
+#[derive(Debug, Serialize)]
 pub struct JwtToken {
     pub token: String,
+    pub refresh_token: String,
+    pub csrf_token: String,
 }
 
 impl<'r> Responder<'r, 'static> for JwtToken {
     fn respond_to(self, _: &rocket::Request<'_>) -> rocket::response::Result<'static> {
-        let mut cookie = Cookie::new("token", self.token);
+        let body = serde_json::to_string(&self).map_err(|_| Status::InternalServerError)?;
+
+        let mut cookie = Cookie::new(TOKEN_COOKIE, self.token);
 
         cookie.set_max_age(Duration::seconds(COOKIE_MAX_AGE));
         cookie.set_path("/");
+        cookie.set_http_only(true);
+        cookie.set_same_site(SameSite::Strict);
+
+        let mut refresh_cookie = Cookie::new("refresh_token", self.refresh_token);
+
+        refresh_cookie.set_max_age(Duration::seconds(COOKIE_MAX_AGE));
+        refresh_cookie.set_path(REFRESH_COOKIE_PATH);
+        refresh_cookie.set_http_only(true);
+        refresh_cookie.set_same_site(SameSite::Strict);
+
+        // Left readable by scripts, which echo it in the CSRF header.
+        let mut csrf_cookie = Cookie::new(CSRF_COOKIE, self.csrf_token);
+
+        csrf_cookie.set_max_age(Duration::seconds(COOKIE_MAX_AGE));
+        csrf_cookie.set_path("/");
+        csrf_cookie.set_same_site(SameSite::Strict);
 
         rocket::Response::build()
             .header(ContentType::JSON)
             .header(cookie)
-            .sized_body(0, std::io::Cursor::new(""))
+            .header_adjoin(refresh_cookie)
+            .header_adjoin(csrf_cookie)
+            .sized_body(body.len(), std::io::Cursor::new(body))
             .ok()
     }
 }
 
+#[derive(Debug, Serialize)]
+pub struct TwoFactorChallenge {
+    pub challenge: String,
+}
+
+#[derive(Debug)]
+pub enum Login {
+    Authenticated(JwtToken),
+    Challenge(TwoFactorChallenge),
+}
+
+impl<'r> Responder<'r, 'static> for Login {
+    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        match self {
+            Login::Authenticated(token) => token.respond_to(request),
+            Login::Challenge(challenge) => {
+                Custom(Status::Accepted, Json(challenge)).respond_to(request)
+            }
+        }
+    }
+}
+
 pub struct Logout;
 
 impl<'r> Responder<'r, 'static> for Logout {
     fn respond_to(self, _: &rocket::Request<'_>) -> rocket::response::Result<'static> {
-        let mut cookie = Cookie::new("token", "");
+        let mut cookie = Cookie::new(TOKEN_COOKIE, "");
 
         cookie.set_max_age(Duration::seconds(0));
         cookie.set_path("/");
 
+        let mut refresh_cookie = Cookie::new("refresh_token", "");
+
+        refresh_cookie.set_max_age(Duration::seconds(0));
+        refresh_cookie.set_path(REFRESH_COOKIE_PATH);
+
+        let mut csrf_cookie = Cookie::new(CSRF_COOKIE, "");
+
+        csrf_cookie.set_max_age(Duration::seconds(0));
+        csrf_cookie.set_path("/");
+
         rocket::Response::build()
             .header(ContentType::JSON)
             .header(cookie)
+            .header_adjoin(refresh_cookie)
+            .header_adjoin(csrf_cookie)
             .sized_body(0, std::io::Cursor::new(""))
             .ok()
     }
 }
-
-#[cfg(feature = "turnstile")]
-#[derive(Debug, Deserialize)]
-pub struct TurnstileResponse {
-    pub success: bool,
-    #[serde(rename(deserialize = "error-codes"))]
-    pub error_codes: Vec<String>,
-    pub challenge_ts: Option<String>,
-    pub hostname: Option<String>,
-    pub action: Option<String>,
-    pub cdata: Option<String>,
-}
@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+/*
+ * A proof-of-work challenge: find a nonce so that the SHA-256 of
+ * `<challenge>:<nonce>` starts with `difficulty` zero bits, then log in with
+ * `<challenge>:<nonce>` as the captcha token.
+ */
+#[derive(Debug, Serialize)]
+pub struct CaptchaChallenge {
+    pub challenge: String,
+    pub difficulty: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SiteVerifyResponse {
+    pub success: bool,
+    pub score: Option<f64>,
+    #[serde(default, rename(deserialize = "error-codes"))]
+    pub error_codes: Vec<String>,
+    pub hostname: Option<String>,
+}
@@ -1,6 +1,8 @@
 use serde::Serialize;
 
 use crate::models::category;
+use crate::response::site::Site;
+use crate::response::WithTotal;
 
 #[derive(Debug, Serialize, Clone)]
 pub struct Category {
@@ -9,6 +11,7 @@ pub struct Category {
     pub description: String,
     pub icon: String,
     pub parent_id: Option<i64>,
+    pub is_public: bool,
     pub children: Option<Vec<Category>>,
 }
 
@@ -20,7 +23,36 @@ impl From<category::Category> for Category {
             description: category.description,
             icon: category.icon,
             parent_id: category.parent_id,
+            is_public: category.is_public,
             children: None,
         }
     }
 }
+
+#[derive(Debug, Serialize)]
+pub struct CategoryWithPath {
+    #[serde(flatten)]
+    pub category: Category,
+    pub path: Vec<Category>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CategorySites {
+    pub category: Category,
+    pub sites: Vec<Site>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum CategorySitesListing {
+    Direct(Vec<Site>),
+    Subtree(WithTotal<CategorySites>),
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DeletedCategory {
+    pub deleted_categories: Vec<i64>,
+    pub deleted_sites: Vec<i64>,
+    pub moved_categories: Vec<i64>,
+    pub moved_sites: Vec<i64>,
+}
@@ -1,9 +1,25 @@
 use serde::Serialize;
 
+use crate::models::user::Role;
+
 #[derive(Debug, Serialize)]
 pub struct User {
     pub username: String,
     pub nickname: String,
     pub email: String,
+    pub email_verified: bool,
     pub avatar: String,
+    pub role: Role,
+    pub disabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub otpauth_url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
 }
@@ -1,5 +1,97 @@
+use rocket::{routes, Route};
+
+pub mod audit;
 pub mod auth;
 pub mod category;
 pub mod site;
 pub mod upload;
 pub mod user;
+
+/*
+ * Routes that change anything declare the guard that authorizes them first,
+ * so nothing else runs for a request that is not allowed to.
+ */
+pub fn mounts() -> Vec<(&'static str, Vec<Route>)> {
+    #[cfg_attr(not(feature = "oidc"), allow(unused_mut))]
+    let mut mounts = vec![
+        (
+            "/api/user",
+            routes![
+                user::me,
+                user::update,
+                user::update_password,
+                user::sessions,
+                user::revoke,
+                user::revoke_others,
+                user::tokens,
+                user::add_token,
+                user::delete_token_by_id,
+                user::enroll_totp,
+                user::confirm_totp,
+                user::disable_totp,
+                user::send_verification,
+            ],
+        ),
+        (
+            "/api/users",
+            routes![user::all, user::add, user::update_role, user::delete],
+        ),
+        (
+            "/api/auth",
+            routes![
+                auth::login,
+                auth::login_totp,
+                auth::captcha,
+                auth::refresh,
+                auth::logout,
+                auth::logout_all,
+                auth::forgot_password,
+                auth::reset_password,
+                auth::verify_email,
+            ],
+        ),
+        ("/api/categories", routes![category::all]),
+        (
+            "/api/category",
+            routes![
+                category::update,
+                category::add,
+                category::delete,
+                category::move_category,
+                category::get,
+                category::get_ancestors,
+                category::get_descendants,
+                category::get_sites,
+                category::sort,
+                category::sort_sites,
+                category::reorder,
+                category::reorder_sites,
+            ],
+        ),
+        ("/api/sites", routes![site::all]),
+        (
+            "/api/site",
+            routes![
+                site::get,
+                site::add,
+                site::update,
+                site::delete,
+                site::analytics
+            ],
+        ),
+        ("/api/upload", routes![upload::upload]),
+        ("/api/audit", routes![audit::all]),
+        ("/.well-known", routes![auth::jwks]),
+    ];
+
+    #[cfg(feature = "oidc")]
+    mounts.push(("/api/auth", routes![auth::oidc_login, auth::oidc_callback]));
+
+    #[cfg(feature = "oidc")]
+    mounts.push((
+        "/api/users",
+        routes![user::link_identity, user::unlink_identity],
+    ));
+
+    mounts
+}
@@ -0,0 +1,27 @@
+use log::error;
+use rocket::get;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket_db_pools::Connection;
+
+use crate::guards::role::Admin;
+use crate::handlers::audit::get_audit_log;
+use crate::request::audit::AuditFilter;
+use crate::response::audit::AuditLog;
+use crate::response::WithTotal;
+use crate::MySQLDb;
+
+#[get("/?<filter..>")]
+pub async fn all(
+    _admin: Admin,
+    filter: AuditFilter<'_>,
+    mut db: Connection<MySQLDb>,
+) -> Result<Json<WithTotal<AuditLog>>, Status> {
+    let result = get_audit_log(&filter, &mut db).await.map_err(|e| {
+        error!("{}", e);
+
+        e.status()
+    })?;
+
+    Ok(Json(result))
+}
@@ -1,16 +1,26 @@
 use log::error;
 use std::ops::Deref;
 
-use rocket::http::Status;
-use rocket::post;
+use jsonwebtoken::jwk::JwkSet;
+use rocket::http::{CookieJar, Status};
 use rocket::serde::json::Json;
 use rocket::State;
-use rocket_db_pools::deadpool_redis::redis::AsyncCommands;
+use rocket::{get, post};
+
+#[cfg(feature = "oidc")]
+use rocket::response::Redirect;
 use rocket_db_pools::Connection;
+use serde_json::json;
 
 use crate::config::Config;
-use crate::guards::{jwt::Middleware, remote_ip::Ip};
+use crate::guards::{
+    csrf::Csrf, device::Device, jwt::Middleware, remote_ip::Ip, user_agent::UserAgent,
+};
+use crate::handlers::audit::{self, Actor};
+use crate::handlers::session::{revoke_all_sessions, revoke_session};
+use crate::models::audit_log::{Action, Entity};
 use crate::response::auth::Logout;
+use crate::response::ErrorResponse;
 use crate::state::AppState;
 use crate::{handlers, request, response, MySQLDb, RedisDb};
 
@@ -20,12 +30,69 @@ pub async fn login(
     state: &State<AppState>,
     config: &State<Config>,
     remote_ip: Ip,
+    user_agent: UserAgent,
     mut db: Connection<MySQLDb>,
     mut cache: Connection<RedisDb>,
-) -> Result<response::auth::JwtToken, Status> {
+) -> Result<response::auth::Login, ErrorResponse> {
     let remote_ip = remote_ip.0;
 
-    let token = handlers::auth::login(user.deref(), state, config, remote_ip, &mut db, &mut cache)
+    let user_agent = user_agent.0;
+
+    let token = handlers::auth::login(
+        user.deref(),
+        state,
+        config,
+        remote_ip,
+        user_agent,
+        &mut db,
+        &mut cache,
+    )
+    .await
+    .map_err(|e| {
+        error!("{}", e);
+
+        ErrorResponse::from(e)
+    })?;
+
+    Ok(token)
+}
+
+#[post("/login/totp", format = "json", data = "<data>")]
+pub async fn login_totp(
+    data: Json<request::auth::TotpLogin<'_>>,
+    state: &State<AppState>,
+    config: &State<Config>,
+    remote_ip: Ip,
+    user_agent: UserAgent,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<response::auth::JwtToken, ErrorResponse> {
+    let token = handlers::auth::login_totp(
+        data.deref(),
+        state,
+        config,
+        remote_ip.0,
+        user_agent.0,
+        &mut db,
+        &mut cache,
+    )
+    .await
+    .map_err(|e| {
+        error!("{}", e);
+
+        ErrorResponse::from(e)
+    })?;
+
+    Ok(token)
+}
+
+#[cfg(feature = "oidc")]
+#[get("/oidc/login")]
+pub async fn oidc_login(
+    config: &State<Config>,
+    mut cache: Connection<RedisDb>,
+) -> Result<Redirect, Status> {
+    let url = handlers::oidc::authorize_url(config, &mut cache)
         .await
         .map_err(|e| {
             error!("{}", e);
@@ -33,18 +100,235 @@ pub async fn login(
             e.status()
         })?;
 
-    Ok(response::auth::JwtToken { token })
+    Ok(Redirect::to(url))
 }
 
-#[post("/logout")]
-pub async fn logout(_jwt: Middleware, mut cache: Connection<RedisDb>) -> Result<Logout, Status> {
-    let session = _jwt.session.clone();
+#[cfg(feature = "oidc")]
+#[get("/oidc/callback?<callback..>")]
+pub async fn oidc_callback(
+    callback: request::auth::OidcCallback<'_>,
+    app_state: &State<AppState>,
+    config: &State<Config>,
+    device: Device,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<response::auth::JwtToken, Status> {
+    let token = handlers::oidc::callback(
+        &callback,
+        app_state,
+        config,
+        device.remote_ip,
+        device.user_agent,
+        &mut db,
+        &mut cache,
+    )
+    .await
+    .map_err(|e| {
+        error!("{}", e);
 
-    cache.del(session).await.map_err(|e| {
+        e.status()
+    })?;
+
+    Ok(token)
+}
+
+#[get("/captcha")]
+pub async fn captcha(
+    state: &State<AppState>,
+    mut cache: Connection<RedisDb>,
+) -> Result<Json<response::captcha::CaptchaChallenge>, Status> {
+    let captcha = state.captcha.as_ref().ok_or(Status::NotFound)?;
+
+    let challenge = captcha
+        .challenge(&mut cache)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?
+        .ok_or(Status::NotFound)?;
+
+    Ok(Json(challenge))
+}
+
+#[post("/refresh", data = "<data>")]
+pub async fn refresh(
+    data: Option<Json<request::auth::Refresh<'_>>>,
+    cookies: &CookieJar<'_>,
+    csrf: Csrf,
+    device: Device,
+    state: &State<AppState>,
+    config: &State<Config>,
+    mut cache: Connection<RedisDb>,
+) -> Result<response::auth::JwtToken, Status> {
+    let refresh_token = match data.as_ref().and_then(|data| data.refresh_token) {
+        Some(refresh_token) => String::from(refresh_token),
+        None => match cookies.get("refresh_token") {
+            Some(_) if !csrf.verified => return Err(Status::Forbidden),
+            Some(cookie) => String::from(cookie.value()),
+            None => return Err(Status::Unauthorized),
+        },
+    };
+
+    let token = handlers::auth::refresh(
+        &refresh_token,
+        device.remote_ip.as_deref(),
+        device.user_agent.as_deref(),
+        state,
+        config,
+        &mut cache,
+    )
+    .await
+    .map_err(|e| {
         error!("{}", e);
 
-        Status::InternalServerError
+        e.status()
     })?;
 
+    Ok(token)
+}
+
+#[post("/logout")]
+pub async fn logout(
+    jwt: Middleware,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<Logout, Status> {
+    revoke_session(&jwt.session, &mut cache)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    let actor = Actor::from((&jwt, &remote_ip));
+
+    audit::record(
+        &actor,
+        Action::Logout,
+        Entity::Session,
+        Some(&jwt.session),
+        None,
+        None,
+        &mut db,
+    )
+    .await;
+
+    Ok(Logout)
+}
+
+#[post("/logout/all")]
+pub async fn logout_all(
+    jwt: Middleware,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<Logout, Status> {
+    let revoked = revoke_all_sessions(jwt.username(), &mut cache)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    let actor = Actor::from((&jwt, &remote_ip));
+
+    audit::record(
+        &actor,
+        Action::Logout,
+        Entity::Session,
+        None,
+        None,
+        Some(json!({ "revoked": revoked })),
+        &mut db,
+    )
+    .await;
+
+    Ok(Logout)
+}
+
+#[post("/password/forgot", format = "json", data = "<data>")]
+pub async fn forgot_password(
+    data: Json<request::auth::ForgotPassword<'_>>,
+    state: &State<AppState>,
+    config: &State<Config>,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<(), Status> {
+    handlers::email::forgot_password(data.email, state, config, &mut db, &mut cache)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    Ok(())
+}
+
+#[post("/password/reset", format = "json", data = "<data>")]
+pub async fn reset_password(
+    data: Json<request::auth::ResetPassword<'_>>,
+    state: &State<AppState>,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<Logout, Status> {
+    let username = handlers::email::reset_password(data.deref(), state, &mut db, &mut cache)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    let actor = Actor {
+        username: Some(&username),
+        session: None,
+        remote_ip: remote_ip.0.as_deref(),
+    };
+
+    audit::record(
+        &actor,
+        Action::Update,
+        Entity::User,
+        Some(&username),
+        None,
+        Some(json!({ "password": "reset" })),
+        &mut db,
+    )
+    .await;
+
     Ok(Logout)
 }
+
+#[post("/email/verify", format = "json", data = "<data>")]
+pub async fn verify_email(
+    data: Json<request::auth::VerifyEmail<'_>>,
+    state: &State<AppState>,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<(), Status> {
+    handlers::email::verify_email(data.token, state, &mut db, &mut cache)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    Ok(())
+}
+
+/*
+ * The public keys session tokens are signed with, for proxies that validate
+ * them on their own. Empty while tokens are signed with the shared secret.
+ */
+#[get("/jwks.json")]
+pub fn jwks(state: &State<AppState>) -> Json<JwkSet> {
+    Json(state.jwt_keys.jwks().clone())
+}
@@ -3,22 +3,32 @@ use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::{delete, get, post, put, State};
 use rocket_db_pools::Connection;
+use serde_json::json;
 
 use crate::config::Config;
-use crate::guards::jwt::Middleware;
+use crate::guards::remote_ip::Ip;
+use crate::guards::scope::{Authorized, CategoriesRead, CategoriesWrite, SitesRead, SitesWrite};
+use crate::guards::visibility::Reader;
+use crate::handlers::audit::{self, Actor};
 use crate::handlers::category::{
-    self, add_category, delete_category, get_categories, get_categories_flat, sort_categories,
-    sort_category_sites, update_category,
+    self, add_category, delete_category, get_categories, get_categories_flat, reorder_categories,
+    reorder_category_sites, sort_categories, sort_category_sites, update_category,
+};
+use crate::models::audit_log::{Action, Entity};
+use crate::request::category::{
+    CategoryOrder, CreateCategory, DeleteOptions, MoveCategory, SiteOrder, SitesFilter,
+    SortCategory, UpdateCategory,
+};
+use crate::response::category::{
+    Category, CategorySitesListing, CategoryWithPath, DeletedCategory,
 };
-use crate::request::category::{CreateCategory, SortCategory, UpdateCategory};
-use crate::response::category::Category;
-use crate::response::site::Site;
 use crate::response::WithTotal;
 use crate::utils::standardize_url;
 use crate::MySQLDb;
 
 #[get("/?<page>&<size>&<search>&<flat>")]
 pub async fn all(
+    reader: Reader<CategoriesRead>,
     page: Option<i64>,
     size: Option<i64>,
     search: Option<&str>,
@@ -31,20 +41,34 @@ pub async fn all(
     let size = size.unwrap_or(10);
 
     let result = match flat {
-        Some(true) => get_categories_flat(page, size, search, &config.upload_url, &mut db)
-            .await
-            .map_err(|e| {
-                error!("{}", e);
+        Some(true) => get_categories_flat(
+            page,
+            size,
+            search,
+            reader.public_only,
+            &config.upload_url,
+            &mut db,
+        )
+        .await
+        .map_err(|e| {
+            error!("{}", e);
 
-                e.status()
-            })?,
-        _ => get_categories(page, size, search, &config.upload_url, &mut db)
-            .await
-            .map_err(|e| {
-                error!("{}", e);
+            e.status()
+        })?,
+        _ => get_categories(
+            page,
+            size,
+            search,
+            reader.public_only,
+            &config.upload_url,
+            &mut db,
+        )
+        .await
+        .map_err(|e| {
+            error!("{}", e);
 
-                e.status()
-            })?,
+            e.status()
+        })?,
     };
 
     Ok(Json(result))
@@ -52,11 +76,12 @@ pub async fn all(
 
 #[put("/<id>", format = "json", data = "<category>")]
 pub async fn update<'r>(
+    auth: Authorized<CategoriesWrite>,
     id: &'r str,
     category: Json<UpdateCategory<'r>>,
     config: &State<Config>,
+    remote_ip: Ip,
     mut db: Connection<MySQLDb>,
-    _jwt: Middleware,
 ) -> Result<(), Status> {
     let mut category = category.into_inner();
 
@@ -67,21 +92,41 @@ pub async fn update<'r>(
 
     category.icon = icon.as_deref();
 
-    update_category(id, &category, &mut db).await.map_err(|e| {
-        error!("{}", e);
+    let before = audit::snapshot(Entity::Category, id, &mut db).await;
 
-        e.status()
-    })?;
+    update_category(id, &category, config.max_category_depth, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    let after = audit::snapshot(Entity::Category, id, &mut db).await;
+
+    let actor = Actor::from((&auth, &remote_ip));
+
+    audit::record(
+        &actor,
+        Action::Update,
+        Entity::Category,
+        Some(id),
+        before,
+        after,
+        &mut db,
+    )
+    .await;
 
     Ok(())
 }
 
 #[post("/", format = "json", data = "<category>")]
 pub async fn add<'r>(
+    auth: Authorized<CategoriesWrite>,
     category: Json<CreateCategory<'r>>,
     config: &State<Config>,
+    remote_ip: Ip,
     mut db: Connection<MySQLDb>,
-    _jwt: Middleware,
 ) -> Result<(), Status> {
     let mut category = category.into_inner();
 
@@ -94,34 +139,188 @@ pub async fn add<'r>(
 
     category.icon = icon.as_str();
 
-    add_category(&category, &mut db).await.map_err(|e| {
-        error!("{}", e);
+    let id = add_category(&category, config.max_category_depth, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
 
-        e.status()
-    })?;
+            e.status()
+        })?
+        .to_string();
+
+    let after = audit::snapshot(Entity::Category, &id, &mut db).await;
+
+    let actor = Actor::from((&auth, &remote_ip));
+
+    audit::record(
+        &actor,
+        Action::Create,
+        Entity::Category,
+        Some(&id),
+        None,
+        after,
+        &mut db,
+    )
+    .await;
 
     Ok(())
 }
 
-#[delete("/<id>")]
-pub async fn delete(id: &str, mut db: Connection<MySQLDb>, _jwt: Middleware) -> Result<(), Status> {
-    delete_category(id, &mut db).await.map_err(|e| {
+#[delete("/<id>?<options..>")]
+pub async fn delete(
+    auth: Authorized<CategoriesWrite>,
+    id: i64,
+    options: DeleteOptions,
+    config: &State<Config>,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+) -> Result<Json<DeletedCategory>, Status> {
+    let entity_id = id.to_string();
+
+    let before = audit::snapshot(Entity::Category, &entity_id, &mut db).await;
+
+    let deleted = delete_category(
+        id,
+        options.strategy.unwrap_or_default(),
+        options.target,
+        config.max_category_depth,
+        &mut db,
+    )
+    .await
+    .map_err(|e| {
         error!("{}", e);
 
         e.status()
     })?;
 
+    let actor = Actor::from((&auth, &remote_ip));
+
+    // What else went with it, or was moved away.
+    audit::record(
+        &actor,
+        Action::Delete,
+        Entity::Category,
+        Some(&entity_id),
+        before,
+        Some(json!(deleted)),
+        &mut db,
+    )
+    .await;
+
+    Ok(Json(deleted))
+}
+
+#[post("/<id>/move", format = "json", data = "<data>")]
+pub async fn move_category(
+    auth: Authorized<CategoriesWrite>,
+    id: i64,
+    data: Json<MoveCategory>,
+    config: &State<Config>,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+) -> Result<(), Status> {
+    let entity_id = id.to_string();
+
+    let before = audit::snapshot(Entity::Category, &entity_id, &mut db).await;
+
+    category::move_category(id, data.parent_id, config.max_category_depth, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    let after = audit::snapshot(Entity::Category, &entity_id, &mut db).await;
+
+    let actor = Actor::from((&auth, &remote_ip));
+
+    audit::record(
+        &actor,
+        Action::Update,
+        Entity::Category,
+        Some(&entity_id),
+        before,
+        after,
+        &mut db,
+    )
+    .await;
+
     Ok(())
 }
 
-#[get("/<id>/sites?<search>")]
+#[get("/<id>")]
+pub async fn get(
+    reader: Reader<CategoriesRead>,
+    id: i64,
+    config: &State<Config>,
+    mut db: Connection<MySQLDb>,
+) -> Result<Json<CategoryWithPath>, Status> {
+    let category = category::get_category(id, reader.public_only, &config.upload_url, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    Ok(Json(category))
+}
+
+#[get("/<id>/ancestors")]
+pub async fn get_ancestors(
+    reader: Reader<CategoriesRead>,
+    id: i64,
+    config: &State<Config>,
+    mut db: Connection<MySQLDb>,
+) -> Result<Json<Vec<Category>>, Status> {
+    let categories = category::get_ancestors(id, reader.public_only, &config.upload_url, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    Ok(Json(categories))
+}
+
+#[get("/<id>/descendants?<depth>")]
+pub async fn get_descendants(
+    reader: Reader<CategoriesRead>,
+    id: i64,
+    depth: Option<i64>,
+    config: &State<Config>,
+    mut db: Connection<MySQLDb>,
+) -> Result<Json<Vec<Category>>, Status> {
+    let categories =
+        category::get_descendants(id, depth, reader.public_only, &config.upload_url, &mut db)
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+
+                e.status()
+            })?;
+
+    Ok(Json(categories))
+}
+
+#[get("/<id>/sites?<filter..>")]
 pub async fn get_sites(
+    reader: Reader<SitesRead>,
     id: &str,
-    search: Option<&str>,
+    filter: SitesFilter<'_>,
     config: &State<Config>,
     mut db: Connection<MySQLDb>,
-) -> Result<Json<Vec<Site>>, Status> {
-    let sites = category::get_sites(id, search, &config.upload_url, &mut db)
+) -> Result<Json<CategorySitesListing>, Status> {
+    if filter.include_descendants == Some(true) {
+        let sites = category::get_subtree_sites(
+            id,
+            &filter,
+            reader.public_only,
+            &config.upload_url,
+            &mut db,
+        )
         .await
         .map_err(|e| {
             error!("{}", e);
@@ -129,11 +328,37 @@ pub async fn get_sites(
             e.status()
         })?;
 
-    Ok(Json(sites))
+        return Ok(Json(CategorySitesListing::Subtree(sites)));
+    }
+
+    let sites = category::get_sites(
+        id,
+        filter.search,
+        reader.public_only,
+        &config.upload_url,
+        &mut db,
+    )
+    .await
+    .map_err(|e| {
+        error!("{}", e);
+
+        e.status()
+    })?;
+
+    Ok(Json(CategorySitesListing::Direct(sites)))
 }
 
 #[post("/sort", format = "json", data = "<data>")]
-pub async fn sort(data: Json<SortCategory>, mut db: Connection<MySQLDb>) -> Result<(), Status> {
+pub async fn sort(
+    auth: Authorized<CategoriesWrite>,
+    data: Json<SortCategory>,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+) -> Result<(), Status> {
+    let id = data.active.to_string();
+
+    let before = audit::snapshot(Entity::Category, &id, &mut db).await;
+
     sort_categories(data.active, data.over, data.parent_id, &mut db)
         .await
         .map_err(|e| {
@@ -142,15 +367,36 @@ pub async fn sort(data: Json<SortCategory>, mut db: Connection<MySQLDb>) -> Resu
             e.status()
         })?;
 
+    let after = audit::snapshot(Entity::Category, &id, &mut db).await;
+
+    let actor = Actor::from((&auth, &remote_ip));
+
+    audit::record(
+        &actor,
+        Action::Sort,
+        Entity::Category,
+        Some(&id),
+        before,
+        after,
+        &mut db,
+    )
+    .await;
+
     Ok(())
 }
 
 #[post("/<id>/sites/sort", format = "json", data = "<data>")]
 pub async fn sort_sites(
+    auth: Authorized<SitesWrite>,
     id: i64,
     data: Json<SortCategory>,
+    remote_ip: Ip,
     mut db: Connection<MySQLDb>,
 ) -> Result<(), Status> {
+    let site_id = data.active.to_string();
+
+    let before = audit::snapshot(Entity::Site, &site_id, &mut db).await;
+
     sort_category_sites(id, data.active, data.over, &mut db)
         .await
         .map_err(|e| {
@@ -159,5 +405,91 @@ pub async fn sort_sites(
             e.status()
         })?;
 
+    let after = audit::snapshot(Entity::Site, &site_id, &mut db).await;
+
+    let actor = Actor::from((&auth, &remote_ip));
+
+    audit::record(
+        &actor,
+        Action::Sort,
+        Entity::Site,
+        Some(&site_id),
+        before,
+        after,
+        &mut db,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[post("/order", format = "json", data = "<data>")]
+pub async fn reorder(
+    auth: Authorized<CategoriesWrite>,
+    data: Json<Vec<CategoryOrder>>,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+) -> Result<(), Status> {
+    reorder_categories(&data, &mut db).await.map_err(|e| {
+        error!("{}", e);
+
+        e.status()
+    })?;
+
+    let actor = Actor::from((&auth, &remote_ip));
+
+    let before = data
+        .iter()
+        .map(|list| json!({ "parent_id": list.parent_id, "order": list.previous }))
+        .collect::<Vec<_>>();
+
+    let after = data
+        .iter()
+        .map(|list| json!({ "parent_id": list.parent_id, "order": list.order }))
+        .collect::<Vec<_>>();
+
+    audit::record(
+        &actor,
+        Action::Sort,
+        Entity::Category,
+        None,
+        Some(json!(before)),
+        Some(json!(after)),
+        &mut db,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[post("/<id>/sites/order", format = "json", data = "<data>")]
+pub async fn reorder_sites(
+    auth: Authorized<SitesWrite>,
+    id: i64,
+    data: Json<SiteOrder>,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+) -> Result<(), Status> {
+    reorder_category_sites(id, &data, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    let actor = Actor::from((&auth, &remote_ip));
+
+    audit::record(
+        &actor,
+        Action::Sort,
+        Entity::Category,
+        Some(&id.to_string()),
+        Some(json!({ "sites": data.previous })),
+        Some(json!({ "sites": data.order })),
+        &mut db,
+    )
+    .await;
+
     Ok(())
 }
@@ -5,9 +5,13 @@ use rocket::{delete, get, post, put, State};
 use rocket_db_pools::Connection;
 
 use crate::config::Config;
-use crate::guards::jwt::Middleware;
+use crate::guards::remote_ip::Ip;
+use crate::guards::scope::{Authorized, SitesRead, SitesWrite};
+use crate::guards::visibility::Reader;
+use crate::handlers::audit::{self, Actor};
 use crate::handlers::site;
 use crate::handlers::site::get_sites;
+use crate::models::audit_log::{Action, Entity};
 use crate::request::site::{CreateSite, UpdateSite};
 use crate::response::site::{Site, SiteWithCategory};
 use crate::response::WithTotal;
@@ -16,15 +20,18 @@ use crate::MySQLDb;
 
 #[get("/<id>")]
 pub async fn get(
+    reader: Reader<SitesRead>,
     id: i64,
     config: &State<Config>,
     mut db: Connection<MySQLDb>,
 ) -> Result<Json<Site>, Status> {
-    let mut site = site::get_site(id, &mut db).await.map_err(|e| {
-        error!("{}", e);
+    let mut site = site::get_site(id, reader.public_only, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
 
-        e.status()
-    })?;
+            e.status()
+        })?;
 
     let icon = site.icon.clone();
 
@@ -41,6 +48,7 @@ pub async fn get(
 
 #[get("/?<page>&<size>&<search>")]
 pub async fn all(
+    reader: Reader<SitesRead>,
     page: Option<i64>,
     size: Option<i64>,
     search: Option<&str>,
@@ -51,23 +59,31 @@ pub async fn all(
 
     let size = size.unwrap_or(10);
 
-    let result = get_sites(page, size, search, &config.upload_url, &mut db)
-        .await
-        .map_err(|e| {
-            error!("{}", e);
+    let result = get_sites(
+        page,
+        size,
+        search,
+        reader.public_only,
+        &config.upload_url,
+        &mut db,
+    )
+    .await
+    .map_err(|e| {
+        error!("{}", e);
 
-            e.status()
-        })?;
+        e.status()
+    })?;
 
     Ok(Json(result))
 }
 
 #[post("/", format = "json", data = "<site>")]
 pub async fn add(
+    auth: Authorized<SitesWrite>,
     site: Json<CreateSite<'_>>,
     config: &State<Config>,
+    remote_ip: Ip,
     mut db: Connection<MySQLDb>,
-    _jwt: Middleware,
 ) -> Result<(), Status> {
     let mut site = site.into_inner();
 
@@ -77,22 +93,41 @@ pub async fn add(
 
     site.icon = icon.as_str();
 
-    site::add_site(&site, &mut db).await.map_err(|e| {
-        error!("{}", e);
+    let id = site::add_site(&site, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
 
-        e.status()
-    })?;
+            e.status()
+        })?
+        .to_string();
+
+    let after = audit::snapshot(Entity::Site, &id, &mut db).await;
+
+    let actor = Actor::from((&auth, &remote_ip));
+
+    audit::record(
+        &actor,
+        Action::Create,
+        Entity::Site,
+        Some(&id),
+        None,
+        after,
+        &mut db,
+    )
+    .await;
 
     Ok(())
 }
 
 #[put("/<id>", format = "json", data = "<site>")]
 pub async fn update<'r>(
+    auth: Authorized<SitesWrite>,
     id: &'r str,
     site: Json<UpdateSite<'r>>,
     config: &State<Config>,
+    remote_ip: Ip,
     mut db: Connection<MySQLDb>,
-    _jwt: Middleware,
 ) -> Result<(), Status> {
     let mut site = site.into_inner();
 
@@ -103,33 +138,76 @@ pub async fn update<'r>(
 
     site.icon = icon.as_deref();
 
+    let before = audit::snapshot(Entity::Site, id, &mut db).await;
+
     site::update_site(id, &site, &mut db).await.map_err(|e| {
         error!("{}", e);
 
         e.status()
     })?;
 
+    let after = audit::snapshot(Entity::Site, id, &mut db).await;
+
+    let actor = Actor::from((&auth, &remote_ip));
+
+    audit::record(
+        &actor,
+        Action::Update,
+        Entity::Site,
+        Some(id),
+        before,
+        after,
+        &mut db,
+    )
+    .await;
+
     Ok(())
 }
 
 #[delete("/<id>")]
-pub async fn delete(id: &str, mut db: Connection<MySQLDb>, _jwt: Middleware) -> Result<(), Status> {
+pub async fn delete(
+    auth: Authorized<SitesWrite>,
+    id: &str,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+) -> Result<(), Status> {
+    let before = audit::snapshot(Entity::Site, id, &mut db).await;
+
     site::delete_site(id, &mut db).await.map_err(|e| {
         error!("{}", e);
 
         e.status()
     })?;
 
+    let actor = Actor::from((&auth, &remote_ip));
+
+    audit::record(
+        &actor,
+        Action::Delete,
+        Entity::Site,
+        Some(id),
+        before,
+        None,
+        &mut db,
+    )
+    .await;
+
     Ok(())
 }
 
 #[post("/<id>/visit")]
-pub async fn analytics(id: &str, mut db: Connection<MySQLDb>) -> Result<(), Status> {
-    site::analytics(id, &mut db).await.map_err(|e| {
-        error!("{}", e);
+pub async fn analytics(
+    reader: Reader<SitesRead>,
+    id: i64,
+    mut db: Connection<MySQLDb>,
+) -> Result<(), Status> {
+    site::analytics(id, reader.public_only, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
 
-        e.status()
-    })?;
+            e.status()
+        })?;
 
     Ok(())
 }
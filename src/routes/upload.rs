@@ -4,10 +4,16 @@ use rocket::fs::TempFile;
 use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::{post, FromForm, State};
+use rocket_db_pools::Connection;
+use serde_json::json;
 
 use crate::config::Config;
-use crate::guards::jwt::Middleware;
+use crate::guards::remote_ip::Ip;
+use crate::guards::scope::{self, Authorized};
 use crate::handlers;
+use crate::handlers::audit::{self, Actor};
+use crate::models::audit_log::{Action, Entity};
+use crate::MySQLDb;
 
 #[derive(FromForm)]
 pub struct Upload<'r> {
@@ -17,9 +23,11 @@ pub struct Upload<'r> {
 
 #[post("/", data = "<data>")]
 pub async fn upload(
+    auth: Authorized<scope::Upload>,
     data: Form<Upload<'_>>,
     config: &State<Config>,
-    _jwt: Middleware,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
 ) -> Result<Json<String>, Status> {
     let result = handlers::upload::upload(&data.file, &config.upload_dir)
         .await
@@ -29,5 +37,20 @@ pub async fn upload(
             e.status()
         })?;
 
-    Ok(Json(format!("{}/{}", config.upload_url, result)))
+    let url = format!("{}/{}", config.upload_url, result);
+
+    let actor = Actor::from((&auth, &remote_ip));
+
+    audit::record(
+        &actor,
+        Action::Create,
+        Entity::Upload,
+        Some(&result),
+        None,
+        Some(json!({ "url": url })),
+        &mut db,
+    )
+    .await;
+
+    Ok(Json(url))
 }
@@ -3,33 +3,48 @@ use std::ops::Deref;
 use log::error;
 use rocket::http::Status;
 use rocket::serde::json::Json;
-use rocket::{get, put, State};
-use rocket_db_pools::deadpool_redis::redis::AsyncCommands;
+use rocket::{delete, get, post, put, State};
 use rocket_db_pools::Connection;
+use serde_json::json;
+use uuid::Uuid;
 
 use crate::config::Config;
 use crate::guards::jwt::Middleware;
-use crate::handlers::user::{get_user, update_user, update_user_password};
-use crate::request::user::{UpdatePassword, UpdateUser};
+use crate::guards::remote_ip::Ip;
+use crate::guards::role::{Admin, SelfOrAdmin};
+use crate::handlers::access_token::{create_token, delete_token, get_tokens};
+use crate::handlers::audit::{self, Actor};
+use crate::handlers::session::{
+    get_sessions, revoke_all_sessions, revoke_other_sessions, revoke_session,
+};
+use crate::handlers::user::{
+    create_user, delete_user, get_user, get_users, update_account, update_user,
+    update_user_password,
+};
+use crate::handlers::{email, totp};
+use crate::models::audit_log::{Action, Entity};
+use crate::request::access_token::CreateAccessToken;
+#[cfg(feature = "oidc")]
+use crate::request::user::LinkIdentity;
+use crate::request::user::{
+    ConfirmTotp, CreateUser, DisableTotp, UpdateAccount, UpdatePassword, UpdateUser,
+};
+use crate::response::access_token::{AccessToken, CreatedAccessToken};
 use crate::response::auth::Logout;
-use crate::response::user::User;
+use crate::response::session::Session;
+use crate::response::user::{RecoveryCodes, TotpEnrollment, User};
+use crate::response::{ErrorResponse, WithTotal};
+use crate::state::AppState;
 use crate::utils::standardize_url;
 use crate::{MySQLDb, RedisDb};
 
 #[get("/")]
 pub async fn me(
+    jwt: Middleware,
     mut db: Connection<MySQLDb>,
     config: &State<Config>,
-    jwt: Middleware,
 ) -> Result<Json<User>, Status> {
-    let session = &jwt.session;
-
-    let username = match session.split(':').next() {
-        Some(username) => username,
-        None => return Err(Status::Unauthorized),
-    };
-
-    let user = get_user(username, &config.upload_url, &mut db)
+    let user = get_user(jwt.username(), &config.upload_url, &mut db)
         .await
         .map_err(|e| {
             error!("{}", e);
@@ -42,11 +57,13 @@ pub async fn me(
 
 #[put("/<username>", format = "json", data = "<user>")]
 pub async fn update(
+    owner: SelfOrAdmin,
     username: &'_ str,
     user: Json<UpdateUser<'_>>,
     config: &State<Config>,
+    remote_ip: Ip,
     mut db: Connection<MySQLDb>,
-    _jwt: Middleware,
+    mut cache: Connection<RedisDb>,
 ) -> Result<(), Status> {
     let mut user = user.into_inner();
 
@@ -57,24 +74,54 @@ pub async fn update(
 
     user.avatar = avatar.as_deref();
 
+    let before = audit::snapshot(Entity::User, username, &mut db).await;
+
     update_user(username, &user, &mut db).await.map_err(|e| {
         error!("{}", e);
 
         e.status()
     })?;
 
+    let renamed = user.username.unwrap_or(username);
+
+    // Sessions are indexed by the old name, which someone else may take.
+    if renamed != username {
+        revoke_all_sessions(username, &mut cache)
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+
+                e.status()
+            })?;
+    }
+
+    let after = audit::snapshot(Entity::User, renamed, &mut db).await;
+
+    audit::record(
+        &Actor::from((&owner.0, &remote_ip)),
+        Action::Update,
+        Entity::User,
+        Some(username),
+        before,
+        after,
+        &mut db,
+    )
+    .await;
+
     Ok(())
 }
 
 #[put("/<username>/password", format = "json", data = "<password>")]
 pub async fn update_password<'r>(
+    owner: SelfOrAdmin,
     username: &'r str,
     password: Json<UpdatePassword<'r>>,
+    state: &State<AppState>,
+    remote_ip: Ip,
     mut db: Connection<MySQLDb>,
     mut cache: Connection<RedisDb>,
-    _jwt: Middleware,
 ) -> Result<Logout, Status> {
-    update_user_password(username, password.deref(), &mut db)
+    update_user_password(username, password.deref(), &state.password_policy, &mut db)
         .await
         .map_err(|e| {
             error!("{}", e);
@@ -82,22 +129,459 @@ pub async fn update_password<'r>(
             e.status()
         })?;
 
-    let keys = cache
-        .keys::<String, Vec<String>>(format!("{}:*", username))
+    revoke_all_sessions(username, &mut cache)
         .await
         .map_err(|e| {
             error!("{}", e);
 
-            Status::InternalServerError
+            e.status()
         })?;
 
-    for key in keys {
-        cache.del(key).await.map_err(|e| {
+    // Only that it changed; the password itself is not kept.
+    audit::record(
+        &Actor::from((&owner.0, &remote_ip)),
+        Action::Update,
+        Entity::User,
+        Some(username),
+        None,
+        Some(json!({ "password": "changed" })),
+        &mut db,
+    )
+    .await;
+
+    Ok(Logout)
+}
+
+#[get("/sessions")]
+pub async fn sessions(
+    jwt: Middleware,
+    mut cache: Connection<RedisDb>,
+) -> Result<Json<Vec<Session>>, Status> {
+    let sessions = get_sessions(jwt.username(), &jwt.session, &mut cache)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    Ok(Json(sessions))
+}
+
+#[delete("/sessions/<id>")]
+pub async fn revoke(
+    jwt: Middleware,
+    id: Uuid,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<(), Status> {
+    let session = format!("{}:{}", jwt.username(), id);
+
+    revoke_session(&session, &mut cache).await.map_err(|e| {
+        error!("{}", e);
+
+        e.status()
+    })?;
+
+    audit::record(
+        &Actor::from((&jwt, &remote_ip)),
+        Action::Logout,
+        Entity::Session,
+        Some(&session),
+        None,
+        None,
+        &mut db,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[delete("/sessions")]
+pub async fn revoke_others(
+    jwt: Middleware,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<(), Status> {
+    let revoked = revoke_other_sessions(jwt.username(), &jwt.session, &mut cache)
+        .await
+        .map_err(|e| {
             error!("{}", e);
 
-            Status::InternalServerError
+            e.status()
         })?;
+
+    audit::record(
+        &Actor::from((&jwt, &remote_ip)),
+        Action::Logout,
+        Entity::Session,
+        None,
+        None,
+        Some(json!({ "revoked": revoked })),
+        &mut db,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[get("/tokens")]
+pub async fn tokens(
+    jwt: Middleware,
+    mut db: Connection<MySQLDb>,
+) -> Result<Json<Vec<AccessToken>>, Status> {
+    let tokens = get_tokens(jwt.username(), &mut db).await.map_err(|e| {
+        error!("{}", e);
+
+        e.status()
+    })?;
+
+    Ok(Json(tokens))
+}
+
+#[post("/tokens", format = "json", data = "<data>")]
+pub async fn add_token(
+    jwt: Middleware,
+    data: Json<CreateAccessToken<'_>>,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+) -> Result<Json<CreatedAccessToken>, Status> {
+    let token = create_token(jwt.username(), &data, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    audit::record(
+        &Actor::from((&jwt, &remote_ip)),
+        Action::Create,
+        Entity::AccessToken,
+        Some(&token.access_token.id.to_string()),
+        None,
+        Some(json!(token.access_token)),
+        &mut db,
+    )
+    .await;
+
+    Ok(Json(token))
+}
+
+#[delete("/tokens/<id>")]
+pub async fn delete_token_by_id(
+    jwt: Middleware,
+    id: i64,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+) -> Result<(), Status> {
+    delete_token(jwt.username(), id, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    audit::record(
+        &Actor::from((&jwt, &remote_ip)),
+        Action::Delete,
+        Entity::AccessToken,
+        Some(&id.to_string()),
+        None,
+        None,
+        &mut db,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[post("/totp")]
+pub async fn enroll_totp(
+    jwt: Middleware,
+    config: &State<Config>,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<Json<TotpEnrollment>, Status> {
+    let enrollment = totp::enroll(jwt.username(), config, &mut db, &mut cache)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    Ok(Json(enrollment))
+}
+
+#[post("/totp/confirm", format = "json", data = "<data>")]
+pub async fn confirm_totp(
+    jwt: Middleware,
+    data: Json<ConfirmTotp<'_>>,
+    config: &State<Config>,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<Json<RecoveryCodes>, Status> {
+    let recovery_codes = totp::confirm(jwt.username(), data.code, config, &mut db, &mut cache)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    Ok(Json(recovery_codes))
+}
+
+#[delete("/totp", format = "json", data = "<data>")]
+pub async fn disable_totp(
+    jwt: Middleware,
+    data: Json<DisableTotp<'_>>,
+    config: &State<Config>,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<(), Status> {
+    totp::disable(jwt.username(), &data, config, &mut db, &mut cache)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    Ok(())
+}
+
+#[post("/email/verification")]
+pub async fn send_verification(
+    jwt: Middleware,
+    state: &State<AppState>,
+    config: &State<Config>,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<(), ErrorResponse> {
+    email::send_verification(jwt.username(), state, config, &mut db, &mut cache)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            ErrorResponse::from(e)
+        })?;
+
+    Ok(())
+}
+
+#[get("/?<page>&<size>&<search>")]
+pub async fn all(
+    _admin: Admin,
+    page: Option<i64>,
+    size: Option<i64>,
+    search: Option<&str>,
+    config: &State<Config>,
+    mut db: Connection<MySQLDb>,
+) -> Result<Json<WithTotal<User>>, Status> {
+    let page = page.unwrap_or(0);
+
+    let size = size.unwrap_or(10);
+
+    let result = get_users(page, size, search, &config.upload_url, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    Ok(Json(result))
+}
+
+#[post("/", format = "json", data = "<user>")]
+pub async fn add(
+    admin: Admin,
+    user: Json<CreateUser<'_>>,
+    config: &State<Config>,
+    state: &State<AppState>,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+) -> Result<(), Status> {
+    let mut user = user.into_inner();
+
+    let avatar = match user.avatar {
+        Some(avatar) => standardize_url(avatar, &config.upload_url),
+        None => None,
+    };
+
+    user.avatar = avatar.as_deref();
+
+    create_user(&user, &state.password_policy, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    let after = audit::snapshot(Entity::User, user.username, &mut db).await;
+
+    audit::record(
+        &Actor::from((&admin.0, &remote_ip)),
+        Action::Create,
+        Entity::User,
+        Some(user.username),
+        None,
+        after,
+        &mut db,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[put("/<username>", format = "json", data = "<account>")]
+pub async fn update_role(
+    admin: Admin,
+    username: &str,
+    account: Json<UpdateAccount>,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<(), Status> {
+    let before = audit::snapshot(Entity::User, username, &mut db).await;
+
+    update_account(username, &account, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    if account.disabled == Some(true) {
+        revoke_all_sessions(username, &mut cache)
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+
+                e.status()
+            })?;
     }
 
-    Ok(Logout)
+    let after = audit::snapshot(Entity::User, username, &mut db).await;
+
+    audit::record(
+        &Actor::from((&admin.0, &remote_ip)),
+        Action::Update,
+        Entity::User,
+        Some(username),
+        before,
+        after,
+        &mut db,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[delete("/<username>")]
+pub async fn delete(
+    admin: Admin,
+    username: &str,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+    mut cache: Connection<RedisDb>,
+) -> Result<(), Status> {
+    let before = audit::snapshot(Entity::User, username, &mut db).await;
+
+    delete_user(username, &mut db).await.map_err(|e| {
+        error!("{}", e);
+
+        e.status()
+    })?;
+
+    revoke_all_sessions(username, &mut cache)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    audit::record(
+        &Actor::from((&admin.0, &remote_ip)),
+        Action::Delete,
+        Entity::User,
+        Some(username),
+        before,
+        None,
+        &mut db,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[cfg(feature = "oidc")]
+#[put("/<username>/identity", format = "json", data = "<identity>")]
+pub async fn link_identity(
+    admin: Admin,
+    username: &str,
+    identity: Json<LinkIdentity<'_>>,
+    config: &State<Config>,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+) -> Result<(), Status> {
+    crate::handlers::oidc::link_user(username, identity.subject, config, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    audit::record(
+        &Actor::from((&admin.0, &remote_ip)),
+        Action::Update,
+        Entity::User,
+        Some(username),
+        None,
+        Some(json!({ "oidc_subject": identity.subject })),
+        &mut db,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[cfg(feature = "oidc")]
+#[delete("/<username>/identity")]
+pub async fn unlink_identity(
+    admin: Admin,
+    username: &str,
+    config: &State<Config>,
+    remote_ip: Ip,
+    mut db: Connection<MySQLDb>,
+) -> Result<(), Status> {
+    crate::handlers::oidc::unlink_user(username, config, &mut db)
+        .await
+        .map_err(|e| {
+            error!("{}", e);
+
+            e.status()
+        })?;
+
+    audit::record(
+        &Actor::from((&admin.0, &remote_ip)),
+        Action::Update,
+        Entity::User,
+        Some(username),
+        None,
+        Some(json!({ "oidc_subject": null })),
+        &mut db,
+    )
+    .await;
+
+    Ok(())
 }
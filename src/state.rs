@@ -1,5 +1,19 @@
 use chrono::Duration;
 
+use crate::captcha::CaptchaVerifier;
+use crate::guards::remote_ip::TrustedProxies;
+use crate::handlers::session::SessionPolicy;
+use crate::keys::JwtKeys;
+use crate::mailer::Mailer;
+use crate::password::PasswordPolicy;
+
 pub struct AppState {
     pub jwt_expiration: Duration,
+    pub refresh_expiration: Duration,
+    pub jwt_keys: JwtKeys,
+    pub captcha: Option<Box<dyn CaptchaVerifier>>,
+    pub trusted_proxies: TrustedProxies,
+    pub password_policy: PasswordPolicy,
+    pub session_policy: SessionPolicy,
+    pub mailer: Option<Mailer>,
 }
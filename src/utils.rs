@@ -1,6 +1,7 @@
 use chrono::prelude::*;
 use chrono::Duration;
 use regex::Regex;
+use sha2::{Digest, Sha256};
 
 use crate::errors::ServiceError;
 
@@ -17,7 +18,7 @@ pub fn parse_duration(expires: &str) -> Result<Duration, ServiceError> {
     };
 
     let num = match caps.get(1) {
-        Some(num) => num.as_str().parse::<usize>()?,
+        Some(num) => num.as_str().parse::<i64>()?,
         None => {
             return Err(ServiceError::FormatError(
                 "Invalid expires format".to_string(),
@@ -35,28 +36,46 @@ pub fn parse_duration(expires: &str) -> Result<Duration, ServiceError> {
     };
 
     let offset = match unit {
-        "s" => Duration::seconds(num as i64),
-        "m" => Duration::minutes(num as i64),
-        "h" => Duration::hours(num as i64),
-        "d" => Duration::days(num as i64),
-        "w" => Duration::weeks(num as i64),
-        "M" => Duration::days(num as i64 * 30),
-        "y" => Duration::days(num as i64 * 365),
-        _ => {
-            return Err(ServiceError::FormatError(
-                "Invalid expires format".to_string(),
-            ))
-        }
+        "s" => Duration::try_seconds(num),
+        "m" => Duration::try_minutes(num),
+        "h" => Duration::try_hours(num),
+        "d" => Duration::try_days(num),
+        "w" => Duration::try_weeks(num),
+        "M" => num.checked_mul(30).and_then(Duration::try_days),
+        "y" => num.checked_mul(365).and_then(Duration::try_days),
+        _ => None,
     };
 
-    Ok(offset)
+    offset.ok_or(ServiceError::FormatError(
+        "Invalid expires format".to_string(),
+    ))
 }
 
 pub fn calculate_expires(expires: &str) -> Result<i64, ServiceError> {
     let now = Utc::now();
     let offset = parse_duration(expires)?;
 
-    Ok((now + offset).timestamp())
+    now.checked_add_signed(offset)
+        .map(|expires| expires.timestamp())
+        .ok_or(ServiceError::FormatError(
+            "Invalid expires format".to_string(),
+        ))
+}
+
+/*
+ * Tokens handed out to clients are random enough that a plain SHA-256 is all
+ * that is needed to store them.
+ */
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub fn paginate(page: Option<i64>, size: Option<i64>) -> (i64, i64) {
+    let size = size.unwrap_or(10).clamp(1, 100);
+
+    let page = page.unwrap_or(0).max(0);
+
+    (size, page.saturating_mul(size))
 }
 
 pub fn standardize_url<'r>(url: &'r str, upload_url: &'r str) -> Option<String> {
@@ -107,6 +126,23 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_duration_out_of_range() {
+        assert!(parse_duration("999999999y").is_err());
+        assert!(parse_duration("200000000000d").is_err());
+        assert!(parse_duration("99999999999999999999s").is_err());
+        assert!(calculate_expires("99999999w").is_err());
+    }
+
+    #[test]
+    fn test_paginate() {
+        assert_eq!(paginate(None, None), (10, 0));
+        assert_eq!(paginate(Some(2), Some(20)), (20, 40));
+        assert_eq!(paginate(Some(-1), Some(0)), (1, 0));
+        assert_eq!(paginate(Some(1), Some(1000)), (100, 100));
+        assert_eq!(paginate(Some(i64::MAX), Some(100)), (100, i64::MAX));
+    }
+
     #[test]
     fn test_standardize_url() {
         assert_eq!(
@@ -0,0 +1,170 @@
+use chrono::Duration;
+use rocket::http::{ContentType, Method, Status};
+use rocket::local::asynchronous::Client;
+use rocket_db_pools::deadpool_redis::{self, Runtime};
+use sqlx::mysql::MySqlPoolOptions;
+
+use startpage::config::{Config, Jwt, Password, Proxy, Session, Visibility};
+use startpage::guards::remote_ip::TrustedProxies;
+use startpage::handlers::session::SessionPolicy;
+use startpage::keys::JwtKeys;
+use startpage::password::PasswordPolicy;
+use startpage::routes;
+use startpage::state::AppState;
+use startpage::{MySQLDb, RedisDb};
+
+/*
+ * Mutating routes that are meant to be called without signing in.
+ */
+const PUBLIC: &[(Method, &str)] = &[
+    (Method::Post, "/api/auth/login"),
+    (Method::Post, "/api/auth/login/totp"),
+    (Method::Post, "/api/auth/refresh"),
+    (Method::Post, "/api/auth/password/forgot"),
+    (Method::Post, "/api/auth/password/reset"),
+    (Method::Post, "/api/auth/email/verify"),
+    (Method::Post, "/api/site/<id>/visit"),
+];
+
+/*
+ * The server with every API route mounted. The pools never connect: a request
+ * without credentials has to be turned away before anything reaches them.
+ */
+async fn client(config: Config) -> Client {
+    let state = AppState {
+        jwt_expiration: Duration::minutes(30),
+        refresh_expiration: Duration::days(7),
+        jwt_keys: JwtKeys::load(&Jwt::default()).unwrap(),
+        captcha: None,
+        trusted_proxies: TrustedProxies::from_config(&Proxy::default()).unwrap(),
+        password_policy: PasswordPolicy::from_config(&Password::default()).unwrap(),
+        session_policy: SessionPolicy::from_config(&Session::default()).unwrap(),
+        mailer: None,
+    };
+
+    let db = MySqlPoolOptions::new()
+        .connect_lazy("mysql://startpage@127.0.0.1:1/startpage")
+        .unwrap();
+
+    let cache = deadpool_redis::Config::from_url("redis://127.0.0.1:1")
+        .create_pool(Some(Runtime::Tokio1))
+        .unwrap();
+
+    let mut rocket = rocket::build()
+        .manage(state)
+        .manage(config)
+        .manage(MySQLDb::from(db))
+        .manage(RedisDb::from(cache));
+
+    for (base, routes) in routes::mounts() {
+        rocket = rocket.mount(base, routes);
+    }
+
+    Client::untracked(rocket).await.unwrap()
+}
+
+fn is_mutating(method: Method) -> bool {
+    matches!(
+        method,
+        Method::Post | Method::Put | Method::Patch | Method::Delete
+    )
+}
+
+/*
+ * Fills in every dynamic segment, so the request reaches the route itself.
+ */
+fn concrete_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match segment.starts_with('<') {
+            true => "1",
+            false => segment,
+        })
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+#[rocket::async_test]
+async fn test_mutating_routes_require_authorization() {
+    let client = client(Config::default()).await;
+
+    let mut unguarded = Vec::new();
+
+    let mut checked = 0;
+
+    for route in client.rocket().routes() {
+        let path = route.uri.path();
+
+        if !is_mutating(route.method) || PUBLIC.contains(&(route.method, path)) {
+            continue;
+        }
+
+        let mut request = client.req(route.method, concrete_path(path));
+
+        if let Some(format) = &route.format {
+            request = request.header(ContentType(format.clone())).body("{}");
+        }
+
+        let status = request.dispatch().await.status();
+
+        if status != Status::Unauthorized && status != Status::Forbidden {
+            unguarded.push(format!("{} {} answered {}", route.method, path, status));
+        }
+
+        checked += 1;
+    }
+
+    assert!(checked > 0);
+    assert!(
+        unguarded.is_empty(),
+        "unguarded mutating routes:\n{}",
+        unguarded.join("\n")
+    );
+}
+
+#[rocket::async_test]
+async fn test_public_routes_are_mounted() {
+    let client = client(Config::default()).await;
+
+    for (method, path) in PUBLIC {
+        assert!(
+            client
+                .rocket()
+                .routes()
+                .any(|route| route.method == *method && route.uri.path() == *path),
+            "{} {} is not mounted",
+            method,
+            path
+        );
+    }
+}
+
+#[rocket::async_test]
+async fn test_private_mode_requires_login() {
+    let client = client(Config {
+        visibility: Visibility::Private,
+        ..Default::default()
+    })
+    .await;
+
+    for path in [
+        "/api/categories",
+        "/api/category/1",
+        "/api/category/1/ancestors",
+        "/api/category/1/descendants",
+        "/api/category/1/sites",
+        "/api/sites",
+        "/api/site/1",
+    ] {
+        assert_eq!(
+            client.get(path).dispatch().await.status(),
+            Status::Unauthorized,
+            "GET {} is readable anonymously",
+            path
+        );
+    }
+    assert_eq!(
+        client.post("/api/site/1/visit").dispatch().await.status(),
+        Status::Unauthorized,
+        "visits are counted anonymously"
+    );
+}